@@ -0,0 +1,77 @@
+//! Compares committing every write on its own with group commit, with
+//! several threads writing concurrently and every commit synced to disk.
+//!
+//! Run with `cargo bench --bench group_commit`.
+
+use key_value_store::{Bitcask, Options, SharedBitcask, SyncPolicy};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const THREADS: usize = 8;
+const WRITES_PER_THREAD: usize = 250;
+const VALUE: &[u8] = &[7; 100];
+
+fn main() {
+    let dir = std::env::temp_dir().join("kvs-bench-group-commit");
+
+    let per_write = run(&dir, |kv| {
+        let kv = Arc::new(Mutex::new(kv));
+        move |key: &[u8]| kv.lock().unwrap().insert(key, VALUE).unwrap()
+    });
+    report("per write", per_write);
+
+    let grouped = run(&dir, |kv| {
+        let kv = SharedBitcask::new(kv);
+        move |key: &[u8]| kv.insert(key, VALUE).unwrap()
+    });
+    report("group commit", grouped);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+/// Opens a fresh store in `dir` and times `THREADS` threads each inserting
+/// `WRITES_PER_THREAD` keys through the writer `setup` returns.
+fn run<S, W>(dir: &Path, setup: S) -> Duration
+where
+    S: FnOnce(Bitcask) -> W,
+    W: Fn(&[u8]) + Clone + Send + 'static,
+{
+    let _ = std::fs::remove_dir_all(dir);
+
+    let mut opts = Options::new(dir);
+    opts.sync = SyncPolicy::Always;
+
+    let write = setup(Bitcask::open(opts).unwrap());
+    let start = Instant::now();
+
+    let threads: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let write = write.clone();
+            thread::spawn(move || {
+                for i in 0..WRITES_PER_THREAD {
+                    let key = format!("{:02}:{:06}", t, i);
+                    write(key.as_bytes());
+                }
+            })
+        })
+        .collect();
+
+    for t in threads {
+        t.join().unwrap();
+    }
+
+    start.elapsed()
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let writes = (THREADS * WRITES_PER_THREAD) as f64;
+
+    println!(
+        "{:<14} {:>8.2?} {:>10.0} writes/s",
+        name,
+        elapsed,
+        writes / elapsed.as_secs_f64()
+    );
+}
@@ -0,0 +1,124 @@
+//! Compares the memory taken by each kind of in-memory index, along with the
+//! time to load it and to look keys up, for a store with many small keys.
+//!
+//! Run with `cargo bench --bench index_memory`.
+
+use key_value_store::{Bitcask, IndexKind, Options, WriteBatch};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+const KEYS: usize = 1_000_000;
+const BATCH: usize = 10_000;
+const LOOKUPS: usize = 100_000;
+const VALUE: &[u8] = &[7; 16];
+
+/// Counts the bytes currently allocated on the heap.
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn main() {
+    let dir = std::env::temp_dir().join("kvs-bench-index-memory");
+    let _ = std::fs::remove_dir_all(&dir);
+
+    populate(&dir);
+
+    let key_bytes: usize = (0..KEYS).map(|i| key(i).len()).sum();
+    println!(
+        "{} keys of {} bytes on average",
+        KEYS,
+        key_bytes as f64 / KEYS as f64
+    );
+
+    for kind in [
+        IndexKind::BTree,
+        IndexKind::Compact,
+        IndexKind::Hash,
+        IndexKind::HashedKeys,
+    ] {
+        let (bytes, load, lookup) = run(&dir, kind);
+
+        println!(
+            "{:<12} {:>7.1} bytes/key {:>8.2?} load {:>6.0} ns/get",
+            format!("{:?}", kind),
+            bytes as f64 / KEYS as f64,
+            load,
+            lookup.as_nanos() as f64 / LOOKUPS as f64
+        );
+    }
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+/// Writes `KEYS` keys to a new store in `dir`, in no particular order.
+fn populate(dir: &Path) {
+    let mut kv = Bitcask::open(Options::new(dir)).unwrap();
+    let mut batch = WriteBatch::new();
+
+    for i in 0..KEYS {
+        batch.put(&key(scatter(i)), VALUE);
+
+        if batch.len() == BATCH {
+            kv.write_batch(std::mem::take(&mut batch)).unwrap();
+        }
+    }
+
+    kv.write_batch(batch).unwrap();
+    kv.close().unwrap();
+}
+
+/// Opens the store in `dir` with an index of `kind`, returning the heap
+/// memory the store takes, the time to open it and the time for `LOOKUPS`
+/// lookups of existing keys.
+fn run(dir: &Path, kind: IndexKind) -> (usize, Duration, Duration) {
+    let mut opts = Options::new(dir);
+    opts.index = kind;
+
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let kv = Bitcask::open(opts).unwrap();
+    let load = start.elapsed();
+    let bytes = ALLOCATED.load(Ordering::Relaxed) - before;
+
+    let start = Instant::now();
+    for i in 0..LOOKUPS {
+        assert!(kv.exists(&key(scatter(i * 7))).unwrap());
+    }
+    let lookup = start.elapsed();
+
+    kv.close().unwrap();
+
+    (bytes, load, lookup)
+}
+
+fn key(i: usize) -> Vec<u8> {
+    format!("user:{}", i).into_bytes()
+}
+
+/// Maps `0..KEYS` onto itself in a scattered order.
+fn scatter(i: usize) -> usize {
+    (i * 7_919) % KEYS
+}
@@ -0,0 +1,38 @@
+use key_value_store::{AsyncBitcask, Options};
+
+#[tokio::main]
+async fn main() {
+    let kv = AsyncBitcask::open(Options::new("./async-kv"))
+        .await
+        .unwrap();
+
+    // concurrent inserts are committed together by the writer
+    let tasks: Vec<_> = (0..100u32)
+        .map(|i| {
+            let kv = kv.clone();
+            tokio::spawn(async move {
+                let key = format!("task:{:03}", i);
+                kv.insert(key.as_bytes(), &i.to_be_bytes()).await.unwrap();
+            })
+        })
+        .collect();
+
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    let value = kv.get(b"task:042").await.unwrap().unwrap();
+    println!("task:042 = {:?}", value.data());
+
+    println!("deleted: {}", kv.delete(b"task:000").await.unwrap());
+
+    let mut iter = kv.iter(..).await.unwrap();
+    let mut count = 0;
+    while let Some(item) = iter.next().await {
+        item.unwrap();
+        count += 1;
+    }
+    println!("{} entries", count);
+
+    kv.merge().await.unwrap();
+}
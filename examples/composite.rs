@@ -0,0 +1,41 @@
+use key_value_store::key::{self, KeyWriter};
+use key_value_store::{Bitcask, Options};
+use std::ops::Bound;
+
+fn main() {
+    let opts = Options::new("./composite-kv");
+    let mut kv = Bitcask::open(opts).unwrap();
+
+    // events keyed by (user id, timestamp), so each user's events are stored
+    // together in time order
+    let events = [
+        (2, 30, "logout"),
+        (1, -5, "signup"),
+        (1, 20, "post"),
+        (1, 7, "login"),
+    ];
+
+    for (user, ts, event) in events {
+        kv.insert(&key::encode(&(user as u64, ts as i64)), event.as_bytes())
+            .unwrap();
+    }
+
+    // every event of user 1, found by the encoding of the leading part
+    for item in kv.scan_prefix(&key::encode(&(1u64,))).unwrap() {
+        let (k, v) = item.unwrap();
+        let (user, ts): (u64, i64) = key::decode(&k).unwrap();
+        println!("{} {:>3} {}", user, ts, String::from_utf8_lossy(v.data()));
+    }
+
+    // events of user 1 from timestamp 0 on
+    let mut start = KeyWriter::new();
+    start.u64(1).i64(0);
+    let end = key::encode(&(2u64,));
+
+    let range = (Bound::Included(start.as_bytes()), Bound::Excluded(&end[..]));
+
+    for k in kv.keys(range).unwrap() {
+        let (user, ts): (u64, i64) = key::decode(k).unwrap();
+        println!("{} {:>3}", user, ts);
+    }
+}
@@ -2,7 +2,7 @@ use key_value_store::{Bitcask, Options};
 
 fn main() {
     let opts = Options::new("./fold-kv");
-    let mut kv = Bitcask::open(opts);
+    let mut kv = Bitcask::open(opts).unwrap();
 
     kv.insert_if_none(b"person:1:name", b"alice").unwrap();
     kv.insert_if_none(b"person:1:age", b"1").unwrap();
@@ -15,11 +15,19 @@ fn main() {
 
     let mut count = 0;
 
-    for (k, _) in kv.iter(..) {
-        if k.starts_with(b"person") && k.ends_with(b"age") {
+    for k in kv.scan_prefix_keys(b"person:").unwrap() {
+        if k.ends_with(b"age") {
             count += 1;
         }
     }
 
     println!("number of contacts: {}", count);
+
+    for (k, v) in kv.scan_prefix(b"person:1:").unwrap().skip_errors() {
+        println!("{} = {}", to_str(&k), to_str(v.data()));
+    }
+}
+
+fn to_str(bytes: &[u8]) -> &str {
+    unsafe { std::str::from_utf8_unchecked(bytes) }
 }
@@ -2,16 +2,16 @@ use key_value_store::{Bitcask, Options};
 
 fn main() {
     let opts = Options::new("./hello-kv");
-    let mut kv = Bitcask::open(opts);
+    let mut kv = Bitcask::open(opts).unwrap();
 
     let res = kv.insert_if_none(b"message", b"Hello, World!");
     println!("{:?}", res);
 
-    kv.flush();
+    kv.flush().unwrap();
 
     let res = kv.get(b"message");
 
-    if let Some(value) = res {
+    if let Ok(Some(value)) = res {
         println!("{}", to_str(value.data()));
     }
 }
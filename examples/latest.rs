@@ -0,0 +1,54 @@
+use key_value_store::{Bitcask, Options};
+use std::ops::Bound;
+
+fn main() {
+    let opts = Options::new("./latest-kv");
+    let mut kv = Bitcask::open(opts).unwrap();
+
+    for (ts, reading) in [
+        (100, b"a"),
+        (200, b"b"),
+        (300, b"c"),
+        (400, b"d"),
+        (500, b"e"),
+    ] {
+        kv.insert(&id(ts), reading).unwrap();
+    }
+
+    let before = id(450);
+
+    println!("latest 2 readings before 450:");
+    for (k, v) in kv
+        .iter((Bound::Unbounded, Bound::Excluded(&before[..])))
+        .unwrap()
+        .skip_errors()
+        .rev()
+        .take(2)
+    {
+        println!("{} {}", from_id(&k), to_str(v.data()));
+    }
+
+    println!("walking back from 250:");
+    let mut cursor = kv.cursor().unwrap();
+    cursor.seek_for_prev(&id(250));
+
+    while let Some(k) = cursor.key() {
+        let v = cursor.value().unwrap().unwrap();
+        println!("{} {}", from_id(k), to_str(v.data()));
+        cursor.prev();
+    }
+}
+
+fn id(v: u64) -> [u8; 8] {
+    v.to_be_bytes()
+}
+
+fn from_id(k: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(k);
+    u64::from_be_bytes(buf)
+}
+
+fn to_str(b: &[u8]) -> &str {
+    unsafe { std::str::from_utf8_unchecked(b) }
+}
@@ -0,0 +1,33 @@
+use key_value_store::{Bitcask, Options};
+
+fn main() {
+    let opts = Options::new("./namespaces-kv");
+    let mut kv = Bitcask::open(opts).unwrap();
+
+    // the same key holds a different value in every namespace
+    let mut users = kv.namespace("users").unwrap();
+    users.insert(b"1", b"ada").unwrap();
+    users.insert(b"2", b"grace").unwrap();
+
+    let mut sessions = kv.namespace("sessions").unwrap();
+    sessions.insert(b"1", b"expires 2030-01-01").unwrap();
+
+    for name in kv.namespaces() {
+        let ns = kv.namespace(&name).unwrap();
+        println!("{} ({} keys)", ns.name(), ns.len());
+
+        for item in ns.iter(..).unwrap() {
+            let (k, v) = item.unwrap();
+            println!(
+                "  {} = {}",
+                String::from_utf8_lossy(&k),
+                String::from_utf8_lossy(v.data())
+            );
+        }
+    }
+
+    // dropping a namespace writes a single record, whatever its size; the
+    // next merge reclaims the space
+    kv.drop_namespace("sessions").unwrap();
+    println!("{:?}", kv.namespaces());
+}
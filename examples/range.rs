@@ -1,8 +1,9 @@
 use key_value_store::{Bitcask, Options};
+use std::ops::Bound;
 
 fn main() {
     let opts = Options::new("./range-kv");
-    let mut kv = Bitcask::open(opts);
+    let mut kv = Bitcask::open(opts).unwrap();
 
     kv.insert(&id(1), b"a").unwrap();
     kv.insert(&id(2), b"b").unwrap();
@@ -10,12 +11,26 @@ fn main() {
     kv.insert(&id(4), b"d").unwrap();
     kv.insert(&id(5), b"e").unwrap();
 
-    let range = id(2).to_vec()..;
+    let start = id(2);
+    let end = id(4);
 
-    for (k, v) in kv.iter(range) {
-        let mut buf = [0u8; 8];
-        buf.copy_from_slice(&k);
-        println!("{} {}", u64::from_be_bytes(buf), to_str(v.data()));
+    for item in kv
+        .iter((Bound::Included(&start[..]), Bound::Unbounded))
+        .unwrap()
+    {
+        let (k, v) = item.unwrap();
+        println!("{} {}", from_id(&k), to_str(v.data()));
+    }
+
+    let range = (Bound::Included(&start[..]), Bound::Excluded(&end[..]));
+
+    for e in kv.entries(range).unwrap() {
+        println!(
+            "{} ({} bytes, ts {})",
+            from_id(e.key()),
+            e.size(),
+            e.timestamp()
+        );
     }
 }
 
@@ -23,6 +38,12 @@ fn id(v: u64) -> [u8; 8] {
     v.to_be_bytes()
 }
 
+fn from_id(k: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(k);
+    u64::from_be_bytes(buf)
+}
+
 fn to_str(b: &[u8]) -> &str {
     unsafe { std::str::from_utf8_unchecked(b) }
 }
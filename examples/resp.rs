@@ -0,0 +1,32 @@
+use key_value_store::server::{Client, Server};
+use key_value_store::{Bitcask, Options};
+use std::thread;
+use std::time::Duration;
+
+fn main() {
+    let kv = Bitcask::open(Options::new("./resp-kv")).unwrap();
+    let server = Server::bind(kv, "127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+
+    thread::spawn(move || server.run().unwrap());
+
+    let mut client = Client::connect(addr).unwrap();
+
+    client.set(b"person:1:name", b"alice").unwrap();
+    client.set(b"person:2:name", b"bob").unwrap();
+    client.set(b"session", b"abc").unwrap();
+
+    let name = client.get(b"person:1:name").unwrap().unwrap();
+    println!("person:1:name = {}", name.escape_ascii());
+
+    for key in client.scan(b"person:*").unwrap() {
+        println!("found {}", key.escape_ascii());
+    }
+
+    client.expire(b"session", 1).unwrap();
+    thread::sleep(Duration::from_millis(1100));
+    println!("session exists: {}", client.exists(b"session").unwrap());
+
+    println!("deleted: {}", client.del(b"person:2:name").unwrap());
+    println!("{}", client.info().unwrap());
+}
@@ -0,0 +1,39 @@
+use key_value_store::{Bitcask, Options};
+use std::ops::Bound;
+
+// values are "<city>,<name>", listed under their city
+fn by_city(_key: &[u8], value: &[u8]) -> Vec<Vec<u8>> {
+    match value.split(|&b| b == b',').next() {
+        Some(city) => vec![city.to_vec()],
+        None => vec![],
+    }
+}
+
+fn main() {
+    let opts = Options::new("./secondary-kv");
+    let mut kv = Bitcask::open(opts).unwrap();
+
+    // functions are not stored, so indexes are registered on every open
+    kv.register_index("city", by_city).unwrap();
+
+    kv.insert(b"user:1", b"paris,ada").unwrap();
+    kv.insert(b"user:2", b"oslo,grace").unwrap();
+    kv.insert(b"user:3", b"paris,linus").unwrap();
+
+    // moving user 3 moves it in the index as well
+    kv.insert(b"user:3", b"rome,linus").unwrap();
+
+    let paris = (
+        Bound::Included(&b"paris"[..]),
+        Bound::Included(&b"paris"[..]),
+    );
+
+    for item in kv.query_index("city", paris).unwrap() {
+        let (k, v) = item.unwrap();
+        println!(
+            "{} {}",
+            String::from_utf8_lossy(&k),
+            String::from_utf8_lossy(v.data())
+        );
+    }
+}
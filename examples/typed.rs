@@ -0,0 +1,39 @@
+use key_value_store::typed::{Bincode, Json, Ordered, TypedStore};
+use key_value_store::Options;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Reading {
+    sensor: String,
+    celsius: f64,
+}
+
+fn main() {
+    // keys are (sensor id, timestamp), so a sensor's readings are stored
+    // next to each other in time order
+    let mut readings: TypedStore<(u32, i64), Reading, Bincode, Ordered> =
+        TypedStore::open(Options::new("./typed-kv")).unwrap();
+
+    for (id, ts, celsius) in [(2, 100, 20.5), (1, -50, -3.0), (1, 200, 4.25), (1, 10, 1.0)] {
+        let reading = Reading {
+            sensor: format!("sensor-{}", id),
+            celsius,
+        };
+        readings.insert(&(id, ts), &reading).unwrap();
+    }
+
+    // every reading of sensor 1 in time order, negative timestamps first
+    for item in readings.iter((1, i64::MIN)..(2, i64::MIN)).unwrap() {
+        let ((id, ts), reading) = item.unwrap();
+        println!("{} {:>4} {:?}", id, ts, reading);
+    }
+
+    // values stored as JSON are readable when inspecting the store directly
+    let mut names: TypedStore<i64, Vec<String>, Json, Ordered> =
+        TypedStore::new(readings.into_inner());
+
+    names
+        .insert(&-1, &vec!["a".to_string(), "b".to_string()])
+        .unwrap();
+    println!("{:?}", names.get(&-1).unwrap());
+}
@@ -9,6 +9,7 @@ pub trait WriteExt {
         self.write(&bytes)
     }
 
+    #[allow(dead_code)]
     fn write_u16(&mut self, val: u16) -> io::Result<u64> {
         let bytes = val.to_be_bytes();
         self.write(&bytes)
@@ -49,6 +50,7 @@ pub trait ReadExt {
         Ok(u8::from_be(buf[0]))
     }
 
+    #[allow(dead_code)]
     fn read_u16(&mut self) -> io::Result<u16> {
         let mut buf = [0; 2];
         self.read(&mut buf)?;
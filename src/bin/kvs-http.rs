@@ -0,0 +1,49 @@
+use clap::Parser;
+use key_value_store::http::HttpServer;
+use key_value_store::{Bitcask, Options};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+/// Serve a key value store as a JSON API over HTTP.
+#[derive(Parser)]
+#[command(name = "kvs-http")]
+struct Cli {
+    /// Directory of the store
+    #[arg(short, long, default_value = ".")]
+    dir: PathBuf,
+
+    /// Address to listen on
+    #[arg(short, long, default_value = "127.0.0.1:8080")]
+    addr: String,
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    let kv = match Bitcask::open(Options::new(&cli.dir)) {
+        Ok(kv) => kv,
+        Err(e) => {
+            eprintln!("error: could not open store {:?}: {}", cli.dir, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut server = match HttpServer::bind(kv, &cli.addr) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("error: could not listen on {}: {}", cli.addr, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    server.on_error(|e| eprintln!("connection error: {}", e));
+
+    println!("serving {:?} on {}", cli.dir, cli.addr);
+
+    if let Err(e) = server.run() {
+        eprintln!("error: {}", e);
+        return ExitCode::FAILURE;
+    }
+
+    ExitCode::SUCCESS
+}
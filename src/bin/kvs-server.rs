@@ -0,0 +1,54 @@
+use clap::Parser;
+use key_value_store::server::Server;
+use key_value_store::{Bitcask, Options};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+/// Serve a key value store over the Redis protocol.
+#[derive(Parser)]
+#[command(name = "kvs-server")]
+struct Cli {
+    /// Directory of the store
+    #[arg(short, long, default_value = ".")]
+    dir: PathBuf,
+
+    /// Address to listen on
+    #[arg(short, long, default_value = "127.0.0.1:6379")]
+    addr: String,
+
+    /// Refuse connections beyond this many clients
+    #[arg(long, default_value_t = Server::DEFAULT_MAX_CLIENTS)]
+    max_clients: usize,
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    let kv = match Bitcask::open(Options::new(&cli.dir)) {
+        Ok(kv) => kv,
+        Err(e) => {
+            eprintln!("error: could not open store {:?}: {}", cli.dir, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut server = match Server::bind(kv, &cli.addr) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("error: could not listen on {}: {}", cli.addr, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    server.set_max_clients(cli.max_clients);
+    server.on_error(|e| eprintln!("connection error: {}", e));
+
+    println!("serving {:?} on {}", cli.dir, cli.addr);
+
+    if let Err(e) = server.run() {
+        eprintln!("error: could not accept connections: {}", e);
+        return ExitCode::FAILURE;
+    }
+
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,380 @@
+use clap::{Parser, Subcommand};
+use key_value_store::{Bitcask, EntryReader, KeyValueStoreError, Options, SharedBitcask};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+/// Inspect and operate key value stores.
+///
+/// Stores are opened read only unless `--write` is given.
+#[derive(Parser)]
+#[command(name = "kvs")]
+struct Cli {
+    /// Directory of the store
+    #[arg(short, long, default_value = ".")]
+    dir: PathBuf,
+
+    /// Allow commands that modify the store
+    #[arg(short, long)]
+    write: bool,
+
+    /// Read keys and values from arguments as hex and print them as hex
+    #[arg(long)]
+    hex: bool,
+
+    /// Limit merge and verify to this many bytes per second
+    #[arg(long, value_name = "BYTES")]
+    io_rate_limit: Option<u64>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the value of a key
+    Get { key: String },
+    /// Insert or replace the value of a key
+    Put { key: String, value: String },
+    /// Delete a key
+    Delete { key: String },
+    /// List entries in key order
+    Scan {
+        /// Only list keys starting with this prefix
+        #[arg(long, conflicts_with = "range")]
+        prefix: Option<String>,
+        /// Only list keys from START (inclusive) to END (exclusive)
+        #[arg(long, num_args = 2, value_names = ["START", "END"])]
+        range: Option<Vec<String>>,
+        /// Stop after this many entries
+        #[arg(long)]
+        limit: Option<usize>,
+        /// List entries in reverse key order
+        #[arg(long)]
+        reverse: bool,
+        /// Only list keys, without reading values
+        #[arg(long)]
+        keys_only: bool,
+    },
+    /// Print statistics about the store
+    Stats,
+    /// Compact the data files
+    Merge,
+    /// Rewrite the blob files that are mostly overwritten or deleted values
+    MergeBlobs,
+    /// Check the checksums of all records and that all keys can be read
+    Verify,
+    /// Write all entries to a dump file
+    Dump {
+        /// Write JSON lines instead of the binary dump format
+        #[arg(long)]
+        json: bool,
+        /// File to write to, defaults to stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Insert all entries from a dump file
+    Load {
+        /// Read JSON lines instead of the binary dump format
+        #[arg(long)]
+        json: bool,
+        /// File to read from, `-` for stdin
+        input: PathBuf,
+    },
+    /// Decode the records of a data file
+    InspectFile { path: PathBuf },
+}
+
+impl Command {
+    fn writes(&self) -> bool {
+        matches!(
+            self,
+            Self::Put { .. }
+                | Self::Delete { .. }
+                | Self::Merge
+                | Self::MergeBlobs
+                | Self::Load { .. }
+        )
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match run(cli) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<ExitCode, String> {
+    if let Command::InspectFile { path } = &cli.command {
+        return inspect_file(path, cli.hex);
+    }
+
+    if cli.command.writes() && !cli.write {
+        return Err("this command modifies the store, pass --write to allow it".to_string());
+    }
+
+    let mut opts = Options::new(&cli.dir);
+    opts.read_only = !cli.write;
+    opts.io_rate_limit = cli.io_rate_limit;
+
+    let mut kv = Bitcask::open(opts).map_err(|e| match e {
+        KeyValueStoreError::ReadOnly => {
+            format!("store {:?} is empty, pass --write to create it", cli.dir)
+        }
+        KeyValueStoreError::Io(e) if e.kind() == io::ErrorKind::NotFound => {
+            format!("store {:?} does not exist", cli.dir)
+        }
+        e => format!("could not open store {:?}: {}", cli.dir, e),
+    })?;
+    let hex = cli.hex;
+
+    match cli.command {
+        Command::Get { key } => match kv.get(&parse(&key, hex)?).map_err(err)? {
+            Some(value) => println!("{}", show(value.data(), hex)),
+            None => {
+                eprintln!("not found");
+                return Ok(ExitCode::FAILURE);
+            }
+        },
+        Command::Put { key, value } => {
+            kv.insert(&parse(&key, hex)?, &parse(&value, hex)?)
+                .map_err(err)?;
+        }
+        Command::Delete { key } => {
+            if !kv.delete(&parse(&key, hex)?).map_err(err)? {
+                eprintln!("not found");
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Command::Scan {
+            prefix,
+            range,
+            limit,
+            reverse,
+            keys_only,
+        } => {
+            let prefix = prefix.map(|p| parse(&p, hex)).transpose()?;
+            let range = match range {
+                Some(range) => Some((parse(&range[0], hex)?, parse(&range[1], hex)?)),
+                None => None,
+            };
+
+            let bounds = match &range {
+                Some((start, end)) => (
+                    Bound::Included(start.as_slice()),
+                    Bound::Excluded(end.as_slice()),
+                ),
+                None => (Bound::Unbounded, Bound::Unbounded),
+            };
+
+            let limit = limit.unwrap_or(usize::MAX);
+            let mut out = BufWriter::new(io::stdout().lock());
+
+            if keys_only {
+                let keys = match &prefix {
+                    Some(prefix) => kv.scan_prefix_keys(prefix),
+                    None => kv.keys(bounds),
+                }
+                .map_err(err)?;
+                let keys: Box<dyn Iterator<Item = &[u8]>> = match reverse {
+                    true => Box::new(keys.rev()),
+                    false => Box::new(keys),
+                };
+
+                for k in keys.take(limit) {
+                    writeln!(out, "{}", show(k, hex)).map_err(err)?;
+                }
+            } else {
+                let iter = match &prefix {
+                    Some(prefix) => kv.scan_prefix(prefix),
+                    None => kv.iter(bounds),
+                }
+                .map_err(err)?;
+                let iter: Box<dyn Iterator<Item = _>> = match reverse {
+                    true => Box::new(iter.rev()),
+                    false => Box::new(iter),
+                };
+
+                for item in iter.take(limit) {
+                    let (k, v) = item.map_err(err)?;
+                    writeln!(out, "{}\t{}", show(&k, hex), show(v.data(), hex)).map_err(err)?;
+                }
+            }
+        }
+        Command::Stats => {
+            let stats = kv.stats();
+            println!("keys:            {}", stats.num_keys);
+            println!("data files:      {}", stats.num_files);
+            println!("data size:       {}", stats.data_size);
+            println!("live size:       {}", stats.live_size);
+            println!("entries deleted: {}", stats.num_entries_deleted);
+            println!("bytes deleted:   {}", stats.num_bytes_deleted);
+            println!("blob files:      {}", stats.num_blob_files);
+            println!("blob size:       {}", stats.blob_size);
+            println!("blob deleted:    {}", stats.blob_bytes_deleted);
+        }
+        // through a shared store, whose merge and verify are throttled
+        Command::Merge => SharedBitcask::new(kv).merge().map_err(err)?,
+        Command::MergeBlobs => {
+            let removed = kv.merge_blobs().map_err(err)?;
+            println!("blob files removed: {}", removed);
+        }
+        Command::Verify => {
+            let report = SharedBitcask::new(kv).verify().map_err(err)?;
+
+            println!("data files: {}", report.num_files);
+            println!("records:    {}", report.num_records);
+            println!("tombstones: {}", report.num_tombstones);
+            println!("keys:       {}", report.num_entries);
+
+            for (file, offset) in &report.corrupt_records {
+                println!("corrupt record in {}.kv at offset {}", file, offset);
+            }
+            for (file, e) in &report.unreadable_files {
+                println!("could not read {}.kv: {}", file, e);
+            }
+            for (key, e) in &report.bad_entries {
+                println!("could not read key {}: {}", show(key, hex), e);
+            }
+            for e in &report.unreadable_keys {
+                println!("could not read back a hashed key: {}", e);
+            }
+
+            if !report.is_ok() {
+                return Ok(ExitCode::FAILURE);
+            }
+
+            println!("ok");
+        }
+        Command::Dump { json, output } => {
+            let writer: Box<dyn Write> = match output {
+                Some(path) => Box::new(File::create(path).map_err(err)?),
+                None => Box::new(io::stdout().lock()),
+            };
+            let writer = BufWriter::new(writer);
+
+            let count = match json {
+                true => kv.export_json(writer),
+                false => kv.export(writer),
+            }
+            .map_err(err)?;
+
+            eprintln!("dumped {} entries", count);
+        }
+        Command::Load { json, input } => {
+            let reader: Box<dyn io::Read> = match input.to_str() {
+                Some("-") => Box::new(io::stdin().lock()),
+                _ => Box::new(File::open(input).map_err(err)?),
+            };
+            let reader = BufReader::new(reader);
+
+            let count = match json {
+                true => kv.import_json(reader),
+                false => kv.import(reader),
+            }
+            .map_err(err)?;
+
+            eprintln!("loaded {} entries", count);
+        }
+        Command::InspectFile { .. } => unreachable!(),
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn inspect_file(path: &PathBuf, hex: bool) -> Result<ExitCode, String> {
+    let mut reader = EntryReader::open(path).map_err(err)?;
+    let mut out = BufWriter::new(io::stdout().lock());
+    let mut ok = true;
+
+    writeln!(
+        out,
+        "{:>12} {:>10} {:>10} {:>8} {:>12} {:>9} {:>10} key",
+        "offset", "size", "checksum", "status", "timestamp", "type", "value len"
+    )
+    .map_err(err)?;
+
+    loop {
+        let record = match reader.next_record() {
+            Ok(Some(record)) => record,
+            Ok(None) => break,
+            Err(e) => {
+                writeln!(out, "error: {}", e).map_err(err)?;
+                ok = false;
+                break;
+            }
+        };
+
+        let status = match record.is_valid() {
+            true => "ok",
+            false => "corrupt",
+        };
+        let kind = record.kind().name();
+
+        writeln!(
+            out,
+            "{:>12} {:>10} {:>10x} {:>8} {:>12} {:>9} {:>10} {}",
+            record.offset(),
+            record.size(),
+            record.checksum(),
+            status,
+            record.timestamp(),
+            kind,
+            record.value_len(),
+            show(record.key(), hex)
+        )
+        .map_err(err)?;
+
+        if !record.is_valid() {
+            // the lengths of a corrupt record can't be trusted, so the
+            // following records can't be found
+            ok = false;
+            break;
+        }
+    }
+
+    match ok {
+        true => Ok(ExitCode::SUCCESS),
+        false => Ok(ExitCode::FAILURE),
+    }
+}
+
+fn parse(arg: &str, hex: bool) -> Result<Vec<u8>, String> {
+    if !hex {
+        return Ok(arg.as_bytes().to_vec());
+    }
+
+    if !arg.len().is_multiple_of(2) || !arg.is_ascii() {
+        return Err(format!("invalid hex string {:?}", arg));
+    }
+
+    (0..arg.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&arg[i..i + 2], 16)
+                .map_err(|_| format!("invalid hex string {:?}", arg))
+        })
+        .collect()
+}
+
+fn show(bytes: &[u8], hex: bool) -> String {
+    match hex {
+        true => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+        false => bytes.escape_ascii().to_string(),
+    }
+}
+
+fn err<E>(e: E) -> String
+where
+    E: std::fmt::Display,
+{
+    e.to_string()
+}
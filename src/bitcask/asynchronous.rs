@@ -0,0 +1,404 @@
+use super::batch::WriteBatch;
+use super::error::{KeyValueStoreError, Result};
+use super::merge::MergeHandle;
+use super::reader::Value;
+use super::settings::Options;
+use super::Bitcask;
+use std::fs::File;
+use std::io;
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+
+/// Most writes committed together by the writer.
+const MAX_GROUP_SIZE: usize = 1024;
+/// Entries read from disk per blocking call while iterating.
+const ITER_CHUNK_SIZE: usize = 256;
+
+enum Request {
+    Put {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        done: oneshot::Sender<Result<()>>,
+    },
+    Delete {
+        key: Vec<u8>,
+        done: oneshot::Sender<Result<bool>>,
+    },
+    Merge {
+        done: oneshot::Sender<Result<Option<MergeHandle>>>,
+    },
+}
+
+/// A handle to a store for use from async code. Cloning the handle is cheap
+/// and every clone refers to the same store.
+///
+/// Reads run on tokio's blocking thread pool. Writes are sent to a dedicated
+/// writer thread, which commits all writes waiting at the time as a single
+/// batch followed by one `fsync`, regardless of the store's
+/// [`SyncPolicy`](crate::SyncPolicy), so a write has reached the disk once
+/// its future completes. The store is released before the sync, so reads go
+/// on while it runs and may see writes whose futures have not completed yet.
+#[derive(Clone)]
+pub struct AsyncBitcask {
+    kv: Arc<RwLock<Bitcask>>,
+    writer: mpsc::UnboundedSender<Request>,
+}
+
+impl AsyncBitcask {
+    /// Wraps an open store, starting its writer thread. The writer stops
+    /// once every handle has been dropped.
+    pub fn new(kv: Bitcask) -> Self {
+        let kv = Arc::new(RwLock::new(kv));
+        let (writer, requests) = mpsc::unbounded_channel();
+
+        let shared = Arc::clone(&kv);
+        thread::spawn(move || write_loop(shared, requests));
+
+        Self { kv, writer }
+    }
+
+    pub async fn open(opts: Options) -> Result<Self> {
+        let kv = task::spawn_blocking(move || Bitcask::open(opts))
+            .await
+            .expect("open panicked")?;
+
+        Ok(Self::new(kv))
+    }
+
+    pub async fn get(&self, key: &[u8]) -> Result<Option<Value>> {
+        let key = key.to_vec();
+        self.read(move |kv| kv.get(&key)).await
+    }
+
+    pub async fn exists(&self, key: &[u8]) -> Result<bool> {
+        let key = key.to_vec();
+        self.read(move |kv| kv.exists(&key)).await
+    }
+
+    pub async fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let (done, result) = oneshot::channel();
+
+        self.send(Request::Put {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            done,
+        });
+
+        result.await.unwrap_or_else(|_| Err(writer_gone()))
+    }
+
+    /// Deletes `key`, returning whether it existed. Unlike
+    /// [`Bitcask::delete`], this fails if the deletion could not be synced.
+    pub async fn delete(&self, key: &[u8]) -> Result<bool> {
+        let (done, result) = oneshot::channel();
+
+        self.send(Request::Delete {
+            key: key.to_vec(),
+            done,
+        });
+
+        result.await.unwrap_or_else(|_| Err(writer_gone()))
+    }
+
+    /// Iterates over the keys in `range`. Entries are read in chunks, each
+    /// while briefly holding the store, so unlike a [`Snapshot`] iteration
+    /// sees writes made while iterating to keys after the last one read.
+    ///
+    /// [`Snapshot`]: crate::Snapshot
+    pub async fn iter<R>(&self, range: R) -> Result<AsyncIter>
+    where
+        R: RangeBounds<[u8]>,
+    {
+        let start = range.start_bound().map(|k| k.to_vec());
+        let end = range.end_bound().map(|k| k.to_vec());
+
+        // fails early if the index has no order
+        self.read(|kv| kv.keys(..).map(|_| ())).await?;
+
+        Ok(AsyncIter {
+            kv: Arc::clone(&self.kv),
+            start,
+            end,
+            buffer: Vec::new().into_iter(),
+            done: false,
+        })
+    }
+
+    /// Merges the data files, see [`Bitcask::merge`], once every write queued
+    /// before this call has been committed. The merge runs on a background
+    /// thread like [`SharedBitcask::merge_in_background`], so reads and
+    /// writes go on while it copies records.
+    ///
+    /// [`SharedBitcask::merge_in_background`]: crate::SharedBitcask::merge_in_background
+    pub async fn merge(&self) -> Result<()> {
+        let (done, result) = oneshot::channel();
+        self.send(Request::Merge { done });
+
+        let handle = match result.await.unwrap_or_else(|_| Err(writer_gone()))? {
+            Some(handle) => handle,
+            None => return Ok(()),
+        };
+
+        task::spawn_blocking(move || handle.wait())
+            .await
+            .expect("merge panicked")
+    }
+
+    async fn read<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&Bitcask) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let kv = Arc::clone(&self.kv);
+
+        task::spawn_blocking(move || f(&kv.read().expect("kv lock")))
+            .await
+            .expect("read panicked")
+    }
+
+    fn send(&self, request: Request) {
+        // if the writer is gone the request is dropped along with its
+        // sender, which the caller sees as an error
+        let _ = self.writer.send(request);
+    }
+}
+
+/// An iterator over a range of a store, see [`AsyncBitcask::iter`].
+pub struct AsyncIter {
+    kv: Arc<RwLock<Bitcask>>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    buffer: std::vec::IntoIter<Result<(Vec<u8>, Value)>>,
+    done: bool,
+}
+
+impl AsyncIter {
+    pub async fn next(&mut self) -> Option<Result<(Vec<u8>, Value)>> {
+        if let Some(item) = self.buffer.next() {
+            return Some(item);
+        }
+
+        if self.done {
+            return None;
+        }
+
+        let kv = Arc::clone(&self.kv);
+        let start = self.start.clone();
+        let end = self.end.clone();
+
+        let (chunk, last) = task::spawn_blocking(move || {
+            let kv = kv.read().expect("kv lock");
+            let range = (start.as_ref().map(|k| &k[..]), end.as_ref().map(|k| &k[..]));
+            let iter = match kv.iter(range) {
+                Ok(iter) => iter,
+                Err(e) => return (vec![Err(e)], None),
+            };
+            let chunk: Vec<_> = iter.take(ITER_CHUNK_SIZE).collect();
+
+            // errors carry no key, so resume after the last key in the index
+            // range rather than the last key read successfully
+            let last = match chunk.len() {
+                0 => None,
+                n => kv
+                    .keys(range)
+                    .ok()
+                    .and_then(|mut keys| keys.nth(n - 1))
+                    .map(|k| k.to_vec()),
+            };
+
+            (chunk, last)
+        })
+        .await
+        .expect("read panicked");
+
+        match last {
+            Some(last) if chunk.len() == ITER_CHUNK_SIZE => self.start = Bound::Excluded(last),
+            _ => self.done = true,
+        }
+
+        self.buffer = chunk.into_iter();
+        self.buffer.next()
+    }
+}
+
+fn write_loop(kv: Arc<RwLock<Bitcask>>, mut requests: mpsc::UnboundedReceiver<Request>) {
+    let mut group = Vec::new();
+
+    while let Some(request) = requests.blocking_recv() {
+        group.push(request);
+
+        while group.len() < MAX_GROUP_SIZE {
+            match requests.try_recv() {
+                Ok(request) => group.push(request),
+                Err(_) => break,
+            }
+        }
+
+        let mut committed = Vec::new();
+
+        {
+            let mut guard = kv.write().expect("kv lock");
+            let mut writes = Vec::new();
+
+            for request in group.drain(..) {
+                match request {
+                    Request::Merge { done } => {
+                        committed.extend(commit(&mut guard, writes.drain(..)));
+
+                        let handle = guard
+                            .plan_merge()
+                            .map(|plan| plan.map(|plan| plan.spawn(Arc::clone(&kv), |kv| kv)));
+                        let _ = done.send(handle);
+                    }
+                    write => writes.push(write),
+                }
+            }
+
+            committed.extend(commit(&mut guard, writes.drain(..)));
+        }
+
+        // synced without holding the store, so reads go on meanwhile
+        for group in committed {
+            group.sync().complete();
+        }
+    }
+}
+
+/// Writes `requests` as one batch without syncing it, returning them along
+/// with the files to sync before they are completed, or `None` if there are
+/// no writes.
+fn commit<I>(kv: &mut Bitcask, requests: I) -> Option<Committed>
+where
+    I: Iterator<Item = Request>,
+{
+    let mut batch = WriteBatch::new();
+    let mut waiters = Vec::new();
+
+    for request in requests {
+        match request {
+            Request::Put { key, value, done } => {
+                batch.put(&key, &value);
+                waiters.push(Waiter::Put(done));
+            }
+            Request::Delete { key, done } => {
+                batch.delete(&key);
+                waiters.push(Waiter::Delete(done));
+            }
+            Request::Merge { .. } => unreachable!("merges are not batched"),
+        }
+    }
+
+    if waiters.is_empty() {
+        return None;
+    }
+
+    let result = kv.commit_unsynced(batch.ops);
+
+    Some(Committed { result, waiters })
+}
+
+/// A batch of writes that are committed but not yet synced.
+struct Committed {
+    /// Whether each write changed the store, and the files they went to.
+    result: Result<(Vec<bool>, Vec<File>)>,
+    waiters: Vec<Waiter>,
+}
+
+impl Committed {
+    fn sync(mut self) -> Self {
+        if let Ok((_, files)) = &self.result {
+            if let Err(e) = files.iter().try_for_each(File::sync_data) {
+                self.result = Err(e.into());
+            }
+        }
+
+        self
+    }
+
+    fn complete(self) {
+        for (i, waiter) in self.waiters.into_iter().enumerate() {
+            let result = self
+                .result
+                .as_ref()
+                .map(|(changed, _)| changed[i])
+                .map_err(Clone::clone);
+
+            // the caller may have stopped waiting
+            match waiter {
+                Waiter::Put(done) => drop(done.send(result.map(|_| ()))),
+                Waiter::Delete(done) => drop(done.send(result)),
+            }
+        }
+    }
+}
+
+enum Waiter {
+    Put(oneshot::Sender<Result<()>>),
+    Delete(oneshot::Sender<Result<bool>>),
+}
+
+fn writer_gone() -> KeyValueStoreError {
+    io::Error::other("writer thread stopped").into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(name: &str) -> Options {
+        let root = std::env::temp_dir().join(format!("kvs-async-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        Options::new(root)
+    }
+
+    #[test]
+    fn unsynced_commit_returns_sealed_files() {
+        let mut opts = store("sealed");
+        opts.file_size_limit = 200;
+        let mut kv = Bitcask::open(opts).unwrap();
+
+        // about three records fit in a file
+        let mut batch = WriteBatch::new();
+        for key in 0..8u8 {
+            batch.put(&[key], &[0; 24]);
+        }
+
+        let first = kv.counter;
+        let (changed, files) = kv.commit_unsynced(batch.ops).unwrap();
+        let sealed = kv.counter - first;
+
+        assert_eq!(changed, vec![true; 8]);
+        assert!(sealed >= 2);
+        assert_eq!(files.len() as u64, sealed + 1);
+    }
+
+    #[tokio::test]
+    async fn concurrent_writes_across_files() {
+        let mut opts = store("writes");
+        opts.file_size_limit = 200;
+        let kv = AsyncBitcask::open(opts).await.unwrap();
+
+        let writes: Vec<_> = (0..32u8)
+            .map(|key| {
+                let kv = kv.clone();
+                tokio::spawn(async move { kv.insert(&[key], &[key; 24]).await })
+            })
+            .collect();
+
+        for write in writes {
+            write.await.unwrap().unwrap();
+        }
+
+        // the store is only closed by the writer thread once every handle
+        // is gone, so it is read through the handle rather than reopened
+        for key in 0..32u8 {
+            let value = kv.get(&[key]).await.unwrap().unwrap();
+            assert_eq!(value.data(), [key; 24]);
+        }
+
+        assert!(kv.read(|kv| kv.counter).await >= 2);
+    }
+}
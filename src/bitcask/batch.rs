@@ -0,0 +1,73 @@
+use super::blob::BlobRef;
+use super::namespace::DEFAULT_NAMESPACE;
+use chrono::Utc;
+
+pub(crate) enum Op {
+    Put {
+        namespace: u32,
+        timestamp: u64,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Delete {
+        namespace: u32,
+        timestamp: u64,
+        key: Vec<u8>,
+    },
+    /// A put of a value already written to a blob file.
+    PutBlob {
+        namespace: u32,
+        timestamp: u64,
+        key: Vec<u8>,
+        blob: BlobRef,
+    },
+}
+
+/// A set of writes that are applied with a single flush by
+/// [`Bitcask::write_batch`](crate::Bitcask::write_batch).
+#[derive(Default)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<Op>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self { ops: Vec::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        let ts = Utc::now().timestamp() as u64;
+        self.put_at(DEFAULT_NAMESPACE, ts, key, value);
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        let ts = Utc::now().timestamp() as u64;
+
+        self.ops.push(Op::Delete {
+            namespace: DEFAULT_NAMESPACE,
+            timestamp: ts,
+            key: key.to_vec(),
+        });
+    }
+
+    pub(crate) fn put_at(&mut self, namespace: u32, timestamp: u64, key: &[u8], value: &[u8]) {
+        self.ops.push(Op::Put {
+            namespace,
+            timestamp,
+            key: key.to_vec(),
+            value: value.to_vec(),
+        });
+    }
+}
@@ -0,0 +1,347 @@
+use super::error::{KeyValueStoreError, Result};
+use super::index::Entry;
+use super::reader::{FileMap, RecordKind};
+use super::util::{blob_path, crc_init, data_path, walk_blobs, BLOB_FILE_HEADER};
+use memmap::Mmap;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Values are copied into blob files in chunks of at most this size.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The location of a value kept in a blob file, stored in place of the value
+/// in its record. Blob files start with a header followed by the raw bytes
+/// of their values, so values can be written to them as they are streamed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobRef {
+    pub file: u64,
+    pub offset: u64,
+    pub len: u64,
+    /// The checksum of the value.
+    pub checksum: u32,
+}
+
+impl BlobRef {
+    const SIZE: usize = 28;
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(Self::SIZE);
+        out.extend_from_slice(&self.file.to_be_bytes());
+        out.extend_from_slice(&self.offset.to_be_bytes());
+        out.extend_from_slice(&self.len.to_be_bytes());
+        out.extend_from_slice(&self.checksum.to_be_bytes());
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let invalid = || KeyValueStoreError::InvalidFormat("invalid blob reference".to_string());
+
+        if bytes.len() != Self::SIZE {
+            return Err(invalid());
+        }
+
+        let u64_at = |i: usize| u64::from_be_bytes(bytes[i..i + 8].try_into().expect("8 bytes"));
+
+        Ok(Self {
+            file: u64_at(0),
+            offset: u64_at(8),
+            len: u64_at(16),
+            checksum: u32::from_be_bytes(bytes[24..].try_into().expect("4 bytes")),
+        })
+    }
+}
+
+/// Returns the blob the value record `record`, as stored in a data file,
+/// refers to, unless its value is stored in the record itself.
+pub fn record_blob(record: &[u8]) -> Option<BlobRef> {
+    let kind = RecordKind::from_flag(*record.get(12)?)?;
+
+    if !kind.is_blob() {
+        return None;
+    }
+
+    let key_len = u64::from_be_bytes(record.get(13..21)?.try_into().ok()?) as usize;
+    BlobRef::decode(record.get(29 + key_len..)?).ok()
+}
+
+/// Calls `f` with the blob each of `entries` refers to if its value is kept
+/// in a blob file, reading their records from the data files in `root`.
+/// Entries whose data file can't be mapped are skipped.
+pub fn entry_blobs<I, F>(root: &Path, entries: I, mut f: F)
+where
+    I: IntoIterator<Item = Entry>,
+    F: FnMut(BlobRef),
+{
+    let mut maps: HashMap<u64, Arc<Mmap>> = HashMap::new();
+
+    for entry in entries {
+        let map = match maps.get(&entry.file()) {
+            Some(map) => map,
+            None => match FileMap::open(data_path(root, entry.file())) {
+                Ok(map) => maps.entry(entry.file()).or_insert(map.map()),
+                Err(_) => continue,
+            },
+        };
+
+        let start = entry.offset() as usize;
+        let record = map.get(start..start + entry.size() as usize);
+
+        if let Some(blob) = record.and_then(record_blob) {
+            f(blob);
+        }
+    }
+}
+
+/// The blob files of a store. Values are appended to the active blob file
+/// until it reaches the size limit, after which it is sealed and never
+/// written to again, so every blob file but the active one is immutable.
+pub struct BlobFiles {
+    root: PathBuf,
+    next: u64,
+    active: Option<BlobWriter>,
+    /// Bytes of values in each blob file that were overwritten or deleted
+    /// since the store was opened. Only kept in memory, so it starts from
+    /// zero on every open.
+    dead: HashMap<u64, u64>,
+}
+
+struct BlobWriter {
+    id: u64,
+    file: File,
+    len: u64,
+}
+
+impl BlobFiles {
+    /// Picks up the blob files in `root`. The first value written goes to a
+    /// new blob file, so the existing ones stay sealed.
+    pub fn open(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            next: walk_blobs(root).last().map_or(0, |id| id + 1),
+            active: None,
+            dead: HashMap::new(),
+        }
+    }
+
+    /// Whether the store has had blob files since it was opened.
+    pub fn any(&self) -> bool {
+        self.next > 0
+    }
+
+    /// Copies exactly `len` bytes from `reader` to the end of the active blob
+    /// file, starting a new one first if the value would take it past
+    /// `limit`. If the reader fails or ends early the file is truncated
+    /// again, or removed if the value was its first. The value is only flushed; see [`BlobFiles::sync`].
+    pub fn append<R>(&mut self, mut reader: R, len: u64, limit: u64) -> Result<BlobRef>
+    where
+        R: Read,
+    {
+        let header = BLOB_FILE_HEADER.len() as u64;
+
+        if let Some(active) = &self.active {
+            if active.len > header && active.len + len > limit {
+                self.seal()?;
+            }
+        }
+
+        if self.active.is_none() {
+            self.active = Some(self.create()?);
+        }
+
+        let active = self.active.as_mut().expect("active blob file");
+        let offset = active.len;
+
+        let crc = crc_init();
+        let mut digest = crc.digest();
+
+        let written = (|| -> io::Result<()> {
+            let mut buf = vec![0u8; CHUNK_SIZE.min(len as usize)];
+            let mut left = len;
+
+            while left > 0 {
+                let n = left.min(CHUNK_SIZE as u64) as usize;
+                reader.read_exact(&mut buf[..n])?;
+                digest.update(&buf[..n]);
+                active.file.write_all(&buf[..n])?;
+                left -= n as u64;
+            }
+
+            active.file.flush()
+        })();
+
+        if let Err(e) = written {
+            match offset == header {
+                true => {
+                    let id = active.id;
+                    self.remove(id)?
+                }
+                false => active.file.set_len(offset)?,
+            }
+            return Err(e.into());
+        }
+
+        active.len += len;
+
+        Ok(BlobRef {
+            file: active.id,
+            offset,
+            len,
+            checksum: digest.finalize(),
+        })
+    }
+
+    /// Forces the active blob file to disk.
+    pub fn sync(&mut self) -> Result<()> {
+        if let Some(active) = &self.active {
+            active.file.sync_all()?;
+        }
+
+        Ok(())
+    }
+
+    /// A second handle to the active blob file, if there is one, for syncing
+    /// it without holding the store.
+    #[cfg(feature = "async")]
+    pub fn try_clone_active(&self) -> io::Result<Option<File>> {
+        self.active
+            .as_ref()
+            .map(|active| active.file.try_clone())
+            .transpose()
+    }
+
+    /// Syncs and seals the active blob file, so the next value starts a new
+    /// one.
+    pub fn seal(&mut self) -> Result<()> {
+        self.sync()?;
+        self.active = None;
+
+        Ok(())
+    }
+
+    /// Counts `len` bytes of values in the blob file `id` as garbage.
+    pub fn mark_dead(&mut self, id: u64, len: u64) {
+        *self.dead.entry(id).or_default() += len;
+    }
+
+    /// The bytes of values in blob files that were overwritten or deleted
+    /// since the store was opened.
+    pub fn bytes_dead(&self) -> u64 {
+        self.dead.values().sum()
+    }
+
+    /// Deletes the blob file `id`, sealing it first if it is the active one.
+    pub fn remove(&mut self, id: u64) -> Result<()> {
+        if self.active.as_ref().map(|active| active.id) == Some(id) {
+            self.active = None;
+        }
+
+        self.dead.remove(&id);
+        std::fs::remove_file(blob_path(&self.root, id))?;
+
+        Ok(())
+    }
+
+    fn create(&mut self) -> Result<BlobWriter> {
+        let id = self.next;
+
+        let mut file = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(blob_path(&self.root, id))?;
+
+        file.write_all(BLOB_FILE_HEADER)?;
+        self.next += 1;
+
+        Ok(BlobWriter {
+            id,
+            file,
+            len: BLOB_FILE_HEADER.len() as u64,
+        })
+    }
+}
+
+/// Returns the map of the blob file `id`, from `maps` if it holds the file
+/// and from `root` otherwise.
+pub fn map_blob(root: &Path, maps: &HashMap<u64, Arc<FileMap>>, id: u64) -> Result<Arc<Mmap>> {
+    match maps.get(&id) {
+        Some(map) => Ok(map.map()),
+        None => Ok(FileMap::open(blob_path(root, id))?.map()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcask::settings::Options;
+    use crate::bitcask::Bitcask;
+    use std::fs;
+
+    fn store(name: &str) -> Options {
+        let root = std::env::temp_dir().join(format!("kvs-blob-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let mut opts = Options::new(root);
+        opts.blob_threshold = Some(10);
+        opts
+    }
+
+    fn value(kv: &Bitcask, key: &[u8]) -> Option<Vec<u8>> {
+        kv.get(key).unwrap().map(|v| v.data().to_vec())
+    }
+
+    /// Writes a to d to blob file 0, then `overwritten` of them again to
+    /// blob file 1 after reopening the store.
+    fn fill(opts: &Options, overwritten: &[&[u8]]) -> Bitcask {
+        let mut kv = Bitcask::open(opts.clone()).unwrap();
+
+        for key in [b"a", b"b", b"c", b"d"] {
+            kv.insert(key, &[b'0'; 100]).unwrap();
+        }
+        drop(kv);
+
+        let mut kv = Bitcask::open(opts.clone()).unwrap();
+
+        for key in overwritten {
+            kv.insert(key, &[b'1'; 100]).unwrap();
+        }
+
+        kv
+    }
+
+    #[test]
+    fn merge_moves_live_values_out_of_mostly_dead_files() {
+        let opts = store("dead");
+        let mut kv = fill(&opts, &[b"a", b"b", b"c"]);
+
+        assert_eq!(kv.merge_blobs().unwrap(), 1);
+        assert_eq!(walk_blobs(kv.root()), vec![1, 2]);
+        drop(kv);
+
+        let kv = Bitcask::open(opts).unwrap();
+
+        for key in [b"a", b"b", b"c"] {
+            assert_eq!(value(&kv, key).unwrap(), [b'1'; 100]);
+        }
+        assert_eq!(value(&kv, b"d").unwrap(), [b'0'; 100]);
+    }
+
+    #[test]
+    fn merge_keeps_mostly_live_files() {
+        let opts = store("live");
+        let mut kv = fill(&opts, &[]);
+        kv.delete(b"b").unwrap();
+
+        assert_eq!(kv.merge_blobs().unwrap(), 0);
+        assert_eq!(walk_blobs(kv.root()), vec![0]);
+        drop(kv);
+
+        let kv = Bitcask::open(opts).unwrap();
+
+        assert_eq!(value(&kv, b"a").unwrap(), [b'0'; 100]);
+        assert_eq!(value(&kv, b"b"), None);
+        assert_eq!(value(&kv, b"c").unwrap(), [b'0'; 100]);
+        assert_eq!(value(&kv, b"d").unwrap(), [b'0'; 100]);
+    }
+}
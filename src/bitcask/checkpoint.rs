@@ -0,0 +1,250 @@
+use super::throttle::RateLimiter;
+use super::util::{
+    blob_path, data_path, idx_path, sync_dir, tmp_idx_path, walk_blobs, walk_dir, DATA_FILE_HEADER,
+};
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// A checkpoint whose files have been picked while the store was held, and
+/// that is then written to its directory without holding the store.
+///
+/// The sealed data files and the blob files are opened when the checkpoint
+/// is prepared, so they can still be copied if a merge deletes them before
+/// [`Checkpoint::write`] gets to them. They are never written again, so the
+/// copies match the index written at the same time.
+pub struct Checkpoint {
+    dest: PathBuf,
+    data: Vec<Source>,
+    blobs: Vec<Source>,
+    active: u64,
+}
+
+struct Source {
+    id: u64,
+    path: PathBuf,
+    file: File,
+}
+
+impl Source {
+    fn open(id: u64, path: PathBuf) -> io::Result<Self> {
+        let file = File::open(&path)?;
+        Ok(Self { id, path, file })
+    }
+}
+
+/// Prepares a checkpoint of the sealed data files `ids` and all blob files
+/// from `root` into `dest`, writing the index file with `write_index` to a
+/// temporary file that only replaces the index in `dest` once every file is
+/// in place. `active` is the id of the empty data file the checkpoint
+/// appends to once it is opened.
+///
+/// In incremental mode data files already present in `dest` with the same
+/// length and modification time are kept as they are, as are blob files,
+/// which never change, and files in `dest` that are no longer part of the
+/// store are removed.
+pub fn prepare<F>(
+    root: &Path,
+    dest: &Path,
+    ids: &[u64],
+    active: u64,
+    write_index: F,
+    incremental: bool,
+) -> io::Result<Checkpoint>
+where
+    F: FnOnce(&Path) -> io::Result<()>,
+{
+    fs::create_dir_all(dest)?;
+
+    if !incremental && !walk_dir(dest).is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "checkpoint directory already contains data files",
+        ));
+    }
+
+    let data = ids
+        .iter()
+        .map(|&id| Source::open(id, data_path(root, id)))
+        .collect::<io::Result<_>>()?;
+
+    let blobs = walk_blobs(root)
+        .into_iter()
+        .map(|id| Source::open(id, blob_path(root, id)))
+        .collect::<io::Result<_>>()?;
+
+    write_index(&tmp_idx_path(dest))?;
+
+    Ok(Checkpoint {
+        dest: dest.to_path_buf(),
+        data,
+        blobs,
+        active,
+    })
+}
+
+impl Checkpoint {
+    /// Links or copies the files into the checkpoint directory, copying no
+    /// faster than `limiter` allows, and syncs them and the directory.
+    pub fn write(self, limiter: &RateLimiter) -> io::Result<()> {
+        let dest = &self.dest;
+
+        // until the new index is in place, the checkpoint is rebuilt from
+        // its data files when opened
+        match fs::remove_file(idx_path(dest)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+
+        for src in &self.data {
+            let dst = data_path(dest, src.id);
+
+            if dst.exists() {
+                if same_file(&src.file.metadata()?, &fs::metadata(&dst)?)? {
+                    continue;
+                }
+                fs::remove_file(&dst)?;
+            }
+
+            link_or_copy(src, &dst, limiter)?;
+        }
+
+        for id in walk_dir(dest) {
+            if !self.data.iter().any(|src| src.id == id) {
+                fs::remove_file(data_path(dest, id))?;
+            }
+        }
+
+        for src in &self.blobs {
+            let dst = blob_path(dest, src.id);
+
+            if !dst.exists() {
+                link_or_copy(src, &dst, limiter)?;
+            }
+        }
+
+        for id in walk_blobs(dest) {
+            if !self.blobs.iter().any(|src| src.id == id) {
+                fs::remove_file(blob_path(dest, id))?;
+            }
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(data_path(dest, self.active))?;
+
+        file.write_all(DATA_FILE_HEADER)?;
+        file.sync_all()?;
+
+        let index = tmp_idx_path(dest);
+        File::open(&index)?.sync_all()?;
+        fs::rename(&index, idx_path(dest))?;
+
+        sync_dir(dest)
+    }
+}
+
+/// Whether a file kept from an earlier checkpoint still has the contents of
+/// the store's file. Copies are given the modification time of the file they
+/// were copied from, and links share it.
+fn same_file(src: &Metadata, dst: &Metadata) -> io::Result<bool> {
+    Ok(src.len() == dst.len() && src.modified()? == dst.modified()?)
+}
+
+/// Hard links `src` to `dest`, falling back to a copy when the link cannot be
+/// created, e.g. because `dest` is on another filesystem or a merge deleted
+/// `src` since it was opened. Either way the file is synced.
+fn link_or_copy(src: &Source, dest: &Path, limiter: &RateLimiter) -> io::Result<()> {
+    if fs::hard_link(&src.path, dest).is_ok() {
+        return src.file.sync_all();
+    }
+
+    let mut reader = limiter.reader(&src.file);
+    let mut file = File::create(dest)?;
+
+    io::copy(&mut reader, &mut file)?;
+    file.set_modified(src.file.metadata()?.modified()?)?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcask::settings::Options;
+    use crate::bitcask::Bitcask;
+
+    fn store(name: &str) -> Options {
+        let root =
+            std::env::temp_dir().join(format!("kvs-checkpoint-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let mut opts = Options::new(root.join("store"));
+        opts.blob_threshold = Some(10);
+        opts
+    }
+
+    fn value(kv: &Bitcask, key: &[u8]) -> Option<Vec<u8>> {
+        kv.get(key).unwrap().map(|v| v.data().to_vec())
+    }
+
+    #[test]
+    fn full_checkpoint() {
+        let opts = store("full");
+        let dest = opts.base_dir.with_file_name("dest");
+        let mut kv = Bitcask::open(opts).unwrap();
+
+        kv.insert(b"a", b"1").unwrap();
+        kv.insert(b"b", &[b'1'; 100]).unwrap();
+        kv.insert(b"c", b"1").unwrap();
+        kv.delete(b"c").unwrap();
+
+        kv.checkpoint(&dest).unwrap();
+        kv.insert(b"a", b"2").unwrap();
+        kv.insert(b"d", b"2").unwrap();
+
+        assert!(kv.checkpoint(&dest).is_err());
+
+        let copy = Bitcask::open(Options::new(&dest)).unwrap();
+
+        assert_eq!(value(&copy, b"a").unwrap(), b"1");
+        assert_eq!(value(&copy, b"b").unwrap(), [b'1'; 100]);
+        assert_eq!(value(&copy, b"c"), None);
+        assert_eq!(value(&copy, b"d"), None);
+    }
+
+    #[test]
+    fn incremental_checkpoint() {
+        let opts = store("incremental");
+        let dest = opts.base_dir.with_file_name("dest");
+        let mut kv = Bitcask::open(opts).unwrap();
+
+        for key in [b"a", b"b", b"c", b"d"] {
+            kv.insert(key, b"1").unwrap();
+        }
+        kv.checkpoint(&dest).unwrap();
+
+        // file 0 is mostly overwritten and merged away
+        for key in [b"a", b"b", b"c"] {
+            kv.insert(key, b"2").unwrap();
+        }
+        kv.insert(b"e", &[b'2'; 100]).unwrap();
+        kv.swap_file().unwrap();
+        kv.merge().unwrap();
+
+        kv.checkpoint_incremental(&dest).unwrap();
+
+        // the files of the store, and an empty active file of its own
+        assert!(!walk_dir(&dest).contains(&0));
+        assert_eq!(walk_dir(&dest), walk_dir(kv.root()));
+        assert_eq!(walk_blobs(&dest), walk_blobs(kv.root()));
+
+        let copy = Bitcask::open(Options::new(&dest)).unwrap();
+
+        for key in [b"a", b"b", b"c"] {
+            assert_eq!(value(&copy, key).unwrap(), b"2");
+        }
+        assert_eq!(value(&copy, b"d").unwrap(), b"1");
+        assert_eq!(value(&copy, b"e").unwrap(), [b'2'; 100]);
+    }
+}
@@ -0,0 +1,86 @@
+use super::error::Result;
+use super::index::{Entry, Index};
+use super::iter::{FileCache, IndexRange};
+use super::reader::Value;
+use std::ops::Bound;
+
+/// A seekable cursor over the index, modelled after RocksDB's iterator.
+///
+/// A new cursor is not positioned; call one of the `seek` methods before
+/// reading. Once the cursor moves past either end of the index it becomes
+/// invalid until it is seeked again.
+pub struct Cursor<'a> {
+    index: &'a Index,
+    cache: FileCache,
+    current: Option<(&'a [u8], Entry)>,
+}
+
+impl<'a> Cursor<'a> {
+    /// Fails with [`KeyValueStoreError::Unsupported`](crate::KeyValueStoreError) if `index` is a hash
+    /// index, which keeps no order to move through.
+    pub fn new(cache: FileCache, index: &'a Index) -> Result<Self> {
+        index.check_ordered()?;
+
+        Ok(Self {
+            index,
+            cache,
+            current: None,
+        })
+    }
+
+    pub fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    pub fn seek_to_first(&mut self) {
+        self.current = self.range(Bound::Unbounded, Bound::Unbounded).next();
+    }
+
+    pub fn seek_to_last(&mut self) {
+        self.current = self.range(Bound::Unbounded, Bound::Unbounded).next_back();
+    }
+
+    /// Positions the cursor at the first key that is greater than or equal
+    /// to `key`.
+    pub fn seek(&mut self, key: &[u8]) {
+        self.current = self.range(Bound::Included(key), Bound::Unbounded).next();
+    }
+
+    /// Positions the cursor at the last key that is less than or equal to
+    /// `key`.
+    pub fn seek_for_prev(&mut self, key: &[u8]) {
+        self.current = self
+            .range(Bound::Unbounded, Bound::Included(key))
+            .next_back();
+    }
+
+    pub fn next(&mut self) {
+        if let Some((key, _)) = self.current {
+            self.current = self.range(Bound::Excluded(key), Bound::Unbounded).next();
+        }
+    }
+
+    pub fn prev(&mut self) {
+        if let Some((key, _)) = self.current {
+            self.current = self
+                .range(Bound::Unbounded, Bound::Excluded(key))
+                .next_back();
+        }
+    }
+
+    pub fn key(&self) -> Option<&'a [u8]> {
+        self.current.map(|(k, _)| k)
+    }
+
+    pub fn timestamp(&self) -> Option<u64> {
+        self.current.map(|(_, e)| e.timestamp())
+    }
+
+    pub fn value(&self) -> Option<Result<Value>> {
+        self.current.map(|(_, e)| self.cache.get(&e))
+    }
+
+    fn range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> IndexRange<'a> {
+        self.index.range((start, end)).expect("ordered index")
+    }
+}
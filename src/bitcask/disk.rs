@@ -1,40 +1,67 @@
+use super::reader::RecordKind;
 use crate::api::WriteExt;
 use crc::{Crc, CRC_32_CKSUM};
 use std::fs::File;
-use std::io::{self, BufWriter, Seek, Write};
+use std::io::{self, BufWriter, Write};
 
 pub struct DiskTable {
     writer: BufWriter<File>,
+    /// The offset of the next write. It is kept here rather than asked from
+    /// the writer, which flushes its buffer to find it out.
+    position: u64,
 }
 
 impl DiskTable {
-    pub fn open(file: File) -> Self {
+    /// Opens a table over `file`, appending to its end.
+    pub fn open(file: File) -> io::Result<Self> {
+        let position = file.metadata()?.len();
         let writer = BufWriter::with_capacity(8192, file);
-        Self { writer }
+
+        Ok(Self { writer, position })
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
     }
 
-    pub fn flush(&mut self) {
-        std::io::Write::flush(&mut self.writer).expect("flush");
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()
+    }
+
+    /// A second handle to the file, for syncing it without holding the
+    /// table. Only what was flushed before the sync reaches the disk.
+    pub fn try_clone_file(&self) -> io::Result<File> {
+        self.writer.get_ref().try_clone()
     }
 
     pub fn append_entry(&mut self, timestamp: u64, key: &[u8], value: &[u8]) -> (u64, u64) {
+        self.append_record(RecordKind::Value, timestamp, key, value)
+    }
+
+    /// Appends a record that has a value, returning its offset and size.
+    pub fn append_record(
+        &mut self,
+        kind: RecordKind,
+        timestamp: u64,
+        key: &[u8],
+        value: &[u8],
+    ) -> (u64, u64) {
+        debug_assert!(!kind.is_tombstone());
+
         let crc = Crc::<u32>::new(&CRC_32_CKSUM);
         let mut digest = crc.digest();
         digest.update(&timestamp.to_be_bytes());
-        digest.update(&0u8.to_be_bytes());
+        digest.update(&kind.flag().to_be_bytes());
         digest.update(&(key.len() as u64).to_be_bytes());
         digest.update(&(value.len() as u64).to_be_bytes());
         digest.update(key);
         digest.update(value);
         let checksum = digest.finalize();
 
-        self.writer
-            .seek(std::io::SeekFrom::End(0))
-            .expect("seek end");
-
         let offset = self.write_u32(checksum).expect("write checksum");
         self.write_u64(timestamp).expect("write u64");
-        self.write_u8(0).expect("write u8");
+        self.write_u8(kind.flag()).expect("write u8");
         self.write_u64(key.len() as u64).expect("write u64");
         self.write_u64(value.len() as u64).expect("write u64");
         self.write(key).expect("write key");
@@ -44,21 +71,24 @@ impl DiskTable {
     }
 
     pub fn delete(&mut self, timestamp: u64, key: &[u8]) {
+        self.append_tombstone(RecordKind::Tombstone, timestamp, key);
+    }
+
+    /// Appends a record without a value.
+    pub fn append_tombstone(&mut self, kind: RecordKind, timestamp: u64, key: &[u8]) {
+        debug_assert!(kind.is_tombstone());
+
         let crc = Crc::<u32>::new(&CRC_32_CKSUM);
         let mut digest = crc.digest();
         digest.update(&timestamp.to_be_bytes());
-        digest.update(&u8::MAX.to_be_bytes());
+        digest.update(&kind.flag().to_be_bytes());
         digest.update(&(key.len() as u64).to_be_bytes());
         digest.update(key);
         let checksum = digest.finalize();
 
-        self.writer
-            .seek(std::io::SeekFrom::End(0))
-            .expect("seek end");
-
         self.write_u32(checksum).expect("write u32");
         self.write_u64(timestamp).expect("write u64");
-        self.write_u8(u8::MAX).expect("write u8");
+        self.write_u8(kind.flag()).expect("write u8");
         self.write_u64(key.len() as u64).expect("write u64");
         self.write(key).expect("write key");
     }
@@ -66,12 +96,13 @@ impl DiskTable {
 
 impl WriteExt for DiskTable {
     fn position(&mut self) -> u64 {
-        self.writer.stream_position().expect("") as u64
+        self.position
     }
 
     fn write(&mut self, bytes: &[u8]) -> io::Result<u64> {
-        let pos = self.writer.stream_position().expect("stream position") as u64;
+        let pos = self.position;
         self.writer.write_all(bytes)?;
+        self.position += bytes.len() as u64;
         Ok(pos)
     }
 }
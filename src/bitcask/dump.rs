@@ -0,0 +1,489 @@
+//! Logical dumps of a store.
+//!
+//! A dump starts with [`DUMP_FILE_HEADER`] followed by the format version as
+//! a big endian `u32`. Every live entry is then written as a record:
+//!
+//! | Field     | Size            |
+//! |-----------|-----------------|
+//! | tag (`1`) | `u8`            |
+//! | timestamp | `u64`           |
+//! | key len   | `u64`           |
+//! | value len | `u64`           |
+//! | key       | key len bytes   |
+//! | value     | value len bytes |
+//! | checksum  | `u32`           |
+//!
+//! Records belong to the default namespace until a namespace record, after
+//! which they belong to the named namespace it starts:
+//!
+//! | Field     | Size           |
+//! |-----------|----------------|
+//! | tag (`2`) | `u8`           |
+//! | name len  | `u64`          |
+//! | name      | name len bytes |
+//! | checksum  | `u32`          |
+//!
+//! The dump ends with a trailer made of a tag of `0`, the number of records
+//! as a `u64` and a checksum. Checksums are CRC-32/CKSUM over all preceding
+//! fields of the record or trailer, and all integers are big endian. Version
+//! 1 dumps have no namespace records.
+//!
+//! The JSON lines variant writes one `{"timestamp":..,"key":"..","value":".."}`
+//! object per entry, with keys and values hex encoded, and a
+//! `{"namespace":".."}` object with the hex encoded name to start a namespace.
+
+use super::error::{KeyValueStoreError, Result};
+use super::util::{crc_init, DUMP_FILE_HEADER, DUMP_FORMAT_VERSION};
+use std::io::{self, BufRead, Read, Write};
+
+const RECORD_TAG: u8 = 1;
+const NAMESPACE_TAG: u8 = 2;
+const TRAILER_TAG: u8 = 0;
+
+/// An item read from a dump.
+pub enum Item {
+    /// The records that follow belong to this namespace.
+    Namespace(String),
+    Record(Record),
+}
+
+pub struct Record {
+    pub timestamp: u64,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+pub struct DumpWriter<W> {
+    writer: W,
+    count: u64,
+}
+
+impl<W> DumpWriter<W>
+where
+    W: Write,
+{
+    pub fn new(mut writer: W) -> Result<Self> {
+        writer.write_all(DUMP_FILE_HEADER)?;
+        writer.write_all(&DUMP_FORMAT_VERSION.to_be_bytes())?;
+
+        Ok(Self { writer, count: 0 })
+    }
+
+    pub fn write(&mut self, timestamp: u64, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut buf = Vec::with_capacity(25 + key.len() + value.len());
+        buf.push(RECORD_TAG);
+        buf.extend_from_slice(&timestamp.to_be_bytes());
+        buf.extend_from_slice(&(key.len() as u64).to_be_bytes());
+        buf.extend_from_slice(&(value.len() as u64).to_be_bytes());
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(value);
+
+        let checksum = crc_init().checksum(&buf);
+
+        self.writer.write_all(&buf)?;
+        self.writer.write_all(&checksum.to_be_bytes())?;
+        self.count += 1;
+
+        Ok(())
+    }
+
+    /// Starts the namespace `name`, to which the records written next belong.
+    pub fn namespace(&mut self, name: &str) -> Result<()> {
+        let mut buf = Vec::with_capacity(9 + name.len());
+        buf.push(NAMESPACE_TAG);
+        buf.extend_from_slice(&(name.len() as u64).to_be_bytes());
+        buf.extend_from_slice(name.as_bytes());
+
+        let checksum = crc_init().checksum(&buf);
+
+        self.writer.write_all(&buf)?;
+        self.writer.write_all(&checksum.to_be_bytes())?;
+
+        Ok(())
+    }
+
+    /// Writes the trailer and returns the number of records in the dump.
+    pub fn finish(mut self) -> Result<u64> {
+        let mut buf = Vec::with_capacity(9);
+        buf.push(TRAILER_TAG);
+        buf.extend_from_slice(&self.count.to_be_bytes());
+
+        let checksum = crc_init().checksum(&buf);
+
+        self.writer.write_all(&buf)?;
+        self.writer.write_all(&checksum.to_be_bytes())?;
+        self.writer.flush()?;
+
+        Ok(self.count)
+    }
+}
+
+pub struct DumpReader<R> {
+    reader: R,
+    count: u64,
+    done: bool,
+}
+
+impl<R> DumpReader<R>
+where
+    R: Read,
+{
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut header = [0u8; DUMP_FILE_HEADER.len()];
+        reader.read_exact(&mut header)?;
+
+        if header != DUMP_FILE_HEADER {
+            return Err(invalid("not a dump file"));
+        }
+
+        let version = read_u32(&mut reader)?;
+
+        if version == 0 || version > DUMP_FORMAT_VERSION {
+            return Err(invalid(format!("unsupported dump version {}", version)));
+        }
+
+        Ok(Self {
+            reader,
+            count: 0,
+            done: false,
+        })
+    }
+
+    fn read_item(&mut self) -> Result<Option<Item>> {
+        let mut tag = [0u8; 1];
+        self.reader.read_exact(&mut tag)?;
+
+        let crc = crc_init();
+        let mut digest = crc.digest();
+        digest.update(&tag);
+
+        match tag[0] {
+            RECORD_TAG => {
+                let mut header = [0u8; 24];
+                self.reader.read_exact(&mut header)?;
+                digest.update(&header);
+
+                let timestamp = u64::from_be_bytes(header[0..8].try_into().unwrap());
+                let key_len = u64::from_be_bytes(header[8..16].try_into().unwrap());
+                let value_len = u64::from_be_bytes(header[16..24].try_into().unwrap());
+
+                let key = read_bytes(&mut self.reader, key_len)?;
+                digest.update(&key);
+
+                let value = read_bytes(&mut self.reader, value_len)?;
+                digest.update(&value);
+
+                if read_u32(&mut self.reader)? != digest.finalize() {
+                    return Err(KeyValueStoreError::ChecksumFailed);
+                }
+
+                self.count += 1;
+
+                Ok(Some(Item::Record(Record {
+                    timestamp,
+                    key,
+                    value,
+                })))
+            }
+            NAMESPACE_TAG => {
+                let len = read_u64(&mut self.reader)?;
+                digest.update(&len.to_be_bytes());
+
+                let name = read_bytes(&mut self.reader, len)?;
+                digest.update(&name);
+
+                if read_u32(&mut self.reader)? != digest.finalize() {
+                    return Err(KeyValueStoreError::ChecksumFailed);
+                }
+
+                let name =
+                    String::from_utf8(name).map_err(|_| invalid("namespace name is not utf-8"))?;
+
+                Ok(Some(Item::Namespace(name)))
+            }
+            TRAILER_TAG => {
+                let count = read_u64(&mut self.reader)?;
+                digest.update(&count.to_be_bytes());
+
+                if read_u32(&mut self.reader)? != digest.finalize() {
+                    return Err(KeyValueStoreError::ChecksumFailed);
+                }
+
+                if count != self.count {
+                    return Err(invalid(format!(
+                        "dump has {} records, trailer expects {}",
+                        self.count, count
+                    )));
+                }
+
+                Ok(None)
+            }
+            tag => Err(invalid(format!("unknown record tag {}", tag))),
+        }
+    }
+}
+
+impl<R> Iterator for DumpReader<R>
+where
+    R: Read,
+{
+    type Item = Result<Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.read_item() {
+            Ok(Some(item)) => Some(Ok(item)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+pub fn write_json_line<W>(writer: &mut W, timestamp: u64, key: &[u8], value: &[u8]) -> Result<()>
+where
+    W: Write,
+{
+    writeln!(
+        writer,
+        r#"{{"timestamp":{},"key":"{}","value":"{}"}}"#,
+        timestamp,
+        to_hex(key),
+        to_hex(value)
+    )?;
+
+    Ok(())
+}
+
+/// Writes the JSON line starting the namespace `name`.
+pub fn write_json_namespace<W>(writer: &mut W, name: &str) -> Result<()>
+where
+    W: Write,
+{
+    writeln!(writer, r#"{{"namespace":"{}"}}"#, to_hex(name.as_bytes()))?;
+
+    Ok(())
+}
+
+/// Reads JSON lines as written by [`write_json_line`] and
+/// [`write_json_namespace`], skipping blank lines.
+pub fn read_json_lines<R>(reader: R) -> impl Iterator<Item = Result<Item>>
+where
+    R: BufRead,
+{
+    reader.lines().filter_map(|line| match line {
+        Ok(line) if line.trim().is_empty() => None,
+        Ok(line) => Some(parse_json_line(&line)),
+        Err(e) => Some(Err(e.into())),
+    })
+}
+
+fn parse_json_line(line: &str) -> Result<Item> {
+    let body = line
+        .trim()
+        .strip_prefix('{')
+        .and_then(|s| s.strip_suffix('}'))
+        .ok_or_else(|| invalid("expected a json object"))?;
+
+    let mut timestamp = None;
+    let mut key = None;
+    let mut value = None;
+    let mut namespace = None;
+
+    for field in body.split(',') {
+        let (name, val) = field
+            .split_once(':')
+            .ok_or_else(|| invalid("expected a json field"))?;
+
+        let name = unquote(name)?;
+        let val = val.trim();
+
+        match name {
+            "timestamp" => {
+                let ts = val.parse().map_err(|_| invalid("invalid timestamp"))?;
+                timestamp = Some(ts);
+            }
+            "key" => key = Some(from_hex(unquote(val)?)?),
+            "value" => value = Some(from_hex(unquote(val)?)?),
+            "namespace" => namespace = Some(from_hex(unquote(val)?)?),
+            _ => return Err(invalid(format!("unknown field {}", name))),
+        }
+    }
+
+    match (timestamp, key, value, namespace) {
+        (Some(timestamp), Some(key), Some(value), None) => Ok(Item::Record(Record {
+            timestamp,
+            key,
+            value,
+        })),
+        (None, None, None, Some(name)) => String::from_utf8(name)
+            .map(Item::Namespace)
+            .map_err(|_| invalid("namespace name is not utf-8")),
+        _ => Err(invalid("missing field")),
+    }
+}
+
+fn unquote(s: &str) -> Result<&str> {
+    s.trim()
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .ok_or_else(|| invalid("expected a json string"))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+
+    for b in bytes {
+        s.push_str(&format!("{:02x}", b));
+    }
+
+    s
+}
+
+fn from_hex(s: &str) -> Result<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return Err(invalid("invalid hex string"));
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| invalid("invalid hex string")))
+        .collect()
+}
+
+/// Reads `len` bytes, growing the buffer as they arrive, so a corrupt length
+/// ends in an error at the end of the dump rather than a huge allocation.
+fn read_bytes<R: Read>(reader: &mut R, len: u64) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    reader.by_ref().take(len).read_to_end(&mut buf)?;
+
+    if (buf.len() as u64) < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+
+    Ok(buf)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn invalid<S>(msg: S) -> KeyValueStoreError
+where
+    S: Into<String>,
+{
+    KeyValueStoreError::InvalidFormat(msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitcask::settings::Options;
+    use crate::bitcask::Bitcask;
+    use std::fs;
+
+    fn store(name: &str) -> Options {
+        let root = std::env::temp_dir().join(format!("kvs-dump-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        Options::new(root)
+    }
+
+    /// The timestamp and value of `key`, in the namespace `space` if set.
+    fn entry(kv: &mut Bitcask, space: Option<&str>, key: &[u8]) -> Option<(u64, Vec<u8>)> {
+        let value = match space {
+            Some(name) => kv.namespace(name).unwrap().get(key).unwrap(),
+            None => kv.get(key).unwrap(),
+        };
+
+        value.map(|v| (v.timestamp(), v.data().to_vec()))
+    }
+
+    const KEYS: [(Option<&str>, &[u8]); 5] = [
+        (None, b"a"),
+        (None, b"b"),
+        (None, b"gone"),
+        (Some("users"), b"a"),
+        (Some("users"), b"\x00\xff"),
+    ];
+
+    fn source(name: &str) -> Bitcask {
+        let mut kv = Bitcask::open(store(name)).unwrap();
+
+        kv.insert(b"a", b"1").unwrap();
+        kv.insert(b"b", b"").unwrap();
+        kv.insert(b"gone", b"1").unwrap();
+        kv.delete(b"gone").unwrap();
+
+        let mut users = kv.namespace("users").unwrap();
+        users.insert(b"a", b"2").unwrap();
+        users.insert(b"\x00\xff", b"\"\\\n").unwrap();
+        kv.namespace("empty").unwrap();
+
+        kv
+    }
+
+    /// Imports a dump with `import` into a new store, reopens it and checks
+    /// that it holds the same entries as `src`.
+    fn check<F>(name: &str, src: &mut Bitcask, import: F)
+    where
+        F: FnOnce(&mut Bitcask) -> u64,
+    {
+        let opts = store(name);
+        let mut kv = Bitcask::open(opts.clone()).unwrap();
+        assert_eq!(import(&mut kv), 4);
+        drop(kv);
+
+        let mut kv = Bitcask::open(opts).unwrap();
+
+        for (space, key) in KEYS {
+            assert_eq!(entry(&mut kv, space, key), entry(src, space, key));
+        }
+        assert_eq!(kv.namespaces(), ["empty", "users"]);
+    }
+
+    #[test]
+    fn dump_round_trip() {
+        let mut src = source("src");
+        let mut dump = Vec::new();
+        assert_eq!(src.export(&mut dump).unwrap(), 4);
+
+        check("dst", &mut src, |kv| kv.import(&dump[..]).unwrap());
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let mut src = source("json-src");
+        let mut dump = Vec::new();
+        assert_eq!(src.export_json(&mut dump).unwrap(), 4);
+
+        check("json-dst", &mut src, |kv| {
+            kv.import_json(&dump[..]).unwrap()
+        });
+    }
+
+    #[test]
+    fn truncated_dump_is_invalid() {
+        let src = source("truncated-src");
+        let mut dump = Vec::new();
+        src.export(&mut dump).unwrap();
+        dump.truncate(dump.len() - 1);
+
+        let mut kv = Bitcask::open(store("truncated-dst")).unwrap();
+        assert!(kv.import(&dump[..]).is_err());
+    }
+}
@@ -6,6 +6,21 @@ pub enum KeyValueStoreError {
     PayloadTooLarge,
     ChecksumFailed,
     ItemDeleted,
+    ReadOnly,
+    InvalidFormat(String),
+    /// A key or value could not be encoded or decoded by a codec.
+    Codec(String),
+    /// No secondary index with this name is registered.
+    UnknownIndex(String),
+    /// The operation is not supported by the store's configuration.
+    Unsupported(String),
+    /// A merge was cancelled before it finished.
+    Cancelled,
+    /// A merge is already running.
+    MergeRunning,
+    /// A thread panicked while it held the store or ran a merge.
+    Panicked,
+    Io(std::io::Error),
 }
 
 impl std::fmt::Display for KeyValueStoreError {
@@ -15,10 +30,46 @@ impl std::fmt::Display for KeyValueStoreError {
             Self::PayloadTooLarge => f.write_str("payload too large"),
             Self::ChecksumFailed => f.write_str("checksum failed"),
             Self::ItemDeleted => f.write_str("item deleted"),
+            Self::ReadOnly => f.write_str("store is read only"),
+            Self::InvalidFormat(msg) => write!(f, "invalid format: {}", msg),
+            Self::Codec(msg) => write!(f, "codec error: {}", msg),
+            Self::UnknownIndex(name) => write!(f, "unknown index: {}", name),
+            Self::Unsupported(msg) => write!(f, "unsupported: {}", msg),
+            Self::Cancelled => f.write_str("merge cancelled"),
+            Self::MergeRunning => f.write_str("a merge is already running"),
+            Self::Panicked => f.write_str("a thread using the store panicked"),
+            Self::Io(e) => write!(f, "io error: {}", e),
         }
     }
 }
 
 impl Error for KeyValueStoreError {}
 
+impl Clone for KeyValueStoreError {
+    /// I/O errors are cloned as a new error with the same kind and message.
+    fn clone(&self) -> Self {
+        match self {
+            Self::NoInsert => Self::NoInsert,
+            Self::PayloadTooLarge => Self::PayloadTooLarge,
+            Self::ChecksumFailed => Self::ChecksumFailed,
+            Self::ItemDeleted => Self::ItemDeleted,
+            Self::ReadOnly => Self::ReadOnly,
+            Self::InvalidFormat(msg) => Self::InvalidFormat(msg.clone()),
+            Self::Codec(msg) => Self::Codec(msg.clone()),
+            Self::UnknownIndex(name) => Self::UnknownIndex(name.clone()),
+            Self::Unsupported(msg) => Self::Unsupported(msg.clone()),
+            Self::Cancelled => Self::Cancelled,
+            Self::MergeRunning => Self::MergeRunning,
+            Self::Panicked => Self::Panicked,
+            Self::Io(e) => Self::Io(std::io::Error::new(e.kind(), e.to_string())),
+        }
+    }
+}
+
+impl From<std::io::Error> for KeyValueStoreError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
 pub type Result<T> = std::result::Result<T, KeyValueStoreError>;
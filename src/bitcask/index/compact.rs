@@ -0,0 +1,601 @@
+use super::Entry;
+use std::collections::{btree_map, BTreeMap, HashMap};
+use std::ops::{Bound, RangeBounds, RangeFull};
+use std::sync::Arc;
+
+/// The delta is merged into the run once it holds more than this many keys
+/// and more than `1 / DELTA_RATIO` of the keys in the run.
+const MIN_DELTA: usize = 4096;
+const DELTA_RATIO: usize = 8;
+
+/// Marks a packed entry whose fields do not fit in 32 bits.
+const WIDE: u32 = u32::MAX;
+
+/// An index for stores with a very large number of keys.
+///
+/// Most keys are kept in a sorted run: the keys are stored one after another
+/// in a single buffer and found by binary search over 32-bit offsets into it,
+/// and every entry is packed into 16 bytes with 32-bit file ids, offsets,
+/// sizes and timestamps. Entries with a field that does not fit are kept
+/// aside at full size. That makes a key cost its own length plus about 20
+/// bytes, where a `BTreeMap` spends around 100 bytes on top of the key.
+///
+/// Writes go to a small `BTreeMap` delta, which is merged into a new run once
+/// it grows past a fraction of the run. Keys inserted in order into an empty
+/// delta, as when loading the index file, are appended to the run directly.
+#[derive(Debug, Clone)]
+pub struct CompactIndex {
+    run: Arc<Run>,
+    /// Writes since the run was built, `None` marking keys deleted from it.
+    delta: Arc<BTreeMap<Vec<u8>, Option<Entry>>>,
+    len: usize,
+}
+
+impl CompactIndex {
+    pub fn new() -> Self {
+        Self {
+            run: Arc::new(Run::default()),
+            delta: Arc::new(BTreeMap::new()),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Entry> {
+        match self.delta.get(key) {
+            Some(entry) => *entry,
+            None => self.run.find(key).ok().map(|i| self.run.entry(i)),
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8], entry: Entry) -> Option<Entry> {
+        let prev = self.get(key);
+
+        if prev.is_none() {
+            self.len += 1;
+
+            let in_order = self.run.last_key().is_none_or(|last| last < key);
+
+            if self.delta.is_empty() && in_order {
+                if let Some(run) = Arc::get_mut(&mut self.run) {
+                    run.push(key, entry);
+                    return None;
+                }
+            }
+        }
+
+        Arc::make_mut(&mut self.delta).insert(key.to_vec(), Some(entry));
+        self.maybe_compact();
+
+        prev
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Option<Entry> {
+        let prev = self.get(key)?;
+        self.len -= 1;
+
+        let delta = Arc::make_mut(&mut self.delta);
+        match self.run.find(key) {
+            Ok(_) => delta.insert(key.to_vec(), None),
+            Err(_) => delta.remove(key),
+        };
+
+        self.maybe_compact();
+
+        Some(prev)
+    }
+
+    pub fn range<R>(&self, range: R) -> Merged<'_>
+    where
+        R: RangeBounds<[u8]>,
+    {
+        let lo = self.run.lower_bound(range.start_bound());
+        let hi = self.run.upper_bound(range.end_bound());
+
+        Merged {
+            run: &self.run,
+            lo,
+            hi: hi.max(lo),
+            delta: self.delta.range::<[u8], _>(range),
+            front: None,
+            back: None,
+        }
+    }
+
+    /// Merges the delta into the run, or releases the memory reserved for
+    /// keys appended to the run.
+    pub fn shrink_to_fit(&mut self) {
+        if !self.delta.is_empty() {
+            self.compact();
+        } else if let Some(run) = Arc::get_mut(&mut self.run) {
+            run.shrink_to_fit();
+        }
+    }
+
+    fn maybe_compact(&mut self) {
+        if self.delta.len() > MIN_DELTA.max(self.run.len() / DELTA_RATIO) {
+            self.compact();
+        }
+    }
+
+    /// Merges the delta into a new run.
+    fn compact(&mut self) {
+        let key_bytes = self.run.keys.len() + self.delta.keys().map(Vec::len).sum::<usize>();
+        let mut run = Run::with_capacity(self.len, key_bytes);
+
+        for (key, entry) in self.range::<RangeFull>(..) {
+            run.push(key, entry);
+        }
+
+        run.shrink_to_fit();
+
+        self.run = Arc::new(run);
+        self.delta = Arc::new(BTreeMap::new());
+    }
+}
+
+/// Keys in sorted order with their entries.
+#[derive(Debug, Clone, Default)]
+struct Run {
+    keys: Vec<u8>,
+    /// The low 32 bits of the start of every key in `keys`.
+    starts: Vec<u32>,
+    /// The position of the first key starting past each 4 GiB of `keys`.
+    wraps: Vec<usize>,
+    entries: Vec<Packed>,
+    /// Entries that do not fit in a [`Packed`], by position.
+    wide: HashMap<usize, Entry>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Packed {
+    file: u32,
+    offset: u32,
+    size: u32,
+    timestamp: u32,
+}
+
+impl Packed {
+    fn new(entry: &Entry) -> Option<Self> {
+        let narrow = |n: u64| u32::try_from(n).ok().filter(|&n| n != WIDE);
+
+        Some(Self {
+            file: narrow(entry.file())?,
+            offset: narrow(entry.offset())?,
+            size: narrow(entry.size())?,
+            timestamp: narrow(entry.timestamp())?,
+        })
+    }
+}
+
+impl Run {
+    fn with_capacity(len: usize, key_bytes: usize) -> Self {
+        Self {
+            keys: Vec::with_capacity(key_bytes),
+            starts: Vec::with_capacity(len),
+            entries: Vec::with_capacity(len),
+            ..Self::default()
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.starts.len()
+    }
+
+    fn start(&self, i: usize) -> usize {
+        let wraps = self.wraps.partition_point(|&w| w <= i) as u64;
+        ((wraps << 32) | self.starts[i] as u64) as usize
+    }
+
+    fn key(&self, i: usize) -> &[u8] {
+        let end = match i + 1 < self.len() {
+            true => self.start(i + 1),
+            false => self.keys.len(),
+        };
+
+        &self.keys[self.start(i)..end]
+    }
+
+    fn last_key(&self) -> Option<&[u8]> {
+        self.len().checked_sub(1).map(|i| self.key(i))
+    }
+
+    fn entry(&self, i: usize) -> Entry {
+        let packed = self.entries[i];
+
+        match packed.file {
+            WIDE => self.wide[&i],
+            _ => Entry::from(
+                packed.timestamp as u64,
+                packed.file as u64,
+                packed.offset as u64,
+                packed.size as u64,
+            ),
+        }
+    }
+
+    /// Appends a key greater than every key in the run.
+    fn push(&mut self, key: &[u8], entry: Entry) {
+        let i = self.len();
+        let start = self.keys.len() as u64;
+
+        while start >> 32 > self.wraps.len() as u64 {
+            self.wraps.push(i);
+        }
+
+        self.starts.push(start as u32);
+        self.keys.extend_from_slice(key);
+
+        match Packed::new(&entry) {
+            Some(packed) => self.entries.push(packed),
+            None => {
+                self.entries.push(Packed {
+                    file: WIDE,
+                    offset: 0,
+                    size: 0,
+                    timestamp: 0,
+                });
+                self.wide.insert(i, entry);
+            }
+        }
+    }
+
+    fn shrink_to_fit(&mut self) {
+        self.keys.shrink_to_fit();
+        self.starts.shrink_to_fit();
+        self.entries.shrink_to_fit();
+    }
+
+    fn find(&self, key: &[u8]) -> Result<usize, usize> {
+        let i = self.partition_point(|k| k < key);
+
+        match i < self.len() && self.key(i) == key {
+            true => Ok(i),
+            false => Err(i),
+        }
+    }
+
+    /// The position of the first key within `bound`.
+    fn lower_bound(&self, bound: Bound<&[u8]>) -> usize {
+        match bound {
+            Bound::Included(key) => self.partition_point(|k| k < key),
+            Bound::Excluded(key) => self.partition_point(|k| k <= key),
+            Bound::Unbounded => 0,
+        }
+    }
+
+    /// The position after the last key within `bound`.
+    fn upper_bound(&self, bound: Bound<&[u8]>) -> usize {
+        match bound {
+            Bound::Included(key) => self.partition_point(|k| k <= key),
+            Bound::Excluded(key) => self.partition_point(|k| k < key),
+            Bound::Unbounded => self.len(),
+        }
+    }
+
+    /// The position of the first key for which `pred` is false, given that
+    /// it is true for every key before and false for every key after it.
+    fn partition_point<F>(&self, pred: F) -> usize
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        let (mut lo, mut hi) = (0, self.len());
+
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            match pred(self.key(mid)) {
+                true => lo = mid + 1,
+                false => hi = mid,
+            }
+        }
+
+        lo
+    }
+}
+
+type DeltaItem<'a> = (&'a Vec<u8>, &'a Option<Entry>);
+
+/// Iterates over a range of the run and the delta together, in key order,
+/// with the delta taking precedence over the run.
+pub struct Merged<'a> {
+    run: &'a Run,
+    /// The range of the run not yet returned from either end.
+    lo: usize,
+    hi: usize,
+    delta: btree_map::Range<'a, Vec<u8>, Option<Entry>>,
+    /// Delta items taken from `delta` but not returned yet.
+    front: Option<DeltaItem<'a>>,
+    back: Option<DeltaItem<'a>>,
+}
+
+impl<'a> Merged<'a> {
+    fn peek_front(&mut self) -> Option<DeltaItem<'a>> {
+        if self.front.is_none() {
+            self.front = self.delta.next().or_else(|| self.back.take());
+        }
+
+        self.front
+    }
+
+    fn peek_back(&mut self) -> Option<DeltaItem<'a>> {
+        if self.back.is_none() {
+            self.back = self.delta.next_back().or_else(|| self.front.take());
+        }
+
+        self.back
+    }
+}
+
+impl<'a> Iterator for Merged<'a> {
+    type Item = (&'a [u8], Entry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let run_key = (self.lo < self.hi).then(|| self.run.key(self.lo));
+
+            match (run_key, self.peek_front()) {
+                (None, None) => return None,
+                (Some(key), Some((delta_key, _))) if key < delta_key.as_slice() => {}
+                (run_key, Some((delta_key, entry))) => {
+                    self.front = None;
+
+                    if run_key == Some(delta_key.as_slice()) {
+                        self.lo += 1;
+                    }
+
+                    match entry {
+                        Some(entry) => return Some((delta_key, *entry)),
+                        None => continue,
+                    }
+                }
+                (Some(_), None) => {}
+            }
+
+            let i = self.lo;
+            self.lo += 1;
+
+            return Some((self.run.key(i), self.run.entry(i)));
+        }
+    }
+}
+
+impl DoubleEndedIterator for Merged<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            let run_key = (self.lo < self.hi).then(|| self.run.key(self.hi - 1));
+
+            match (run_key, self.peek_back()) {
+                (None, None) => return None,
+                (Some(key), Some((delta_key, _))) if key > delta_key.as_slice() => {}
+                (run_key, Some((delta_key, entry))) => {
+                    self.back = None;
+
+                    if run_key == Some(delta_key.as_slice()) {
+                        self.hi -= 1;
+                    }
+
+                    match entry {
+                        Some(entry) => return Some((delta_key, *entry)),
+                        None => continue,
+                    }
+                }
+                (Some(_), None) => {}
+            }
+
+            self.hi -= 1;
+
+            return Some((self.run.key(self.hi), self.run.entry(self.hi)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(n: u64) -> Entry {
+        Entry::from(n, 1, n * 10, 20)
+    }
+
+    fn key(n: u32) -> Vec<u8> {
+        n.to_be_bytes().to_vec()
+    }
+
+    /// An index whose run holds the even keys below `2 * n` and whose delta
+    /// holds odd keys added between them, overwrites of some run keys and
+    /// deletes of others, along with a model of its contents.
+    fn split_index(n: u32) -> (CompactIndex, BTreeMap<Vec<u8>, Entry>) {
+        let mut index = CompactIndex::new();
+        let mut model = BTreeMap::new();
+
+        for i in (0..2 * n).step_by(2) {
+            index.insert(&key(i), entry(i as u64));
+            model.insert(key(i), entry(i as u64));
+        }
+
+        assert!(index.delta.is_empty());
+
+        for i in 0..2 * n {
+            match i % 6 {
+                // a new key between two run keys
+                1 | 3 => {
+                    index.insert(&key(i), entry(i as u64 + 1000));
+                    model.insert(key(i), entry(i as u64 + 1000));
+                }
+                // an overwritten run key
+                2 => {
+                    assert_eq!(
+                        index.insert(&key(i), entry(i as u64 + 2000)),
+                        Some(entry(i as u64))
+                    );
+                    model.insert(key(i), entry(i as u64 + 2000));
+                }
+                // a deleted run key
+                4 => {
+                    assert_eq!(index.delete(&key(i)), Some(entry(i as u64)));
+                    model.remove(&key(i));
+                }
+                _ => {}
+            }
+        }
+
+        // a key added to the delta and deleted again
+        index.insert(&key(2 * n + 1), entry(0));
+        index.delete(&key(2 * n + 1));
+
+        assert!(!index.delta.is_empty());
+        assert_eq!(index.len(), model.len());
+
+        (index, model)
+    }
+
+    fn collect<'a, I>(iter: I) -> Vec<(Vec<u8>, Entry)>
+    where
+        I: Iterator<Item = (&'a [u8], Entry)>,
+    {
+        iter.map(|(k, e)| (k.to_vec(), e)).collect()
+    }
+
+    fn expected<R>(model: &BTreeMap<Vec<u8>, Entry>, range: R) -> Vec<(Vec<u8>, Entry)>
+    where
+        R: RangeBounds<[u8]>,
+    {
+        model
+            .range::<[u8], _>(range)
+            .map(|(k, e)| (k.clone(), *e))
+            .collect()
+    }
+
+    #[test]
+    fn forward_and_reverse() {
+        let (index, model) = split_index(50);
+        let all = expected(&model, ..);
+
+        assert_eq!(collect(index.range::<RangeFull>(..)), all);
+
+        let mut reversed = collect(index.range::<RangeFull>(..).rev());
+        reversed.reverse();
+        assert_eq!(reversed, all);
+
+        for (k, e) in &all {
+            assert_eq!(index.get(k), Some(*e));
+        }
+    }
+
+    #[test]
+    fn both_ends_meet() {
+        let (index, model) = split_index(50);
+        let all = expected(&model, ..);
+
+        let mut iter = index.range::<RangeFull>(..);
+        let (mut front, mut back) = (Vec::new(), Vec::new());
+
+        while let Some((k, e)) = iter.next() {
+            front.push((k.to_vec(), e));
+
+            match iter.next_back() {
+                Some((k, e)) => back.push((k.to_vec(), e)),
+                None => break,
+            }
+        }
+
+        back.reverse();
+        front.extend(back);
+        assert_eq!(front, all);
+    }
+
+    type RangeOf<'a> = (Bound<&'a [u8]>, Bound<&'a [u8]>);
+
+    #[test]
+    fn bounded_ranges() {
+        let (index, model) = split_index(50);
+
+        // bounds on run keys, delta keys, deleted keys and missing keys
+        for (lo, hi) in [
+            (0, 99),
+            (2, 4),
+            (3, 9),
+            (4, 10),
+            (5, 5),
+            (16, 15),
+            (90, 200),
+        ] {
+            let (lo, hi) = (key(lo), key(hi));
+
+            let ranges: [RangeOf<'_>; 3] = [
+                (Bound::Included(&lo), Bound::Excluded(&hi)),
+                (Bound::Excluded(&lo), Bound::Included(&hi)),
+                (Bound::Included(&lo), Bound::Unbounded),
+            ];
+
+            for range in ranges {
+                if lo > hi && range.1 != Bound::Unbounded {
+                    continue;
+                }
+
+                assert_eq!(collect(index.range(range)), expected(&model, range));
+
+                let mut reversed = collect(index.range(range).rev());
+                reversed.reverse();
+                assert_eq!(reversed, expected(&model, range));
+            }
+        }
+    }
+
+    #[test]
+    fn compact_keeps_contents() {
+        let (mut index, model) = split_index(50);
+        let before = index.clone();
+
+        index.shrink_to_fit();
+
+        assert!(index.delta.is_empty());
+        assert_eq!(index.run.len(), model.len());
+        assert_eq!(collect(index.range::<RangeFull>(..)), expected(&model, ..));
+
+        // the clone taken before still sees its own run and delta
+        assert!(!before.delta.is_empty());
+        assert_eq!(collect(before.range::<RangeFull>(..)), expected(&model, ..));
+    }
+
+    #[test]
+    fn compacts_large_delta() {
+        let mut index = CompactIndex::new();
+        let mut model = BTreeMap::new();
+
+        // inserted out of order, so every key goes to the delta
+        for i in (0..MIN_DELTA as u32 + 10).rev() {
+            index.insert(&key(i), entry(i as u64));
+            model.insert(key(i), entry(i as u64));
+        }
+
+        assert!(index.delta.len() <= MIN_DELTA);
+        assert!(index.run.len() > 0);
+        assert_eq!(index.len(), model.len());
+        assert_eq!(collect(index.range::<RangeFull>(..)), expected(&model, ..));
+    }
+
+    #[test]
+    fn wide_entries() {
+        let mut index = CompactIndex::new();
+        let wide = Entry::from(1, u32::MAX as u64, 1 << 40, 7);
+
+        index.insert(b"a", entry(1));
+        index.insert(b"b", wide);
+        index.insert(b"c", entry(3));
+        index.shrink_to_fit();
+
+        assert_eq!(index.get(b"b"), Some(wide));
+        assert_eq!(
+            collect(index.range::<RangeFull>(..).rev()),
+            vec![
+                (b"c".to_vec(), entry(3)),
+                (b"b".to_vec(), wide),
+                (b"a".to_vec(), entry(1)),
+            ]
+        );
+    }
+}
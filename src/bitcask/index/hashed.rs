@@ -0,0 +1,383 @@
+use super::Entry;
+use crate::bitcask::error::Result;
+use crate::bitcask::reader::FileMap;
+use crate::bitcask::util::data_path;
+use crc::{Crc, CRC_64_XZ};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const KEY_HASH: Crc<u64> = Crc::<u64>::new(&CRC_64_XZ);
+
+/// The hash a [`HashedIndex`] keeps in place of `key`. It is stored in the
+/// index file, so it must not change between versions.
+pub fn key_hash(key: &[u8]) -> u64 {
+    KEY_HASH.checksum(key)
+}
+
+/// An index that keeps a 64-bit hash of every key instead of the key, so it
+/// takes the same memory whatever the length of the keys.
+///
+/// Looking a key up reads the key of the entry with the same hash back from
+/// the data file to make sure it is the same key. Keys that share their hash
+/// with another key are kept in full, so they are told apart without reading
+/// the data files.
+#[derive(Debug, Clone)]
+pub struct HashedIndex {
+    keys: Arc<KeyReader>,
+    slots: Arc<HashMap<u64, Entry>>,
+    /// The keys of hashes that several keys share.
+    collisions: Arc<HashMap<u64, BTreeMap<Vec<u8>, Entry>>>,
+    len: usize,
+}
+
+impl HashedIndex {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            keys: Arc::new(KeyReader::new(root)),
+            slots: Arc::new(HashMap::new()),
+            collisions: Arc::new(HashMap::new()),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Drops the maps of the data files `ids`.
+    pub fn forget_files(&mut self, ids: &[u64]) {
+        let mut maps = self.keys.maps.lock().expect("maps lock");
+        maps.retain(|id, _| !ids.contains(id));
+    }
+
+    /// Reads keys from `maps` where they hold the file, so keys stay
+    /// readable after a merge removed their files.
+    pub fn use_maps(&mut self, maps: &HashMap<u64, Arc<FileMap>>) {
+        let root = self.keys.root.clone();
+        self.keys = Arc::new(KeyReader::with_maps(&root, maps.clone()));
+    }
+
+    /// Fails if the key of the entry with the same hash can't be read.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Entry>> {
+        let hash = key_hash(key);
+
+        if let Some(keys) = self.collisions.get(&hash) {
+            return Ok(keys.get(key).copied());
+        }
+
+        let Some(entry) = self.slots.get(&hash) else {
+            return Ok(None);
+        };
+
+        match self.keys.key(entry)? == key {
+            true => Ok(Some(*entry)),
+            false => Ok(None),
+        }
+    }
+
+    /// Fails, leaving the index as it was, if the key of the entry with the
+    /// same hash can't be read.
+    pub fn insert(&mut self, key: &[u8], entry: Entry) -> Result<Option<Entry>> {
+        let hash = key_hash(key);
+
+        if self.collisions.contains_key(&hash) {
+            let collisions = Arc::make_mut(&mut self.collisions);
+            let keys = collisions.get_mut(&hash).expect("collision");
+            let prev = keys.insert(key.to_vec(), entry);
+
+            if prev.is_none() {
+                self.len += 1;
+            }
+
+            return Ok(prev);
+        }
+
+        let other = match self.slots.get(&hash) {
+            None => {
+                Arc::make_mut(&mut self.slots).insert(hash, entry);
+                self.len += 1;
+                return Ok(None);
+            }
+            Some(other) => *other,
+        };
+
+        let other_key = self.keys.key(&other)?;
+        let slots = Arc::make_mut(&mut self.slots);
+
+        if other_key == key {
+            return Ok(slots.insert(hash, entry));
+        }
+
+        slots.remove(&hash);
+        let keys = BTreeMap::from([(other_key, other), (key.to_vec(), entry)]);
+        Arc::make_mut(&mut self.collisions).insert(hash, keys);
+        self.len += 1;
+
+        Ok(None)
+    }
+
+    /// Fails, leaving the index as it was, if the key of the entry with the
+    /// same hash can't be read.
+    pub fn delete(&mut self, key: &[u8]) -> Result<Option<Entry>> {
+        let hash = key_hash(key);
+
+        if self.collisions.contains_key(&hash) {
+            let collisions = Arc::make_mut(&mut self.collisions);
+            let keys = collisions.get_mut(&hash).expect("collision");
+            let Some(prev) = keys.remove(key) else {
+                return Ok(None);
+            };
+
+            // the last key left with the hash goes back to the slots
+            if keys.len() == 1 {
+                let (_, entry) = keys.pop_first().expect("key");
+                collisions.remove(&hash);
+                Arc::make_mut(&mut self.slots).insert(hash, entry);
+            }
+
+            self.len -= 1;
+
+            return Ok(Some(prev));
+        }
+
+        let Some(prev) = self.get(key)? else {
+            return Ok(None);
+        };
+
+        Arc::make_mut(&mut self.slots).remove(&hash);
+        self.len -= 1;
+
+        Ok(Some(prev))
+    }
+
+    /// Adds the entry of a key known only by its hash, as read from the
+    /// index file.
+    pub fn insert_hash(&mut self, hash: u64, entry: Entry) {
+        if Arc::make_mut(&mut self.slots).insert(hash, entry).is_none() {
+            self.len += 1;
+        }
+    }
+
+    /// Iterates over every entry with its key, reading the keys that are
+    /// kept as hashes from the data files. Entries whose key can't be read
+    /// are returned as errors.
+    pub fn entries(&self) -> impl Iterator<Item = Result<(Cow<'_, [u8]>, Entry)>> + '_ {
+        let hashed = self
+            .slots
+            .values()
+            .map(|entry| Ok((Cow::Owned(self.keys.key(entry)?), *entry)));
+
+        let collided = self
+            .collisions
+            .values()
+            .flatten()
+            .map(|(key, entry)| Ok((Cow::Borrowed(key.as_slice()), *entry)));
+
+        hashed.chain(collided)
+    }
+
+    pub fn locations(&self) -> impl Iterator<Item = Entry> + '_ {
+        let collided = self.collisions.values().flat_map(|keys| keys.values());
+        self.slots.values().chain(collided).copied()
+    }
+
+    /// The entries kept by the hash of their key.
+    pub fn slots(&self) -> &HashMap<u64, Entry> {
+        &self.slots
+    }
+
+    /// The entries of keys that share their hash, by key.
+    pub fn collided(&self) -> impl Iterator<Item = (&[u8], Entry)> + '_ {
+        self.collisions
+            .values()
+            .flatten()
+            .map(|(key, entry)| (key.as_slice(), *entry))
+    }
+}
+
+/// Reads the keys of entries back from the data files, keeping the files
+/// mapped.
+pub struct KeyReader {
+    root: PathBuf,
+    maps: Mutex<HashMap<u64, Arc<FileMap>>>,
+}
+
+impl KeyReader {
+    pub fn new(root: &Path) -> Self {
+        Self::with_maps(root, HashMap::new())
+    }
+
+    fn with_maps(root: &Path, maps: HashMap<u64, Arc<FileMap>>) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            maps: Mutex::new(maps),
+        }
+    }
+
+    pub fn key(&self, entry: &Entry) -> Result<Vec<u8>> {
+        let map = {
+            let mut maps = self.maps.lock().expect("maps lock");
+
+            match maps.get(&entry.file()) {
+                Some(map) => Arc::clone(map),
+                None => {
+                    let map = Arc::new(FileMap::open(data_path(&self.root, entry.file()))?);
+                    maps.insert(entry.file(), Arc::clone(&map));
+                    map
+                }
+            }
+        };
+
+        map.key(entry.offset(), entry.size())
+    }
+}
+
+impl std::fmt::Debug for KeyReader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyReader")
+            .field("root", &self.root)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcask::disk::DiskTable;
+    use crate::bitcask::util::DATA_FILE_HEADER;
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    /// A directory holding data file 1 with a record of each of `keys`,
+    /// along with the entries of the records.
+    fn data_file(name: &str, keys: &[&[u8]]) -> (PathBuf, Vec<Entry>) {
+        let root = std::env::temp_dir().join(format!("kvs-hashed-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+
+        let mut file = File::create(data_path(&root, 1)).unwrap();
+        file.write_all(DATA_FILE_HEADER).unwrap();
+        let mut disk = DiskTable::open(file).unwrap();
+
+        let entries = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| {
+                let (offset, size) = disk.append_entry(i as u64, key, b"value");
+                Entry::from(i as u64, 1, offset, size)
+            })
+            .collect();
+
+        disk.sync().unwrap();
+
+        (root, entries)
+    }
+
+    /// An index where `b"b"` shares its hash with `b"a"`, as if their hashes
+    /// were the same, along with the entries of `a` and `b`.
+    fn collided_index(name: &str) -> (PathBuf, HashedIndex, Entry, Entry) {
+        let (root, entries) = data_file(name, &[b"a", b"b"]);
+        let (a, b) = (entries[0], entries[1]);
+
+        let mut index = HashedIndex::new(&root);
+        index.insert_hash(key_hash(b"b"), a);
+        assert_eq!(index.insert(b"b", b).unwrap(), None);
+
+        (root, index, a, b)
+    }
+
+    #[test]
+    fn insert_and_get() {
+        let (root, entries) = data_file("insert", &[b"a", b"b"]);
+        let mut index = HashedIndex::new(&root);
+
+        assert_eq!(index.insert(b"a", entries[0]).unwrap(), None);
+        assert_eq!(index.insert(b"b", entries[1]).unwrap(), None);
+        assert_eq!(index.insert(b"a", entries[0]).unwrap(), Some(entries[0]));
+
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.get(b"a").unwrap(), Some(entries[0]));
+        assert_eq!(index.get(b"b").unwrap(), Some(entries[1]));
+        assert_eq!(index.get(b"c").unwrap(), None);
+        assert_eq!(index.collided().count(), 0);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn collision_keeps_both_keys() {
+        let (root, index, a, b) = collided_index("collision");
+
+        assert_eq!(index.len(), 2);
+        assert!(index.slots().is_empty());
+        assert_eq!(index.get(b"a").unwrap(), None);
+        assert_eq!(index.get(b"b").unwrap(), Some(b));
+
+        let mut collided: Vec<_> = index.collided().collect();
+        collided.sort_by_key(|(key, _)| *key);
+        assert_eq!(collided, vec![(&b"a"[..], a), (&b"b"[..], b)]);
+
+        let mut keys: Vec<_> = index
+            .entries()
+            .map(|item| item.unwrap().0.into_owned())
+            .collect();
+        keys.sort();
+        assert_eq!(keys, vec![b"a".to_vec(), b"b".to_vec()]);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn delete_from_collision() {
+        let (root, mut index, a, b) = collided_index("delete");
+
+        assert_eq!(index.delete(b"c").unwrap(), None);
+        assert_eq!(index.delete(b"b").unwrap(), Some(b));
+        assert_eq!(index.delete(b"b").unwrap(), None);
+
+        // the key left goes back to the slot of the shared hash
+        assert_eq!(index.len(), 1);
+        assert_eq!(index.collided().count(), 0);
+        assert_eq!(index.slots().get(&key_hash(b"b")), Some(&a));
+        assert_eq!(index.get(b"b").unwrap(), None);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn reinsert_after_delete() {
+        let (root, mut index, a, b) = collided_index("reinsert");
+
+        assert_eq!(index.delete(b"b").unwrap(), Some(b));
+        assert_eq!(index.insert(b"b", b).unwrap(), None);
+        assert_eq!(index.insert(b"b", a).unwrap(), Some(b));
+
+        assert_eq!(index.len(), 2);
+        assert!(index.slots().is_empty());
+        assert_eq!(index.get(b"b").unwrap(), Some(a));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn unreadable_key_is_an_error() {
+        let (root, entries) = data_file("unreadable", &[b"a"]);
+        let missing = Entry::from(0, 2, entries[0].offset(), entries[0].size());
+
+        let mut index = HashedIndex::new(&root);
+        index.insert_hash(key_hash(b"a"), missing);
+
+        assert!(index.get(b"a").is_err());
+        assert!(index.insert(b"a", entries[0]).is_err());
+        assert!(index.delete(b"a").is_err());
+        assert!(index.entries().all(|item| item.is_err()));
+
+        // the failed calls left the index as it was
+        assert_eq!(index.len(), 1);
+        assert_eq!(index.slots().get(&key_hash(b"a")), Some(&missing));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
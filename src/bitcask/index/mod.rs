@@ -0,0 +1,341 @@
+mod compact;
+mod hashed;
+
+use self::compact::CompactIndex;
+use self::hashed::HashedIndex;
+pub use self::hashed::KeyReader;
+use super::disk::DiskTable;
+use super::error::{KeyValueStoreError, Result};
+use super::iter::IndexRange;
+use super::reader::{self, FileMap};
+use super::settings::IndexKind;
+use crate::api::WriteExt;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::ops::{RangeBounds, RangeFull};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Every entry of an index with its key, see [`Index::entries`].
+pub type IndexEntries<'a> = Box<dyn Iterator<Item = Result<(Cow<'a, [u8]>, Entry)>> + 'a>;
+
+type KeyedEntries<'a> = Box<dyn Iterator<Item = (&'a [u8], Entry)> + 'a>;
+
+/// The in-memory key directory, kept in the representation chosen by
+/// [`IndexKind`]. Cloning an index is cheap: its contents are shared until
+/// one of the clones is modified, at which point that clone makes its own
+/// copy.
+///
+/// Hash indexes keep no order, so [`Index::range`] fails for them and
+/// [`Index::entries`] returns their entries in no particular order. Hashed
+/// key indexes read keys back from the data files in `root`, so looking up,
+/// writing and listing keys fails for them if those files can't be read.
+#[derive(Debug, Clone)]
+pub struct Index {
+    repr: Repr,
+}
+
+#[derive(Debug, Clone)]
+enum Repr {
+    Tree(Arc<BTreeMap<Vec<u8>, Entry>>),
+    Compact(CompactIndex),
+    Hash(Arc<HashMap<Vec<u8>, Entry>>),
+    Hashed(HashedIndex),
+}
+
+impl Index {
+    pub fn new(kind: IndexKind, root: &Path) -> Self {
+        let repr = match kind {
+            IndexKind::BTree => Repr::Tree(Arc::new(BTreeMap::new())),
+            IndexKind::Compact => Repr::Compact(CompactIndex::new()),
+            IndexKind::Hash => Repr::Hash(Arc::new(HashMap::new())),
+            IndexKind::HashedKeys => Repr::Hashed(HashedIndex::new(root)),
+        };
+
+        Self { repr }
+    }
+
+    pub fn kind(&self) -> IndexKind {
+        match self.repr {
+            Repr::Tree(_) => IndexKind::BTree,
+            Repr::Compact(_) => IndexKind::Compact,
+            Repr::Hash(_) => IndexKind::Hash,
+            Repr::Hashed(_) => IndexKind::HashedKeys,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.repr {
+            Repr::Tree(map) => map.len(),
+            Repr::Compact(index) => index.len(),
+            Repr::Hash(map) => map.len(),
+            Repr::Hashed(index) => index.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Applies a record read from the data file `file_id` under `key`,
+    /// unless the index already holds a newer entry for it.
+    pub fn load(&mut self, key: &[u8], entry: &reader::Entry, file_id: u64) -> Result<()> {
+        if let Some(curr) = self.get(key)? {
+            if curr.timestamp() > entry.timestamp() {
+                return Ok(());
+            }
+        }
+
+        if entry.is_deleted() {
+            self.delete(key)?;
+            return Ok(());
+        }
+
+        self.insert(
+            key,
+            entry.timestamp(),
+            file_id,
+            entry.offset(),
+            entry.size(),
+        )?;
+
+        Ok(())
+    }
+
+    /// Releases memory held for further writes, for use once the index has
+    /// been loaded.
+    pub fn shrink_to_fit(&mut self) {
+        if let Repr::Compact(index) = &mut self.repr {
+            index.shrink_to_fit();
+        }
+    }
+
+    /// Drops the maps of the data files `ids` kept to read keys from, once
+    /// a merge deleted them.
+    pub fn forget_files(&mut self, ids: &[u64]) {
+        if let Repr::Hashed(index) = &mut self.repr {
+            index.forget_files(ids);
+        }
+    }
+
+    /// Reads keys from the files in `maps`, which stay readable when a merge
+    /// removes them.
+    pub fn use_maps(&mut self, maps: &HashMap<u64, Arc<FileMap>>) {
+        if let Repr::Hashed(index) = &mut self.repr {
+            index.use_maps(maps);
+        }
+    }
+
+    /// Iterates over every entry, in key order unless this is a hash index.
+    /// Hashed key indexes read the keys from the data files, returning an
+    /// error for every entry whose key can't be read.
+    pub fn entries(&self) -> IndexEntries<'_> {
+        match &self.repr {
+            Repr::Hashed(index) => Box::new(index.entries()),
+            _ => Box::new(
+                self.keyed_entries()
+                    .into_iter()
+                    .flatten()
+                    .map(borrowed)
+                    .map(Ok),
+            ),
+        }
+    }
+
+    /// Iterates over every entry without its key.
+    pub fn locations(&self) -> Box<dyn Iterator<Item = Entry> + '_> {
+        match &self.repr {
+            Repr::Hashed(index) => Box::new(index.locations()),
+            _ => Box::new(
+                self.keyed_entries()
+                    .into_iter()
+                    .flatten()
+                    .map(|(_, entry)| entry),
+            ),
+        }
+    }
+
+    /// Iterates over every entry of an index that keeps its keys in memory,
+    /// or returns nothing for hashed key indexes.
+    fn keyed_entries(&self) -> Option<KeyedEntries<'_>> {
+        match &self.repr {
+            Repr::Tree(map) => Some(Box::new(map.iter().map(tree_item))),
+            Repr::Compact(index) => Some(Box::new(index.range::<RangeFull>(..))),
+            Repr::Hash(map) => Some(Box::new(map.iter().map(tree_item))),
+            Repr::Hashed(_) => None,
+        }
+    }
+
+    /// Fails with [`KeyValueStoreError::Unsupported`] for hash indexes.
+    pub fn check_ordered(&self) -> Result<()> {
+        match self.repr {
+            Repr::Hash(_) | Repr::Hashed(_) => Err(unordered()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn range<R>(&self, range: R) -> Result<IndexRange<'_>>
+    where
+        R: RangeBounds<[u8]>,
+    {
+        match &self.repr {
+            Repr::Tree(map) => Ok(Box::new(map.range::<[u8], _>(range).map(tree_item))),
+            Repr::Compact(index) => Ok(Box::new(index.range(range))),
+            Repr::Hash(_) | Repr::Hashed(_) => Err(unordered()),
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Entry>> {
+        match &self.repr {
+            Repr::Tree(map) => Ok(map.get(key).copied()),
+            Repr::Compact(index) => Ok(index.get(key)),
+            Repr::Hash(map) => Ok(map.get(key).copied()),
+            Repr::Hashed(index) => index.get(key),
+        }
+    }
+
+    pub fn insert(
+        &mut self,
+        key: &[u8],
+        timestamp: u64,
+        file: u64,
+        offset: u64,
+        size: u64,
+    ) -> Result<Option<Entry>> {
+        let entry = Entry::from(timestamp, file, offset, size);
+
+        match &mut self.repr {
+            Repr::Tree(map) => Ok(Arc::make_mut(map).insert(key.to_vec(), entry)),
+            Repr::Compact(index) => Ok(index.insert(key, entry)),
+            Repr::Hash(map) => Ok(Arc::make_mut(map).insert(key.to_vec(), entry)),
+            Repr::Hashed(index) => index.insert(key, entry),
+        }
+    }
+
+    /// Adds the entry of a key known only by its hash, as stored by hashed
+    /// key indexes in the index file, reading the key with `keys` unless
+    /// this index is one too.
+    pub fn insert_hash(&mut self, hash: u64, entry: Entry, keys: &KeyReader) -> Result<()> {
+        match &mut self.repr {
+            Repr::Hashed(index) => index.insert_hash(hash, entry),
+            _ => {
+                let key = keys.key(&entry)?;
+                let (ts, file, offset, size) = (
+                    entry.timestamp(),
+                    entry.file(),
+                    entry.offset(),
+                    entry.size(),
+                );
+                self.insert(&key, ts, file, offset, size)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<Option<Entry>> {
+        match &mut self.repr {
+            Repr::Tree(map) => Ok(Arc::make_mut(map).remove(key)),
+            Repr::Compact(index) => Ok(index.delete(key)),
+            Repr::Hash(map) => Ok(Arc::make_mut(map).remove(key)),
+            Repr::Hashed(index) => index.delete(key),
+        }
+    }
+
+    /// Writes the number of entries followed by every entry, in the layout
+    /// of the index file. Hashed key indexes write the number of hashes and
+    /// every hash with its entry, followed by the keys that share a hash,
+    /// written like the entries of other indexes.
+    pub fn write_entries(&self, disk: &mut DiskTable) -> io::Result<()> {
+        let index = match &self.repr {
+            Repr::Hashed(index) => index,
+            _ => {
+                disk.write_u64(self.len() as u64)?;
+
+                for (k, v) in self.keyed_entries().into_iter().flatten() {
+                    write_entry(disk, k, &v)?;
+                }
+
+                return Ok(());
+            }
+        };
+
+        disk.write_u64(index.slots().len() as u64)?;
+
+        for (hash, v) in index.slots() {
+            disk.write_u64(*hash)?;
+            write_location(disk, v)?;
+        }
+
+        let collided: Vec<_> = index.collided().collect();
+        disk.write_u64(collided.len() as u64)?;
+
+        for (k, v) in collided {
+            write_entry(disk, k, &v)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn write_entry(disk: &mut DiskTable, key: &[u8], entry: &Entry) -> io::Result<()> {
+    disk.write_u64(key.len() as u64)?;
+    disk.write(key)?;
+    write_location(disk, entry)
+}
+
+fn write_location(disk: &mut DiskTable, entry: &Entry) -> io::Result<()> {
+    disk.write_u64(entry.timestamp())?;
+    disk.write_u64(entry.file())?;
+    disk.write_u64(entry.offset())?;
+    disk.write_u64(entry.size())?;
+    Ok(())
+}
+
+fn borrowed((key, entry): (&[u8], Entry)) -> (Cow<'_, [u8]>, Entry) {
+    (Cow::Borrowed(key), entry)
+}
+
+fn unordered() -> KeyValueStoreError {
+    KeyValueStoreError::Unsupported("range scans need an ordered index".to_string())
+}
+
+fn tree_item<'a>((key, entry): (&'a Vec<u8>, &'a Entry)) -> (&'a [u8], Entry) {
+    (key, *entry)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    file: u64,
+    timestamp: u64,
+    offset: u64,
+    size: u64,
+}
+
+impl Entry {
+    pub fn from(timestamp: u64, file: u64, offset: u64, size: u64) -> Self {
+        Self {
+            timestamp,
+            file,
+            offset,
+            size,
+        }
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn file(&self) -> u64 {
+        self.file
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
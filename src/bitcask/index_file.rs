@@ -0,0 +1,355 @@
+use super::disk::DiskTable;
+use super::error::{KeyValueStoreError, Result};
+use super::index::{self, Index, KeyReader};
+use super::namespace::{Namespaces, DEFAULT_NAMESPACE};
+use super::reader::{Entry, IndexReader};
+use super::secondary::{SecondaryIndex, SecondaryIndexes};
+use super::settings::IndexKind;
+use super::util::{INDEX_FILE_HEADER, INDEX_FORMAT_MARKER, INDEX_FORMAT_VERSION};
+use crate::api::WriteExt;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::OpenOptions;
+use std::io;
+use std::path::Path;
+
+const NAMESPACE_SECTION: u8 = 1;
+const SECONDARY_INDEX_SECTION: u8 = 2;
+const HASHED_NAMESPACE_SECTION: u8 = 3;
+
+/// Reads the index file at `path` into `index`, the index of the default
+/// namespace, the other namespaces and the secondary indexes. Version 1 files
+/// only hold the default namespace, version 2 files only namespaces, and
+/// version 3 files no hashed namespaces. Fails if a key kept as a hash
+/// can't be read back from the data files, and with
+/// [`KeyValueStoreError::InvalidFormat`] if the file is cut short or holds a
+/// section it doesn't know.
+pub fn read<P>(
+    path: P,
+    index: &mut Index,
+    namespaces: &mut Namespaces,
+    secondary: &mut SecondaryIndexes,
+) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let root = path
+        .as_ref()
+        .parent()
+        .expect("store directory")
+        .to_path_buf();
+    let mut ir = IndexReader::open(path)?;
+
+    if ir.version() == 1 {
+        while let Some(entry) = ir.next()? {
+            insert(index, &entry)?;
+        }
+
+        return Ok(());
+    }
+
+    namespaces.set_next_id(ir.read_u32()?);
+
+    while !ir.at_end()? {
+        // version 2 files hold namespace sections without a tag
+        let tag = match ir.version() {
+            2 => NAMESPACE_SECTION,
+            _ => ir.read_u8()?,
+        };
+
+        match tag {
+            NAMESPACE_SECTION => read_namespace(&mut ir, index, namespaces)?,
+            SECONDARY_INDEX_SECTION => read_secondary(&mut ir, secondary)?,
+            HASHED_NAMESPACE_SECTION => {
+                let keys = KeyReader::new(&root);
+                read_hashed_namespace(&mut ir, index, namespaces, &keys)?
+            }
+            _ => {
+                return Err(KeyValueStoreError::InvalidFormat(format!(
+                    "unknown index file section {}",
+                    tag
+                )))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the index file: the header, the format version and the next
+/// namespace id, followed by tagged sections. There is a section per
+/// namespace, starting with the default one, holding the namespace id, name
+/// and entries, and a section per secondary index holding its name and every
+/// index key with the primary keys listed under it. Namespaces whose index
+/// keeps hashes of the keys get a hashed namespace section instead, holding
+/// the hashes in place of most keys.
+pub fn write<P>(
+    path: P,
+    index: &Index,
+    namespaces: &Namespaces,
+    secondary: &SecondaryIndexes,
+) -> io::Result<()>
+where
+    P: AsRef<Path>,
+{
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path.as_ref())?;
+
+    let mut disk = DiskTable::open(file)?;
+    disk.write(INDEX_FILE_HEADER)?;
+    disk.write_u64(INDEX_FORMAT_MARKER)?;
+    disk.write_u32(INDEX_FORMAT_VERSION)?;
+    disk.write_u32(namespaces.next_id())?;
+
+    write_namespace(&mut disk, DEFAULT_NAMESPACE, "", index)?;
+
+    for (id, space) in namespaces.iter() {
+        write_namespace(&mut disk, id, &space.name, &space.index)?;
+    }
+
+    for (name, index) in secondary.iter() {
+        disk.write_u8(SECONDARY_INDEX_SECTION)?;
+        write_bytes(&mut disk, name.as_bytes())?;
+        disk.write_u64(index.map().len() as u64)?;
+
+        for (index_key, keys) in index.map() {
+            write_bytes(&mut disk, index_key)?;
+            disk.write_u64(keys.len() as u64)?;
+
+            for key in keys {
+                write_bytes(&mut disk, key)?;
+            }
+        }
+    }
+
+    disk.flush()
+}
+
+fn read_namespace(
+    ir: &mut IndexReader,
+    index: &mut Index,
+    namespaces: &mut Namespaces,
+) -> Result<()> {
+    let target = namespace_index(ir, index, namespaces)?;
+    let count = ir.read_u64()?;
+
+    for _ in 0..count {
+        insert(target, &ir.read_entry()?)?;
+    }
+
+    Ok(())
+}
+
+/// Reads a hashed namespace section, whose keys are read back from the data
+/// files with `keys` unless `index` keeps hashes too.
+fn read_hashed_namespace(
+    ir: &mut IndexReader,
+    index: &mut Index,
+    namespaces: &mut Namespaces,
+    keys: &KeyReader,
+) -> Result<()> {
+    let target = namespace_index(ir, index, namespaces)?;
+    let count = ir.read_u64()?;
+
+    for _ in 0..count {
+        let hash = ir.read_u64()?;
+        let (ts, file) = (ir.read_u64()?, ir.read_u64()?);
+        let (offset, size) = (ir.read_u64()?, ir.read_u64()?);
+        target.insert_hash(hash, index::Entry::from(ts, file, offset, size), keys)?;
+    }
+
+    let count = ir.read_u64()?;
+
+    for _ in 0..count {
+        insert(target, &ir.read_entry()?)?;
+    }
+
+    Ok(())
+}
+
+/// Reads the id and name at the start of a namespace section, returning the
+/// index of the namespace.
+fn namespace_index<'a>(
+    ir: &mut IndexReader,
+    index: &'a mut Index,
+    namespaces: &'a mut Namespaces,
+) -> Result<&'a mut Index> {
+    let id = ir.read_u32()?;
+    let name = read_name(ir)?;
+
+    match id {
+        DEFAULT_NAMESPACE => Ok(index),
+        _ => {
+            namespaces.insert(id, name);
+            Ok(&mut namespaces.get_mut(id).expect("namespace").index)
+        }
+    }
+}
+
+fn read_secondary(ir: &mut IndexReader, secondary: &mut SecondaryIndexes) -> Result<()> {
+    let name = read_name(ir)?;
+    let count = ir.read_u64()?;
+    let mut map = BTreeMap::new();
+
+    for _ in 0..count {
+        let index_key = ir.read_bytes()?;
+        let keys = (0..ir.read_u64()?)
+            .map(|_| ir.read_bytes())
+            .collect::<Result<BTreeSet<Vec<u8>>>>()?;
+        map.insert(index_key, keys);
+    }
+
+    secondary.insert(&name, SecondaryIndex::loaded(map));
+    Ok(())
+}
+
+/// Reads the name of a namespace or secondary index.
+fn read_name(ir: &mut IndexReader) -> Result<String> {
+    String::from_utf8(ir.read_bytes()?).map_err(|_| {
+        KeyValueStoreError::InvalidFormat("index file holds a name that isn't UTF-8".to_string())
+    })
+}
+
+fn write_namespace(disk: &mut DiskTable, id: u32, name: &str, index: &Index) -> io::Result<()> {
+    let tag = match index.kind() {
+        IndexKind::HashedKeys => HASHED_NAMESPACE_SECTION,
+        _ => NAMESPACE_SECTION,
+    };
+
+    disk.write_u8(tag)?;
+    disk.write_u32(id)?;
+    write_bytes(disk, name.as_bytes())?;
+    index.write_entries(disk)
+}
+
+fn write_bytes(disk: &mut DiskTable, bytes: &[u8]) -> io::Result<()> {
+    disk.write_u64(bytes.len() as u64)?;
+    disk.write(bytes)?;
+    Ok(())
+}
+
+fn insert(index: &mut Index, entry: &Entry) -> Result<()> {
+    index.insert(
+        entry.key(),
+        entry.timestamp(),
+        entry.file(),
+        entry.offset(),
+        entry.size(),
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcask::error::KeyValueStoreError;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// An index file holding `b"a"` in the default namespace and `b"b"` in
+    /// the namespace `space`, returning the path and the file's bytes.
+    fn index_file(name: &str) -> (PathBuf, Vec<u8>) {
+        let root = std::env::temp_dir().join(format!("kvs-index-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+
+        let mut index = Index::new(IndexKind::BTree, &root);
+        index.insert(b"a", 1, 1, 10, 40).unwrap();
+
+        let mut namespaces = Namespaces::new(IndexKind::BTree, &root);
+        let id = namespaces.allocate();
+        namespaces.insert(id, "space".to_string());
+        let space = &mut namespaces.get_mut(id).unwrap().index;
+        space.insert(b"b", 2, 1, 50, 40).unwrap();
+
+        let path = root.join("db.idx");
+        write(&path, &index, &namespaces, &SecondaryIndexes::new()).unwrap();
+        let bytes = fs::read(&path).unwrap();
+
+        (path, bytes)
+    }
+
+    fn read_back(path: &Path) -> Result<(Index, Namespaces)> {
+        let root = path.parent().unwrap();
+        let mut index = Index::new(IndexKind::BTree, root);
+        let mut namespaces = Namespaces::new(IndexKind::BTree, root);
+        read(
+            path,
+            &mut index,
+            &mut namespaces,
+            &mut SecondaryIndexes::new(),
+        )?;
+
+        Ok((index, namespaces))
+    }
+
+    fn assert_invalid(result: Result<(Index, Namespaces)>) {
+        match result {
+            Err(KeyValueStoreError::InvalidFormat(_)) => {}
+            Err(e) => panic!("expected an invalid format, got {:?}", e),
+            Ok(_) => panic!("expected an invalid format, got an index"),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let (path, _) = index_file("round-trip");
+        let (index, mut namespaces) = read_back(&path).unwrap();
+
+        assert_eq!(index.get(b"a").unwrap().unwrap().offset(), 10);
+
+        let id = namespaces.id("space").unwrap();
+        let space = &namespaces.get_mut(id).unwrap().index;
+        assert_eq!(space.get(b"b").unwrap().unwrap().offset(), 50);
+    }
+
+    #[test]
+    fn truncated_file_is_invalid() {
+        let (path, bytes) = index_file("truncated");
+
+        for len in [
+            INDEX_FILE_HEADER.len() - 1,
+            INDEX_FILE_HEADER.len() + 4,
+            bytes.len() - 1,
+        ] {
+            fs::write(&path, &bytes[..len]).unwrap();
+            assert_invalid(read_back(&path));
+        }
+    }
+
+    #[test]
+    fn bad_header_is_invalid() {
+        let (path, mut bytes) = index_file("header");
+        bytes[0] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        assert_invalid(read_back(&path));
+    }
+
+    #[test]
+    fn unknown_section_is_invalid() {
+        let (path, mut bytes) = index_file("section");
+        bytes.push(0xee);
+        fs::write(&path, &bytes).unwrap();
+
+        assert_invalid(read_back(&path));
+    }
+
+    #[test]
+    fn huge_length_is_invalid() {
+        let (path, mut bytes) = index_file("length");
+
+        // the key length of the only entry of the default namespace, after
+        // the marker, version, next namespace id, section tag, namespace id,
+        // the length of its empty name and the number of entries
+        let at = INDEX_FILE_HEADER.len() + 8 + 4 + 4 + 1 + 4 + 8 + 8;
+        assert_eq!(bytes[at..at + 8], 1u64.to_be_bytes());
+        bytes[at..at + 8].copy_from_slice(&u64::MAX.to_be_bytes());
+        fs::write(&path, &bytes).unwrap();
+
+        assert_invalid(read_back(&path));
+    }
+}
@@ -1,51 +1,291 @@
+use super::blob::map_blob;
+use super::error::Result;
 use super::index::Entry;
-use super::reader::{FileMap, Value};
+use super::reader::{FileMap, Value, ValueRef};
 use super::util::data_path;
-use crate::Bitcask;
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
 
-pub struct RangeIter<'a> {
-    cache: HashMap<u64, FileMap>,
-    range: Box<dyn Iterator<Item = (&'a Vec<u8>, &'a Entry)> + 'a>,
+pub type IndexRange<'a> = Box<dyn DoubleEndedIterator<Item = (&'a [u8], Entry)> + 'a>;
+
+pub struct FileCache {
+    maps: RefCell<HashMap<u64, Arc<FileMap>>>,
+    blobs: HashMap<u64, Arc<FileMap>>,
     root: PathBuf,
 }
 
+impl FileCache {
+    pub fn new<P>(root: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        Self::with_maps(root, HashMap::new(), HashMap::new())
+    }
+
+    /// Creates a cache that starts out with already mapped data and blob
+    /// files, so entries in those files are read from the maps even if the
+    /// files have since been removed from `root`.
+    pub fn with_maps<P>(
+        root: P,
+        maps: HashMap<u64, Arc<FileMap>>,
+        blobs: HashMap<u64, Arc<FileMap>>,
+    ) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        Self {
+            maps: RefCell::new(maps),
+            blobs,
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn get(&self, entry: &Entry) -> Result<Value> {
+        let value = self.get_ref(entry)?;
+        value.check()?;
+
+        Ok(value.to_value())
+    }
+
+    /// Whether the data file `id` is mapped or still in the store.
+    pub fn has_file(&self, id: u64) -> bool {
+        self.maps.borrow().contains_key(&id) || data_path(&self.root, id).exists()
+    }
+
+    /// Reads the value of `entry` in place, see [`FileMap::get_ref`].
+    /// Fails if the data file of `entry` can't be opened, for example if a
+    /// merge removed it.
+    pub fn get_ref(&self, entry: &Entry) -> Result<ValueRef> {
+        let f = {
+            let mut maps = self.maps.borrow_mut();
+
+            match maps.get(&entry.file()) {
+                Some(map) => Arc::clone(map),
+                None => {
+                    let map = Arc::new(FileMap::open(data_path(&self.root, entry.file()))?);
+                    maps.insert(entry.file(), Arc::clone(&map));
+                    map
+                }
+            }
+        };
+
+        f.get_ref_with(entry.offset(), entry.size(), |id| {
+            map_blob(&self.root, &self.blobs, id)
+        })
+    }
+}
+
+pub struct RangeIter<'a> {
+    cache: FileCache,
+    range: IndexRange<'a>,
+}
+
+impl<'a> RangeIter<'a> {
+    pub fn new(cache: FileCache, iter: IndexRange<'a>) -> Self {
+        Self { cache, range: iter }
+    }
+}
+
 impl<'a> RangeIter<'a> {
-    pub fn new(
-        bitcask: &'a Bitcask,
-        iter: Box<dyn Iterator<Item = (&'a Vec<u8>, &'a Entry)> + 'a>,
-    ) -> Self {
+    /// Skips entries that fail to load instead of yielding the error,
+    /// counting them in [`SkipErrors::skipped`].
+    pub fn skip_errors(self) -> SkipErrors<Self> {
+        SkipErrors::new(self)
+    }
+}
+
+impl<'a> Iterator for RangeIter<'a> {
+    type Item = Result<(Vec<u8>, Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (k, entry) = self.range.next()?;
+        let value = self.cache.get(&entry);
+
+        Some(value.map(|v| (k.to_vec(), v)))
+    }
+}
+
+impl<'a> DoubleEndedIterator for RangeIter<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let (k, entry) = self.range.next_back()?;
+        let value = self.cache.get(&entry);
+
+        Some(value.map(|v| (k.to_vec(), v)))
+    }
+}
+
+pub struct Keys<'a> {
+    range: IndexRange<'a>,
+}
+
+impl<'a> Keys<'a> {
+    pub fn new(iter: IndexRange<'a>) -> Self {
+        Self { range: iter }
+    }
+}
+
+impl<'a> Iterator for Keys<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (k, _) = self.range.next()?;
+        Some(k)
+    }
+}
+
+impl<'a> DoubleEndedIterator for Keys<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let (k, _) = self.range.next_back()?;
+        Some(k)
+    }
+}
+
+pub struct Values<'a> {
+    entries: Entries<'a>,
+}
+
+impl<'a> Values<'a> {
+    pub fn new(entries: Entries<'a>) -> Self {
+        Self { entries }
+    }
+}
+
+impl<'a> Values<'a> {
+    /// Skips values that fail to load instead of yielding the error,
+    /// counting them in [`SkipErrors::skipped`].
+    pub fn skip_errors(self) -> SkipErrors<Self> {
+        SkipErrors::new(self)
+    }
+}
+
+impl<'a> Iterator for Values<'a> {
+    type Item = Result<Value>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (_, entry) = self.entries.range.next()?;
+        Some(self.entries.cache.get(&entry))
+    }
+}
+
+impl<'a> DoubleEndedIterator for Values<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let (_, entry) = self.entries.range.next_back()?;
+        Some(self.entries.cache.get(&entry))
+    }
+}
+
+pub struct Entries<'a> {
+    cache: Rc<FileCache>,
+    range: IndexRange<'a>,
+}
+
+impl<'a> Entries<'a> {
+    pub fn new(cache: FileCache, iter: IndexRange<'a>) -> Self {
         Self {
-            cache: HashMap::new(),
+            cache: Rc::new(cache),
             range: iter,
-            root: bitcask.root().to_path_buf(),
         }
     }
 }
 
-impl<'a> Iterator for RangeIter<'a> {
-    type Item = (Vec<u8>, Value);
+impl<'a> Iterator for Entries<'a> {
+    type Item = EntryRef<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.range.next() {
-            Some((k, entry)) => {
-                let f = match self.cache.get(&entry.file()) {
-                    Some(f) => f,
-                    None => {
-                        let path = data_path(&self.root, entry.file());
-                        let map = FileMap::new(path);
-
-                        self.cache.insert(entry.file(), map);
-                        self.cache.get(&entry.file()).unwrap()
-                    }
-                };
-                match f.get(entry.offset(), entry.size()) {
-                    Ok(value) => Some((k.to_vec(), value)),
-                    _ => None,
-                }
+        let (key, entry) = self.range.next()?;
+
+        Some(EntryRef {
+            key,
+            entry,
+            cache: Rc::clone(&self.cache),
+        })
+    }
+}
+
+impl<'a> DoubleEndedIterator for Entries<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let (key, entry) = self.range.next_back()?;
+
+        Some(EntryRef {
+            key,
+            entry,
+            cache: Rc::clone(&self.cache),
+        })
+    }
+}
+
+/// A handle to an entry in the index. The value is only read from the data
+/// file when [`EntryRef::value`] is called.
+pub struct EntryRef<'a> {
+    key: &'a [u8],
+    entry: Entry,
+    cache: Rc<FileCache>,
+}
+
+impl<'a> EntryRef<'a> {
+    pub fn key(&self) -> &'a [u8] {
+        self.key
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.entry.timestamp()
+    }
+
+    /// Size of the record on disk, including the header and key.
+    pub fn size(&self) -> u64 {
+        self.entry.size()
+    }
+
+    pub fn value(&self) -> Result<Value> {
+        self.cache.get(&self.entry)
+    }
+}
+
+pub struct SkipErrors<I> {
+    inner: I,
+    skipped: usize,
+}
+
+impl<I> SkipErrors<I> {
+    pub fn new(inner: I) -> Self {
+        Self { inner, skipped: 0 }
+    }
+
+    /// The number of entries skipped so far because they failed to load.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+}
+
+impl<I, T> Iterator for SkipErrors<I>
+where
+    I: Iterator<Item = Result<T>>,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.inner.next()? {
+                Ok(item) => return Some(item),
+                Err(_) => self.skipped += 1,
+            }
+        }
+    }
+}
+
+impl<I, T> DoubleEndedIterator for SkipErrors<I>
+where
+    I: DoubleEndedIterator<Item = Result<T>>,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.inner.next_back()? {
+                Ok(item) => return Some(item),
+                Err(_) => self.skipped += 1,
             }
-            None => None,
         }
     }
 }
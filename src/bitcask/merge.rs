@@ -0,0 +1,924 @@
+use super::disk::DiskTable;
+use super::error::{KeyValueStoreError, Result};
+use super::index::{Entry, Index};
+use super::namespace::{split_key, DEFAULT_NAMESPACE};
+use super::reader::{EntryReader, FileMap, RawEntry, RecordKind};
+use super::throttle::RateLimiter;
+use super::util::{data_path, walk_dir, DATA_FILE_HEADER};
+use super::{new_data_file, Bitcask};
+use crate::api::WriteExt;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::thread::JoinHandle;
+
+/// Sealed data files no larger than half of the file size limit are grouped
+/// into tiers of files within a factor of two in size of each other, and a
+/// tier with this many files is merged into fewer, larger files even if they
+/// hold little garbage. This keeps stores whose data files are sealed early,
+/// for example by checkpoints, from ending up with ever more small files.
+const MERGE_TIER_FILES: usize = 4;
+
+/// A sealed data file considered for merging.
+struct FileUsage {
+    id: u64,
+    /// The size of the file without its header.
+    size: u64,
+    /// The size of the records in the file the indexes point to.
+    live: u64,
+}
+
+/// The files a merge rewrites, picked while holding the store. Copying
+/// their records only reads the files, so it can run while the store keeps
+/// being read and written.
+pub(crate) struct MergePlan {
+    root: PathBuf,
+    /// The files to rewrite, oldest first.
+    ids: Vec<u64>,
+    /// The oldest sealed file left in place.
+    oldest_kept: u64,
+    /// The ids reserved for the merged files. Writes continue in files after
+    /// them, so records written while the merge runs stay the latest ones
+    /// when the index is rebuilt from the data files.
+    first: u64,
+    last: u64,
+    file_size_limit: u64,
+    /// The live entries in each file by their offset, with the id of their
+    /// namespace.
+    live: HashMap<u64, HashMap<u64, (u32, Entry)>>,
+    bytes_total: u64,
+    limiter: RateLimiter,
+}
+
+/// The files written by a merge and the entries it moved into them.
+pub(crate) struct MergeOutput {
+    ids: Vec<u64>,
+    temp: PathBuf,
+    first: u64,
+    last: u64,
+    moved: Vec<Moved>,
+}
+
+struct Moved {
+    namespace: u32,
+    key: Vec<u8>,
+    old: Entry,
+    file: u64,
+    offset: u64,
+}
+
+/// The progress of a merge, shared with the thread running it.
+#[derive(Default)]
+pub(crate) struct MergeState {
+    bytes_read: AtomicU64,
+    bytes_total: AtomicU64,
+    cancelled: AtomicBool,
+}
+
+/// How far a merge has got, see [`MergeHandle::progress`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MergeProgress {
+    /// Bytes of the data files being merged read so far.
+    pub bytes_read: u64,
+    /// Total size of the data files being merged.
+    pub bytes_total: u64,
+}
+
+/// A merge running on a background thread, returned by
+/// [`SharedBitcask::merge_in_background`](crate::SharedBitcask::merge_in_background).
+/// Dropping the handle lets the merge run to the end.
+pub struct MergeHandle {
+    state: Arc<MergeState>,
+    thread: JoinHandle<Result<()>>,
+}
+
+impl MergeHandle {
+    pub(crate) fn new(state: Arc<MergeState>, thread: JoinHandle<Result<()>>) -> Self {
+        Self { state, thread }
+    }
+
+    /// How many bytes of the files being merged were read so far.
+    pub fn progress(&self) -> MergeProgress {
+        MergeProgress {
+            bytes_read: self.state.bytes_read.load(Ordering::Relaxed),
+            bytes_total: self.state.bytes_total.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Asks the merge to stop. Unless it is already updating the index, it
+    /// stops after the record it is copying, deletes the files it wrote and
+    /// leaves the store as it was.
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the merge has finished, successfully or not.
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Waits for the merge to finish. Fails with
+    /// [`KeyValueStoreError::Cancelled`] if it was cancelled, and with
+    /// [`KeyValueStoreError::Panicked`] if the merge thread panicked.
+    pub fn wait(self) -> Result<()> {
+        self.thread
+            .join()
+            .unwrap_or(Err(KeyValueStoreError::Panicked))
+    }
+}
+
+impl MergeState {
+    pub(crate) fn new(bytes_total: u64) -> Self {
+        Self {
+            bytes_total: AtomicU64::new(bytes_total),
+            ..Self::default()
+        }
+    }
+}
+
+impl Bitcask {
+    /// Merges the sealed data files that are worth rewriting: those in which
+    /// at least [`Options::bytes_deleted_ratio`] of the bytes are overwritten
+    /// or deleted records, and tiers of small files of similar size. Their
+    /// live records are copied into new data files, the index entries
+    /// pointing into them are moved to the copies and the files are deleted.
+    /// The other files, including the active one, are left as they are, and
+    /// writes continue in a new data file. Fails with
+    /// [`KeyValueStoreError::ReadOnly`] if the store is read-only, and leaves
+    /// the store as it was if copying the records fails.
+    ///
+    /// The store is held until the merge is done, so it is not throttled by
+    /// [`Options::io_rate_limit`]; a background merge is, see
+    /// [`SharedBitcask::merge_in_background`].
+    ///
+    /// [`Options::bytes_deleted_ratio`]: crate::Options::bytes_deleted_ratio
+    /// [`Options::io_rate_limit`]: crate::Options::io_rate_limit
+    /// [`SharedBitcask::merge_in_background`]: crate::SharedBitcask::merge_in_background
+    pub fn merge(&mut self) -> Result<()> {
+        let Some(mut plan) = self.plan_merge()? else {
+            return Ok(());
+        };
+
+        plan.limiter = RateLimiter::new(None);
+
+        let state = MergeState::new(plan.bytes_total);
+        let output = plan.run(&state, |record, shadows| self.keeps_record(record, shadows));
+
+        self.finish_merge(output)
+    }
+
+    /// Picks the files to merge and moves writes to a new data file after
+    /// the ids reserved for the merged files. Returns `None` if no file is
+    /// worth merging.
+    pub(crate) fn plan_merge(&mut self) -> Result<Option<MergePlan>> {
+        if self.opts.read_only {
+            return Err(KeyValueStoreError::ReadOnly);
+        }
+
+        if self.merging {
+            return Err(KeyValueStoreError::MergeRunning);
+        }
+
+        let root = self.root().to_path_buf();
+        let usage = self.file_usage(&root);
+        let selected = select_files(
+            &usage,
+            self.opts.bytes_deleted_ratio,
+            self.opts.file_size_limit,
+        );
+
+        if selected.is_empty() {
+            return Ok(None);
+        }
+
+        // tombstones and drops are only kept while files older than them are
+        // left in place, since these may hold the records they shadow
+        let oldest_kept = usage
+            .iter()
+            .map(|file| file.id)
+            .filter(|id| !selected.contains(id))
+            .min()
+            .unwrap_or(u64::MAX);
+
+        let mut live: HashMap<u64, HashMap<u64, (u32, Entry)>> = HashMap::new();
+
+        for (namespace, index) in self.sources() {
+            for entry in index.locations() {
+                if selected.contains(&entry.file()) {
+                    let file = live.entry(entry.file()).or_default();
+                    file.insert(entry.offset(), (namespace, entry));
+                }
+            }
+        }
+
+        let header = DATA_FILE_HEADER.len() as u64;
+        let bytes_total = usage
+            .iter()
+            .filter(|file| selected.contains(&file.id))
+            .map(|file| file.size + header)
+            .sum::<u64>();
+
+        // every merged file but the last is more than half full, as the
+        // record that started the next one didn't fit
+        let limit = self.opts.file_size_limit;
+        let reserved = 2 * (bytes_total / limit.max(1)) + 2;
+
+        let first = self.counter + 1;
+        let last = self.counter + reserved;
+        self.counter = last;
+        self.swap_file()?;
+        self.merging = true;
+
+        let mut ids: Vec<u64> = selected.into_iter().collect();
+        ids.sort();
+
+        Ok(Some(MergePlan {
+            root,
+            ids,
+            oldest_kept,
+            first,
+            last,
+            file_size_limit: limit,
+            live,
+            bytes_total,
+            limiter: self.limiter.clone(),
+        }))
+    }
+
+    /// Moves the merged files into the store and the index entries that
+    /// still point to the records they copied to the copies, and deletes the
+    /// files they replace. Entries written since the merge started keep
+    /// their new location.
+    pub(crate) fn finish_merge(&mut self, output: Result<MergeOutput>) -> Result<()> {
+        self.merging = false;
+        let output = output?;
+        let root = self.root().to_path_buf();
+
+        for id in output.first..=output.last {
+            std::fs::rename(data_path(&output.temp, id), data_path(&root, id))?;
+        }
+
+        for moved in output.moved {
+            let old = moved.old;
+            let mut space = None;
+
+            let index = match moved.namespace {
+                DEFAULT_NAMESPACE => &mut self.index,
+                id => match self.namespaces.get_mut(id) {
+                    Some(found) => &mut space.insert(found).index,
+                    None => {
+                        self.stats.count_deleted(moved.file, 1, old.size());
+                        continue;
+                    }
+                },
+            };
+
+            // on errors the merged files are kept, so entries not moved yet
+            // still point to records that exist
+            let current = index.get(&moved.key)?;
+
+            if current.is_some_and(|e| e.file() == old.file() && e.offset() == old.offset()) {
+                index.insert(
+                    &moved.key,
+                    old.timestamp(),
+                    moved.file,
+                    moved.offset,
+                    old.size(),
+                )?;
+
+                // the record is left behind in a file kept for a corrupt
+                // record, where it is garbage now
+                let left = !output.ids.contains(&old.file());
+
+                if left {
+                    self.stats.count_deleted(old.file(), 1, old.size());
+                }
+
+                if let Some(space) = space {
+                    space.count_moved(&old, moved.file);
+
+                    if left {
+                        space.stats.count_deleted(old.file(), 1, old.size());
+                    }
+                }
+            } else {
+                // overwritten while the merge ran, so the copy is garbage
+                self.stats.count_deleted(moved.file, 1, old.size());
+
+                if let Some(space) = space {
+                    space.stats.count_deleted(moved.file, 1, old.size());
+                }
+            }
+        }
+
+        // the copies are synced, and this makes the renames and the index
+        // pointing to them durable before the records they replace are
+        // deleted
+        self.replace_index()?;
+
+        for &id in &output.ids {
+            std::fs::remove_file(data_path(&root, id))?;
+        }
+
+        self.index.forget_files(&output.ids);
+        self.namespaces.forget_files(&output.ids);
+        self.stats.forget_files(&output.ids);
+
+        Ok(())
+    }
+
+    /// The sealed data files with their size and the size of their live
+    /// records.
+    fn file_usage(&self, root: &Path) -> Vec<FileUsage> {
+        let mut live: HashMap<u64, u64> = HashMap::new();
+
+        for (_, index) in self.sources() {
+            for entry in index.locations() {
+                *live.entry(entry.file()).or_default() += entry.size();
+            }
+        }
+
+        walk_dir(root)
+            .into_iter()
+            .filter(|&id| id != self.counter)
+            .map(|id| {
+                let size = std::fs::metadata(data_path(root, id)).map_or(0, |m| m.len());
+
+                FileUsage {
+                    id,
+                    size: size.saturating_sub(DATA_FILE_HEADER.len() as u64),
+                    live: live.get(&id).copied().unwrap_or(0),
+                }
+            })
+            .collect()
+    }
+
+    /// Whether a merge copies `record`, which is not a live value, out of a
+    /// file it rewrites. `shadows` is whether files older than the record's
+    /// are left in place. Tombstones are kept unless the index is known to
+    /// hold their key, which it can't tell if the key can't be read back.
+    pub(crate) fn keeps_record(&self, record: &RawEntry, shadows: bool) -> bool {
+        let deleted = |index: &Index, key: &[u8]| !matches!(index.get(key), Ok(Some(_)));
+
+        match record.kind() {
+            RecordKind::Tombstone => shadows && deleted(&self.index, record.key()),
+            RecordKind::NamespaceTombstone => match split_key(record.key()) {
+                Some((id, key)) => {
+                    let space = self.namespaces.get(id);
+                    shadows && space.is_some_and(|space| deleted(&space.index, key))
+                }
+                None => false,
+            },
+            RecordKind::NamespaceCreate => match split_key(record.key()) {
+                Some((id, _)) => self.namespaces.get(id).is_some(),
+                None => false,
+            },
+            RecordKind::NamespaceDrop => shadows,
+            // live values are copied from the indexes
+            RecordKind::Value
+            | RecordKind::NamespaceValue
+            | RecordKind::Blob
+            | RecordKind::NamespaceBlob => false,
+        }
+    }
+
+    /// The indexes of the default namespace and every other namespace, with
+    /// the id of their namespace.
+    fn sources(&self) -> impl Iterator<Item = (u32, &Index)> + '_ {
+        std::iter::once((DEFAULT_NAMESPACE, &self.index))
+            .chain(self.namespaces.iter().map(|(id, space)| (id, &space.index)))
+    }
+}
+
+/// A merge running on its own thread, which clears the merging flag of the
+/// store if the thread panics before the merge finishes, so later merges can
+/// still run.
+struct Running<T: 'static> {
+    owner: Arc<T>,
+    kv: fn(&T) -> &RwLock<Bitcask>,
+}
+
+impl<T> Running<T> {
+    fn kv(&self) -> &RwLock<Bitcask> {
+        (self.kv)(&self.owner)
+    }
+}
+
+impl<T> Drop for Running<T> {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            let mut kv = self.kv().write().unwrap_or_else(PoisonError::into_inner);
+            kv.merging = false;
+        }
+    }
+}
+
+/// Whether a data file can't be read past a record because the record is
+/// torn or corrupt, rather than because reading failed.
+fn is_corrupt(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::InvalidData
+    )
+}
+
+/// Picks the files worth merging out of `files`: those in which at least
+/// `ratio` of the bytes are garbage, and every tier of small files that has
+/// [`MERGE_TIER_FILES`] files.
+fn select_files(files: &[FileUsage], ratio: f64, file_size_limit: u64) -> HashSet<u64> {
+    let mut selected = HashSet::new();
+    let mut tiers: BTreeMap<u32, Vec<u64>> = BTreeMap::new();
+
+    for file in files {
+        let garbage = file.size.saturating_sub(file.live);
+
+        if file.live == 0 || garbage as f64 >= ratio * file.size as f64 {
+            selected.insert(file.id);
+        } else if file.size <= file_size_limit / 2 {
+            let tier = (file_size_limit / file.size.max(1)).ilog2();
+            tiers.entry(tier).or_default().push(file.id);
+        }
+    }
+
+    for ids in tiers.into_values() {
+        if ids.len() >= MERGE_TIER_FILES {
+            selected.extend(ids);
+        }
+    }
+
+    selected
+}
+
+impl MergePlan {
+    /// Runs the merge on a new thread for a store shared as `kv(&owner)`,
+    /// which is only held to check records and to update the index at the
+    /// end, so reads and writes go on while the records are copied.
+    pub(crate) fn spawn<T>(self, owner: Arc<T>, kv: fn(&T) -> &RwLock<Bitcask>) -> MergeHandle
+    where
+        T: Send + Sync + 'static,
+    {
+        let state = Arc::new(MergeState::new(self.bytes_total));
+        let progress = state.clone();
+
+        let thread = std::thread::spawn(move || {
+            let merge = Running { owner, kv };
+
+            let output = self.run(&progress, |record, shadows| {
+                let kv = merge.kv().read().expect("kv lock");
+                kv.keeps_record(record, shadows)
+            });
+
+            let result = merge.kv().write().expect("kv lock").finish_merge(output);
+            result
+        });
+
+        MergeHandle::new(state, thread)
+    }
+
+    /// Copies the live records of the files, and the other records for
+    /// which `keeps` returns true, to new data files in the temporary
+    /// directory. `keeps` is called with whether the record may shadow
+    /// records in files left in place. Reads and writes are throttled by the
+    /// limiter of the plan. Stops with
+    /// [`KeyValueStoreError::Cancelled`] once `state` is cancelled, deleting
+    /// the files written so far, as it does on errors.
+    pub(crate) fn run<F>(mut self, state: &MergeState, keeps: F) -> Result<MergeOutput>
+    where
+        F: Fn(&RawEntry, bool) -> bool,
+    {
+        let temp = self.root.join("temp");
+        std::fs::create_dir_all(&temp)?;
+
+        // left behind by a merge that was interrupted
+        for id in walk_dir(&temp) {
+            std::fs::remove_file(data_path(&temp, id))?;
+        }
+
+        let limiter = self.limiter.clone();
+        let mut writer =
+            MergeWriter::new(&temp, self.first, self.last, self.file_size_limit, limiter);
+        let moved = self
+            .copy(&mut writer, state, keeps)
+            .and_then(|moved| writer.finish().map(|_| moved));
+        let last = writer.last_id();
+
+        match moved {
+            Ok(moved) => Ok(MergeOutput {
+                ids: self.ids,
+                temp,
+                first: self.first,
+                last,
+                moved,
+            }),
+            Err(e) => {
+                for id in self.first..=last {
+                    let _ = std::fs::remove_file(data_path(&temp, id));
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn copy<F>(
+        &mut self,
+        writer: &mut MergeWriter,
+        state: &MergeState,
+        keeps: F,
+    ) -> Result<Vec<Moved>>
+    where
+        F: Fn(&RawEntry, bool) -> bool,
+    {
+        let mut moved = Vec::new();
+        let mut corrupt = Vec::new();
+        let mut bytes_read = 0;
+
+        for &id in &self.ids {
+            let path = data_path(&self.root, id);
+            let map = FileMap::open(&path)?;
+            let bytes = map.map();
+            let mut reader = EntryReader::open(&path)?;
+            let mut live = self.live.remove(&id).unwrap_or_default();
+            let shadows = self.oldest_kept < id;
+
+            loop {
+                let record = match reader.next_record() {
+                    Ok(Some(record)) if record.is_valid() => record,
+                    Ok(None) => break,
+                    Ok(Some(_)) => {
+                        corrupt.push(id);
+                        break;
+                    }
+                    Err(e) if is_corrupt(&e) => {
+                        corrupt.push(id);
+                        break;
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+
+                if state.cancelled.load(Ordering::Relaxed) {
+                    return Err(KeyValueStoreError::Cancelled);
+                }
+
+                self.limiter.acquire(record.size());
+
+                let start = record.offset() as usize;
+                let raw = &bytes[start..start + record.size() as usize];
+
+                match live.remove(&record.offset()) {
+                    Some((namespace, old)) => {
+                        let key = match namespace {
+                            DEFAULT_NAMESPACE => record.key(),
+                            _ => split_key(record.key()).map_or(&[][..], |(_, key)| key),
+                        };
+
+                        let (file, offset) = writer.write(raw)?;
+                        moved.push(Moved {
+                            namespace,
+                            key: key.to_vec(),
+                            old,
+                            file,
+                            offset,
+                        });
+                    }
+                    None if keeps(&record, shadows) => {
+                        writer.write(raw)?;
+                    }
+                    None => {}
+                }
+
+                state.bytes_read.store(
+                    bytes_read + record.offset() + record.size(),
+                    Ordering::Relaxed,
+                );
+            }
+
+            bytes_read += bytes.len() as u64;
+            state.bytes_read.store(bytes_read, Ordering::Relaxed);
+
+            // a file the scan couldn't read to the end is kept, with the
+            // records past the corrupt one left in place, and the tombstones
+            // of later files may shadow them
+            if corrupt.last() == Some(&id) {
+                self.oldest_kept = self.oldest_kept.min(id);
+            }
+        }
+
+        self.ids.retain(|id| !corrupt.contains(id));
+
+        Ok(moved)
+    }
+}
+
+/// Writes the records copied by a merge to new data files with the ids
+/// reserved for it in the temporary directory, starting a new file when one
+/// is full, no faster than `limiter` allows. Every file is synced once it is
+/// full or the merge is done, so the copies are on disk before the files
+/// they replace are deleted.
+struct MergeWriter<'a> {
+    dir: &'a Path,
+    limit: u64,
+    first: u64,
+    last: u64,
+    id: u64,
+    disk: Option<DiskTable>,
+    limiter: RateLimiter,
+}
+
+impl<'a> MergeWriter<'a> {
+    fn new(dir: &'a Path, first: u64, last: u64, limit: u64, limiter: RateLimiter) -> Self {
+        Self {
+            dir,
+            limit,
+            first,
+            last,
+            id: first,
+            disk: None,
+            limiter,
+        }
+    }
+
+    /// Appends `record`, returning the file and offset it was written at.
+    /// The last reserved file takes whatever doesn't fit in the others.
+    fn write(&mut self, record: &[u8]) -> Result<(u64, u64)> {
+        self.limiter.acquire(record.len() as u64);
+
+        if let Some(disk) = &mut self.disk {
+            if disk.position() + record.len() as u64 > self.limit && self.id < self.last {
+                disk.sync()?;
+                self.disk = None;
+                self.id += 1;
+            }
+        }
+
+        let disk = match &mut self.disk {
+            Some(disk) => disk,
+            None => self
+                .disk
+                .insert(new_data_file(data_path(self.dir, self.id))?),
+        };
+        let offset = disk.write(record)?;
+
+        Ok((self.id, offset))
+    }
+
+    /// Syncs the last file.
+    fn finish(&mut self) -> Result<()> {
+        if let Some(disk) = &mut self.disk {
+            disk.sync()?;
+        }
+
+        Ok(())
+    }
+
+    /// The id of the last file written, which is less than the first id if
+    /// nothing was written.
+    fn last_id(&self) -> u64 {
+        match self.disk {
+            Some(_) => self.id,
+            None => self.first - 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcask::settings::Options;
+    use crate::bitcask::shared::SharedBitcask;
+    use std::fs;
+    use std::thread;
+
+    fn store(name: &str) -> Options {
+        let root = std::env::temp_dir().join(format!("kvs-merge-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        Options::new(root)
+    }
+
+    fn value(kv: &Bitcask, key: &[u8]) -> Option<Vec<u8>> {
+        kv.get(key).unwrap().map(|v| v.data().to_vec())
+    }
+
+    fn usage(id: u64, size: u64, live: u64) -> FileUsage {
+        FileUsage { id, size, live }
+    }
+
+    fn selected(files: &[FileUsage]) -> Vec<u64> {
+        let mut ids: Vec<u64> = select_files(files, 0.5, 1000).into_iter().collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn selects_files_with_enough_garbage() {
+        let files = [
+            usage(0, 900, 400),
+            usage(1, 900, 500),
+            usage(2, 900, 0),
+            usage(3, 0, 0),
+        ];
+
+        assert_eq!(selected(&files), vec![0, 2, 3]);
+    }
+
+    #[test]
+    fn selects_full_tiers_of_small_files() {
+        // 3 files in the tier of files of 125 to 250 bytes, and 4 in the
+        // tier of 62 to 125 bytes
+        let files = [
+            usage(0, 200, 200),
+            usage(1, 150, 150),
+            usage(2, 130, 130),
+            usage(3, 100, 100),
+            usage(4, 90, 90),
+            usage(5, 70, 70),
+            usage(6, 63, 63),
+            usage(7, 600, 600),
+        ];
+
+        assert_eq!(selected(&files), vec![3, 4, 5, 6]);
+        assert!(selected(&files[..6]).is_empty());
+    }
+
+    #[test]
+    fn merges_only_fragmented_files() {
+        let mut opts = store("select");
+        opts.file_size_limit = 1000;
+        let mut kv = Bitcask::open(opts.clone()).unwrap();
+
+        for prefix in [b'k', b'm'] {
+            for i in 0..10u8 {
+                kv.insert(&[prefix, i], &[i; 50]).unwrap();
+            }
+            kv.swap_file().unwrap();
+        }
+
+        for i in 0..10u8 {
+            kv.insert(&[b'k', i], &[i + 1; 50]).unwrap();
+        }
+        kv.swap_file().unwrap();
+
+        kv.merge().unwrap();
+
+        let ids = walk_dir(kv.root());
+        assert!(!ids.contains(&0));
+        assert!(ids.contains(&1) && ids.contains(&2));
+        assert_eq!(kv.stats().num_bytes_deleted, 0);
+        drop(kv);
+
+        let kv = Bitcask::open(opts).unwrap();
+
+        for i in 0..10u8 {
+            assert_eq!(value(&kv, &[b'k', i]).unwrap(), [i + 1; 50]);
+            assert_eq!(value(&kv, &[b'm', i]).unwrap(), [i; 50]);
+            assert_eq!(kv.index.get(&[b'm', i]).unwrap().unwrap().file(), 1);
+        }
+    }
+
+    #[test]
+    fn corrupt_file_is_kept() {
+        let opts = store("corrupt");
+        let mut kv = Bitcask::open(opts.clone()).unwrap();
+
+        for key in [b"a", b"d", b"b", b"e", b"c"] {
+            kv.insert(key, b"old").unwrap();
+        }
+
+        let d = kv.index.get(b"d").unwrap().unwrap();
+        kv.swap_file().unwrap();
+
+        for key in [b"a", b"b", b"c"] {
+            kv.insert(key, b"new").unwrap();
+        }
+
+        // the value of the overwritten record of b, between d and e
+        let path = data_path(kv.root(), d.file());
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[(d.offset() + d.size()) as usize + 30] ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+
+        kv.merge().unwrap();
+        assert!(path.exists());
+
+        for kv in [kv, Bitcask::open(opts).unwrap()] {
+            assert_eq!(value(&kv, b"a").unwrap(), b"new");
+            assert_eq!(value(&kv, b"b").unwrap(), b"new");
+            assert_eq!(value(&kv, b"d").unwrap(), b"old");
+            assert_eq!(value(&kv, b"e").unwrap(), b"old");
+            assert_ne!(kv.index.get(b"d").unwrap().unwrap().file(), d.file());
+            assert_eq!(kv.index.get(b"e").unwrap().unwrap().file(), d.file());
+        }
+    }
+
+    #[test]
+    fn writes_during_a_merge_keep_their_location() {
+        let opts = store("race");
+        let mut kv = Bitcask::open(opts.clone()).unwrap();
+
+        for key in [b"a", b"b", b"c", b"d"] {
+            kv.insert(key, b"old").unwrap();
+        }
+        kv.swap_file().unwrap();
+
+        for key in [b"a", b"b"] {
+            kv.insert(key, b"new").unwrap();
+        }
+
+        let plan = kv.plan_merge().unwrap().unwrap();
+        let state = MergeState::new(plan.bytes_total);
+        let output = plan.run(&state, |record, shadows| kv.keeps_record(record, shadows));
+
+        // written after c and d were copied, before the index is updated
+        kv.insert(b"c", b"newer").unwrap();
+        kv.delete(b"d").unwrap();
+        kv.finish_merge(output).unwrap();
+
+        assert!(!data_path(kv.root(), 0).exists());
+
+        for kv in [kv, Bitcask::open(opts).unwrap()] {
+            assert_eq!(value(&kv, b"a").unwrap(), b"new");
+            assert_eq!(value(&kv, b"b").unwrap(), b"new");
+            assert_eq!(value(&kv, b"c").unwrap(), b"newer");
+            assert_eq!(value(&kv, b"d"), None);
+        }
+    }
+
+    #[test]
+    fn background_merge_races_with_writes() {
+        let mut opts = store("background");
+        opts.file_size_limit = 1000;
+        opts.io_rate_limit = Some(4000);
+        let kv = SharedBitcask::open(opts.clone()).unwrap();
+
+        for i in 0..40u8 {
+            kv.insert(&[i], &[0; 50]).unwrap();
+        }
+        for i in 0..40u8 {
+            kv.insert(&[i], &[1; 50]).unwrap();
+        }
+
+        let handle = kv.merge_in_background().unwrap().unwrap();
+
+        let writer = {
+            let kv = kv.clone();
+            thread::spawn(move || {
+                for i in 0..40u8 {
+                    if i % 4 == 0 {
+                        kv.delete(&[i]).unwrap();
+                    } else {
+                        kv.insert(&[i], &[2; 50]).unwrap();
+                    }
+                }
+            })
+        };
+
+        writer.join().unwrap();
+        handle.wait().unwrap();
+        drop(kv);
+
+        let kv = Bitcask::open(opts).unwrap();
+
+        for i in 0..40u8 {
+            let expected = (i % 4 != 0).then(|| vec![2; 50]);
+            assert_eq!(value(&kv, &[i]), expected);
+        }
+    }
+
+    #[test]
+    fn cancelled_merge_leaves_the_store_as_it_was() {
+        let mut opts = store("cancel");
+        opts.io_rate_limit = Some(100);
+        let mut kv = Bitcask::open(opts.clone()).unwrap();
+
+        for i in 0..20u8 {
+            kv.insert(&[i], &[0; 50]).unwrap();
+        }
+        kv.swap_file().unwrap();
+
+        for i in 0..10u8 {
+            kv.insert(&[i], &[1; 50]).unwrap();
+        }
+        drop(kv);
+
+        let kv = SharedBitcask::open(opts.clone()).unwrap();
+        let handle = kv.merge_in_background().unwrap().unwrap();
+        handle.cancel();
+
+        assert!(matches!(handle.wait(), Err(KeyValueStoreError::Cancelled)));
+        assert!(data_path(&opts.base_dir, 0).exists());
+        assert!(walk_dir(opts.base_dir.join("temp")).is_empty());
+
+        // the merge no longer counts as running
+        kv.set_io_rate_limit(None);
+        kv.merge().unwrap();
+        assert!(!data_path(&opts.base_dir, 0).exists());
+        drop(kv);
+
+        let kv = Bitcask::open(opts).unwrap();
+
+        for i in 0..20u8 {
+            let expected = if i < 10 { [1; 50] } else { [0; 50] };
+            assert_eq!(value(&kv, &[i]).unwrap(), expected);
+        }
+    }
+}
@@ -1,61 +1,116 @@
+#[cfg(feature = "async")]
+pub(crate) mod asynchronous;
+pub(crate) mod batch;
+pub(crate) mod blob;
+pub(crate) mod checkpoint;
+pub(crate) mod cursor;
 pub(crate) mod disk;
+pub(crate) mod dump;
 pub(crate) mod error;
 pub(crate) mod index;
+pub(crate) mod index_file;
 pub(crate) mod iter;
+pub(crate) mod merge;
+pub(crate) mod namespace;
 pub(crate) mod reader;
+pub(crate) mod secondary;
 pub(crate) mod settings;
+pub(crate) mod shared;
+pub(crate) mod snapshot;
+pub(crate) mod throttle;
 pub(crate) mod util;
+pub(crate) mod verify;
 
+use self::batch::{Op, WriteBatch};
+use self::blob::{BlobFiles, BlobRef};
+use self::checkpoint::Checkpoint;
+use self::cursor::Cursor;
 use self::disk::DiskTable;
-use self::error::Result;
+use self::dump::{read_json_lines, write_json_line, write_json_namespace, DumpReader, DumpWriter};
+use self::error::{KeyValueStoreError, Result};
 use self::index::Index;
-use self::iter::RangeIter;
-use self::reader::{FileMap, Value};
-use self::settings::{Metrics, Options};
-use self::util::{compute_size, data_path, delete_files, idx_path, walk_dir, DATA_FILE_HEADER};
+use self::iter::{Entries, FileCache, Keys, RangeIter, Values};
+use self::namespace::{namespaced_key, Namespaces, DEFAULT_NAMESPACE};
+use self::reader::{FileMap, RecordKind, Value, ValueRef};
+use self::secondary::SecondaryIndexes;
+use self::settings::{Metrics, Options, Stats, SyncPolicy};
+use self::snapshot::Snapshot;
+use self::throttle::RateLimiter;
+use self::util::{
+    blob_path, compute_size, data_path, idx_path, prefix_successor, sync_dir, tmp_idx_path,
+    walk_blobs, walk_dir, BLOB_FILE_HEADER, DATA_FILE_HEADER,
+};
+use self::verify::VerifyReport;
 use crate::api::WriteExt;
 use chrono::Utc;
-use std::collections::HashMap;
-use std::fs::OpenOptions;
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::ops::RangeBounds;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, Read, Write};
+use std::ops::{Bound, RangeBounds};
 use std::path::Path;
+use std::sync::Arc;
+
+const IMPORT_BATCH_SIZE: usize = 1024;
 
 pub struct Bitcask {
     pub(crate) index: Index,
+    namespaces: Namespaces,
+    secondary: SecondaryIndexes,
     disk: DiskTable,
     counter: u64,
+    blobs: BlobFiles,
+    /// Whether a merge started by [`Bitcask::plan_merge`] hasn't finished.
+    merging: bool,
+    /// Handles to the data files sealed during a commit whose sync is left
+    /// to the caller, see [`Bitcask::commit_unsynced`].
+    unsynced: Option<Vec<std::fs::File>>,
+    limiter: RateLimiter,
     stats: Metrics,
     opts: Options,
 }
 
 impl Bitcask {
-    pub fn open(opts: Options) -> Self {
+    /// Opens the store in [`Options::base_dir`], creating it if the
+    /// directory does not exist or holds no data files. In read only mode a
+    /// missing directory fails with a [`io::ErrorKind::NotFound`] error and
+    /// an empty one with [`KeyValueStoreError::ReadOnly`].
+    pub fn open(opts: Options) -> Result<Self> {
         let root = opts.base_dir.to_path_buf();
 
         if !root.exists() {
-            std::fs::create_dir_all(&root).expect("create dir");
+            if opts.read_only {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("store {:?} does not exist", root),
+                )
+                .into());
+            }
+
+            std::fs::create_dir_all(&root)?;
             return Self::create(opts);
         }
 
         let mut ids = walk_dir(&root);
 
-        if ids.len() == 0 {
+        if ids.is_empty() {
             return Self::create(opts);
         }
 
         ids.sort();
 
-        let mut index = Index::new();
+        let mut index = Index::new(opts.index, &root);
+        let mut namespaces = Namespaces::new(opts.index, &root);
+        let mut secondary = SecondaryIndexes::new();
         let i_path = idx_path(&root);
         let last_id = ids.pop().unwrap();
 
         match i_path.exists() {
-            true => index.open(&i_path),
+            true => index_file::read(&i_path, &mut index, &mut namespaces, &mut secondary)?,
             false => {
                 for id in ids {
                     let d_path = data_path(&root, id);
-                    index.from_data(d_path, id);
+                    namespaces.load_data(d_path, id, &mut index)?;
                 }
             }
         }
@@ -63,133 +118,296 @@ impl Bitcask {
         let d_path = data_path(&root, last_id);
 
         let data_file = OpenOptions::new()
-            .write(true)
-            .append(true)
-            .open(&d_path)
-            .expect("open data file");
+            .read(opts.read_only)
+            .append(!opts.read_only)
+            .open(&d_path)?;
 
-        let disk = DiskTable::open(data_file);
+        let disk = DiskTable::open(data_file)?;
 
-        match i_path.exists() {
-            true => {}
-            false => {
-                let d_path = data_path(&root, last_id);
-                index.from_data(d_path, last_id);
-                index.write(&i_path);
-            }
+        let rebuilt = !i_path.exists();
+
+        if rebuilt {
+            let d_path = data_path(&root, last_id);
+            namespaces.load_data(d_path, last_id, &mut index)?;
+            namespaces.finish_load();
+        }
+
+        index.shrink_to_fit();
+        namespaces.shrink_to_fit();
+
+        let blobs = BlobFiles::open(&root);
+        namespaces.count_usage(blobs.any());
+
+        if rebuilt && !opts.read_only {
+            index_file::write(&i_path, &index, &namespaces, &secondary)?;
         }
 
-        Self {
+        Ok(Self {
             index,
+            namespaces,
+            secondary,
             disk,
             counter: last_id,
+            blobs,
+            merging: false,
+            unsynced: None,
+            limiter: RateLimiter::new(opts.io_rate_limit),
 
             opts,
             stats: Metrics::new(),
-        }
+        })
     }
 
-    pub fn create(opts: Options) -> Self {
+    /// Creates an empty store in the existing directory
+    /// [`Options::base_dir`]. Fails with [`KeyValueStoreError::ReadOnly`] in
+    /// read only mode.
+    pub fn create(opts: Options) -> Result<Self> {
+        if opts.read_only {
+            return Err(KeyValueStoreError::ReadOnly);
+        }
+
         let counter = 0;
 
         let root = opts.base_dir.to_path_buf();
         let d_path = data_path(&root, counter);
 
-        let index = Index::new();
-        let disk = new_data_file(&d_path);
+        let index = Index::new(opts.index, &root);
+        let disk = new_data_file(&d_path)?;
         let stats = Metrics::new();
 
-        Self {
+        Ok(Self {
             index,
+            namespaces: Namespaces::new(opts.index, &root),
+            secondary: SecondaryIndexes::new(),
             disk,
             counter,
+            blobs: BlobFiles::open(&root),
+            merging: false,
+            unsynced: None,
+            limiter: RateLimiter::new(opts.io_rate_limit),
 
             opts,
             stats,
-        }
+        })
     }
 
-    pub fn close(mut self) {
-        self.flush();
+    pub fn close(mut self) -> Result<()> {
+        self.flush()
     }
 
     pub fn root(&self) -> &Path {
         &self.opts.base_dir
     }
 
-    pub fn exists(&self, key: &[u8]) -> bool {
-        match self.index.get(key) {
-            Some(_) => true,
-            None => false,
-        }
+    /// Whether `key` exists. Fails only for [`IndexKind::HashedKeys`]
+    /// indexes, if the key they compare against can't be read back.
+    ///
+    /// [`IndexKind::HashedKeys`]: crate::IndexKind::HashedKeys
+    pub fn exists(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.index.get(key)?.is_some())
     }
 
-    pub fn flush(&mut self) {
-        self.disk.flush();
+    pub fn flush(&mut self) -> Result<()> {
+        if self.opts.read_only {
+            return Ok(());
+        }
+
+        self.disk.flush()?;
         let path = idx_path(&self.opts.base_dir);
-        self.index.write(&path);
+        self.write_index(path)?;
+
+        Ok(())
     }
 
-    pub fn keys<'a>(&'a self) -> impl Iterator<Item = &'a Vec<u8>> {
-        self.index.keys()
+    /// Flushes and then forces the active data file to disk, so writes made
+    /// so far survive a crash of the machine and not only of the process.
+    pub fn sync(&mut self) -> Result<()> {
+        if self.opts.read_only {
+            return Ok(());
+        }
+
+        self.flush()?;
+        self.disk.sync()?;
+
+        Ok(())
+    }
+
+    /// Commits `ops` like [`Bitcask::commit`] without syncing them, so they
+    /// can be forced to disk after the store is released. Returns handles to
+    /// every file the writes went to that is not synced yet, in the order
+    /// they must be synced: the data files sealed during the commit, the
+    /// active blob file and the active data file. Blob files sealed during
+    /// the commit are synced as they are sealed.
+    #[cfg(feature = "async")]
+    pub(crate) fn commit_unsynced(
+        &mut self,
+        ops: Vec<Op>,
+    ) -> Result<(Vec<bool>, Vec<std::fs::File>)> {
+        self.unsynced = Some(Vec::new());
+        let changed = self.commit(ops, false);
+        let mut files = self.unsynced.take().unwrap_or_default();
+        let changed = changed?;
+
+        files.extend(self.blobs.try_clone_active()?);
+        files.push(self.disk.try_clone_file()?);
+
+        Ok((changed, files))
+    }
+
+    /// Iterates over the keys in `range`. This and the other range and
+    /// prefix scans fail with [`KeyValueStoreError::Unsupported`] if the
+    /// store uses [`IndexKind::Hash`] or [`IndexKind::HashedKeys`].
+    ///
+    /// [`KeyValueStoreError::Unsupported`]: crate::KeyValueStoreError::Unsupported
+    /// [`IndexKind::Hash`]: crate::IndexKind::Hash
+    /// [`IndexKind::HashedKeys`]: crate::IndexKind::HashedKeys
+    pub fn keys<R>(&self, range: R) -> Result<Keys<'_>>
+    where
+        R: RangeBounds<[u8]>,
+    {
+        Ok(Keys::new(self.index.range(range)?))
     }
 
-    pub fn keys_range<'a, R>(
+    pub fn entries<R>(&self, range: R) -> Result<Entries<'_>>
+    where
+        R: RangeBounds<[u8]>,
+    {
+        let range = self.index.range(range)?;
+        Ok(Entries::new(FileCache::new(self.root()), range))
+    }
+
+    pub fn values<R>(&self, range: R) -> Result<Values<'_>>
+    where
+        R: RangeBounds<[u8]>,
+    {
+        Ok(Values::new(self.entries(range)?))
+    }
+
+    /// Returns the value of `key`, or `None` if the key does not exist.
+    /// Fails if the value can't be read or its checksum doesn't match.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Value>> {
+        self.index
+            .get(key)?
+            .map(|entry| self.read_entry(&entry))
+            .transpose()
+    }
+
+    /// Like [`Bitcask::get`], but returns the value in place in the mapped
+    /// data file instead of copying it. The returned [`ValueRef`] stays
+    /// valid after later writes and merges. Unlike [`Bitcask::get`], this
+    /// doesn't check the checksum of values kept in blob files, which would
+    /// mean reading them whole; [`Bitcask::verify`] does.
+    pub fn get_ref(&self, key: &[u8]) -> Result<Option<ValueRef>> {
+        self.index
+            .get(key)?
+            .map(|entry| self.read_entry_ref(&entry))
+            .transpose()
+    }
+
+    /// Calls `f` with the value of `key` read in place, returning what it
+    /// returns, or `None` if the key does not exist.
+    pub fn with_value<F, T>(&self, key: &[u8], f: F) -> Result<Option<T>>
+    where
+        F: FnOnce(&[u8]) -> T,
+    {
+        Ok(self.get_ref(key)?.map(|value| f(&value)))
+    }
+
+    /// Returns a reader over the value of `key`, reading it in place like
+    /// [`Bitcask::get_ref`], so large values can be streamed without loading
+    /// them into memory.
+    pub fn get_reader(&self, key: &[u8]) -> Result<Option<io::Cursor<ValueRef>>> {
+        Ok(self.get_ref(key)?.map(io::Cursor::new))
+    }
+
+    /// Reads every entry of the default namespace, in key order unless the
+    /// index is a hash index.
+    fn scan_all(&self) -> impl Iterator<Item = Result<(Vec<u8>, Value)>> + '_ {
+        self.scan_index(&self.index)
+    }
+
+    /// Reads every entry of `index`, the index of a namespace of this store.
+    fn scan_index<'a>(
         &'a self,
-        start: &[u8],
-        end: &[u8],
-    ) -> impl Iterator<Item = &'a Vec<u8>> {
-        self.index.range(start, end).map(|(k, _)| k)
+        index: &'a Index,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Value)>> + 'a {
+        let cache = FileCache::new(self.root());
+
+        index.entries().map(move |item| {
+            let (k, entry) = item?;
+            cache.get(&entry).map(|v| (k.into_owned(), v))
+        })
     }
 
-    pub fn get(&self, key: &[u8]) -> Option<Value> {
-        let entry = self.index.get(key)?;
-        let path = data_path(self.root(), entry.file());
+    fn read_entry(&self, entry: &index::Entry) -> Result<Value> {
+        let value = self.read_entry_ref(entry)?;
+        value.check()?;
 
-        let map = FileMap::new(path);
-        let value = map.get(entry.offset(), entry.size());
+        Ok(value.to_value())
+    }
 
-        match value {
-            Ok(value) => Some(value),
-            Err(e) => {
-                println!("{:?}", e);
-                None
-            }
-        }
+    fn read_entry_ref(&self, entry: &index::Entry) -> Result<ValueRef> {
+        let path = data_path(self.root(), entry.file());
+        let map = FileMap::open(path)?;
+
+        map.get_ref(entry.offset(), entry.size())
     }
 
     pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
-        if let Some(v) = self.get(key) {
+        if self.opts.read_only {
+            return Err(KeyValueStoreError::ReadOnly);
+        }
+
+        if let Some(v) = self.get(key)? {
             if v.data() == value {
                 return Ok(());
             }
         }
 
-        let ts = Utc::now().timestamp() as u64;
+        let mut batch = WriteBatch::new();
+        batch.put(key, value);
+
+        self.write_batch(batch)
+    }
+
+    /// Applies every write in `batch` in order, flushing once at the end and
+    /// syncing once if the [`SyncPolicy`] asks for it.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let sync = self.opts.sync == SyncPolicy::Always;
+        self.commit(batch.ops, sync)?;
+
+        Ok(())
+    }
 
-        if self.disk.position() + compute_size(key, value) > self.opts.file_size_limit {
-            self.swap_file();
+    /// Inserts the `len` bytes read from `reader` as the value of `key`. The
+    /// bytes are copied in chunks to a blob file, so the value never has to
+    /// fit in memory and may be larger than the data files. Fails if
+    /// `reader` ends before `len` bytes.
+    pub fn insert_stream<R>(&mut self, key: &[u8], reader: R, len: u64) -> Result<()>
+    where
+        R: Read,
+    {
+        if self.opts.read_only {
+            return Err(KeyValueStoreError::ReadOnly);
         }
 
-        let (offset, size) = self.disk.append_entry(ts, key, value);
-        let file_id = self.counter;
+        let sync = self.opts.sync == SyncPolicy::Always;
+        let blob = self.write_blob(reader, len)?;
 
-        match self.index.insert(key, ts, file_id, offset, size) {
-            Some(prev) => {
-                self.stats.num_entries_deleted += 1;
-                self.stats.num_bytes_deleted += prev.size();
-            }
-            None => {}
+        let op = Op::PutBlob {
+            namespace: DEFAULT_NAMESPACE,
+            timestamp: Utc::now().timestamp() as u64,
+            key: key.to_vec(),
+            blob,
         };
-
-        self.flush();
+        self.commit(vec![op], sync)?;
 
         Ok(())
     }
 
     pub fn insert_if_none(&mut self, key: &[u8], value: &[u8]) -> Result<bool> {
-        match self.exists(key) {
-            true => return Ok(false),
+        match self.exists(key)? {
+            true => Ok(false),
             false => {
                 self.insert(key, value)?;
                 Ok(true)
@@ -197,140 +415,796 @@ impl Bitcask {
         }
     }
 
-    pub fn delete(&mut self, key: &[u8]) -> bool {
-        let res = match self.index.delete(key) {
-            Some(e) => {
-                let ts = Utc::now().timestamp() as u64;
-                self.disk.delete(ts, key);
-                self.stats.num_bytes_deleted += e.size();
-                self.stats.num_entries_deleted += 1;
-                true
-            }
-            None => false,
-        };
+    /// Deletes `key`, returning whether it existed. Fails with
+    /// [`KeyValueStoreError::ReadOnly`] if the store is read-only.
+    pub fn delete(&mut self, key: &[u8]) -> Result<bool> {
+        if self.opts.read_only {
+            return Err(KeyValueStoreError::ReadOnly);
+        }
 
-        res
+        let ts = Utc::now().timestamp() as u64;
+        self.remove(ts, key)
     }
 
-    pub fn iter<'a, R>(&'a self, range: R) -> RangeIter<'a>
+    pub fn iter<R>(&self, range: R) -> Result<RangeIter<'_>>
     where
-        R: RangeBounds<Vec<u8>>,
+        R: RangeBounds<[u8]>,
     {
-        RangeIter::new(&self, Box::new(self.index.inner.range(range)))
+        let range = self.index.range(range)?;
+        Ok(RangeIter::new(FileCache::new(self.root()), range))
     }
 
-    pub fn merge(&mut self) {
-        let temp = self.opts.base_dir.join("temp");
+    pub fn cursor(&self) -> Result<Cursor<'_>> {
+        Cursor::new(FileCache::new(self.root()), &self.index)
+    }
+
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<RangeIter<'_>> {
+        let end = prefix_successor(prefix);
+        self.iter(prefix_bounds(prefix, &end))
+    }
+
+    pub fn scan_prefix_keys(&self, prefix: &[u8]) -> Result<Keys<'_>> {
+        let end = prefix_successor(prefix);
+        self.keys(prefix_bounds(prefix, &end))
+    }
 
-        if !temp.exists() {
-            std::fs::create_dir_all(&temp).expect("");
+    /// Takes a point-in-time snapshot of the store. The snapshot does not
+    /// borrow the store, so it can be sent to another thread and read while
+    /// the store keeps being written to. Fails if a data or blob file can't
+    /// be mapped.
+    ///
+    /// This is cheap, but the first write to a namespace while the snapshot
+    /// is alive copies the index of that namespace, see [`Snapshot`].
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let root = self.root().to_path_buf();
+        let mut files = HashMap::new();
+
+        for id in walk_dir(&root) {
+            files.insert(id, Arc::new(FileMap::open(data_path(&root, id))?));
         }
 
-        if self.stats.num_bytes_deleted == 0 {
-            return;
+        let mut blobs = HashMap::new();
+
+        for id in walk_blobs(&root) {
+            blobs.insert(id, Arc::new(FileMap::open(blob_path(&root, id))?));
         }
 
-        let mut counter = 0;
+        let namespaces = self
+            .namespaces
+            .iter()
+            .map(|(_, space)| (space.name.clone(), space.index.clone()))
+            .collect();
+
+        Ok(Snapshot::new(
+            self.index.clone(),
+            namespaces,
+            files,
+            blobs,
+            root,
+        ))
+    }
+
+    /// Writes a consistent copy of the store to `dest`, which can be opened
+    /// with [`Bitcask::open`]. The active data file is sealed first, and the
+    /// sealed data files are hard linked into `dest` where possible, so the
+    /// checkpoint takes little time or space when `dest` is on the same
+    /// filesystem. `dest` must not already contain data files. Files that
+    /// have to be copied are synced, along with `dest`.
+    ///
+    /// [`SharedBitcask::checkpoint`] copies the files without holding up
+    /// writers, and is the one throttled by [`Options::io_rate_limit`].
+    ///
+    /// [`SharedBitcask::checkpoint`]: crate::SharedBitcask::checkpoint
+    pub fn checkpoint<P>(&mut self, dest: P) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let checkpoint = self.prepare_checkpoint(dest.as_ref(), false)?;
+        checkpoint.write(&RateLimiter::new(None))?;
+
+        Ok(())
+    }
+
+    /// Brings a checkpoint previously written to `dest` up to date, only
+    /// copying the data files that were created or changed since then and
+    /// removing the ones that have since been merged away.
+    pub fn checkpoint_incremental<P>(&mut self, dest: P) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let checkpoint = self.prepare_checkpoint(dest.as_ref(), true)?;
+        checkpoint.write(&RateLimiter::new(None))?;
+
+        Ok(())
+    }
+
+    /// Seals the active data file and the blob files and writes the index of
+    /// a checkpoint to `dest`, returning the checkpoint for its files to be
+    /// written once the store is released.
+    pub(crate) fn prepare_checkpoint(
+        &mut self,
+        dest: &Path,
+        incremental: bool,
+    ) -> Result<Checkpoint> {
+        if self.opts.read_only {
+            return Err(KeyValueStoreError::ReadOnly);
+        }
+
+        // blob files are sealed too, so the checkpoint can share them
+        self.swap_file()?;
+        self.blobs.seal()?;
+
+        let ids: Vec<u64> = walk_dir(self.root())
+            .into_iter()
+            .filter(|&id| id < self.counter)
+            .collect();
+
+        let checkpoint = checkpoint::prepare(
+            self.root(),
+            dest,
+            &ids,
+            self.counter,
+            |path| self.write_index(path),
+            incremental,
+        )?;
+
+        Ok(checkpoint)
+    }
+
+    /// Writes every live entry of every namespace to `writer` in the dump
+    /// format described in the [`dump`] module, returning the number of
+    /// entries written.
+    pub fn export<W>(&self, writer: W) -> Result<u64>
+    where
+        W: Write,
+    {
+        let mut dump = DumpWriter::new(writer)?;
+
+        for (name, index) in self.spaces() {
+            if let Some(name) = name {
+                dump.namespace(name)?;
+            }
+
+            for item in self.scan_index(index) {
+                let (k, v) = item?;
+                dump.write(v.timestamp(), &k, v.data())?;
+            }
+        }
+
+        dump.finish()
+    }
+
+    /// Like [`Bitcask::export`], but writes one JSON object per line.
+    pub fn export_json<W>(&self, mut writer: W) -> Result<u64>
+    where
+        W: Write,
+    {
+        let mut count = 0;
+
+        for (name, index) in self.spaces() {
+            if let Some(name) = name {
+                write_json_namespace(&mut writer, name)?;
+            }
+
+            for item in self.scan_index(index) {
+                let (k, v) = item?;
+                write_json_line(&mut writer, v.timestamp(), &k, v.data())?;
+                count += 1;
+            }
+        }
+
+        writer.flush()?;
+
+        Ok(count)
+    }
+
+    /// Inserts every entry of a dump written by [`Bitcask::export`], keeping
+    /// the original timestamps and creating the namespaces it holds. Entries
+    /// are written in batches, so entries read before an error is found in
+    /// the dump have already been inserted when the error is returned.
+    pub fn import<R>(&mut self, reader: R) -> Result<u64>
+    where
+        R: Read,
+    {
+        self.import_items(DumpReader::new(reader)?)
+    }
 
-        let d_path = data_path(&temp, counter);
-        let mut disk = new_data_file(d_path);
+    /// Like [`Bitcask::import`], but reads a dump written by
+    /// [`Bitcask::export_json`].
+    pub fn import_json<R>(&mut self, reader: R) -> Result<u64>
+    where
+        R: BufRead,
+    {
+        self.import_items(read_json_lines(reader))
+    }
 
-        let mut index = Index::new();
-        let mut files: HashMap<u64, std::fs::File> = HashMap::new();
+    fn import_items<I>(&mut self, items: I) -> Result<u64>
+    where
+        I: Iterator<Item = Result<dump::Item>>,
+    {
+        let mut batch = WriteBatch::new();
+        let mut namespace = DEFAULT_NAMESPACE;
+        let mut count = 0;
+
+        for item in items {
+            let record = match item? {
+                dump::Item::Record(record) => record,
+                dump::Item::Namespace(name) => {
+                    self.namespace(&name)?;
+                    namespace = self.namespaces.id(&name).expect("namespace");
+                    continue;
+                }
+            };
 
-        for (k, v) in self.index.entries() {
-            if disk.position() + v.size() > self.opts.file_size_limit {
-                let i_path = idx_path(&temp);
-                index.write(i_path);
-                counter += 1;
+            batch.put_at(namespace, record.timestamp, &record.key, &record.value);
+            count += 1;
 
-                disk = new_data_file(data_path(&temp, counter));
-                index.clear();
+            if batch.len() >= IMPORT_BATCH_SIZE {
+                self.write_batch(std::mem::take(&mut batch))?;
             }
+        }
+
+        self.write_batch(batch)?;
 
-            match files.get_mut(&v.file()) {
-                Some(f) => {
-                    f.seek(SeekFrom::Start(v.offset())).expect("seek");
+        Ok(count)
+    }
 
-                    let mut buf = vec![0u8; v.size() as usize];
-                    f.read_exact(&mut buf).expect("read exact");
+    pub fn stats(&self) -> Stats {
+        let ids = walk_dir(self.root());
+        let data_size = ids
+            .iter()
+            .filter_map(|&id| std::fs::metadata(data_path(self.root(), id)).ok())
+            .map(|m| m.len())
+            .sum();
+
+        let blob_ids = walk_blobs(self.root());
+        let blob_size = blob_ids
+            .iter()
+            .filter_map(|&id| std::fs::metadata(blob_path(self.root(), id)).ok())
+            .map(|m| m.len())
+            .sum();
+
+        let indexes = self.indexes();
+
+        Stats {
+            num_keys: indexes.iter().map(|index| index.len() as u64).sum(),
+            num_files: ids.len() as u64,
+            data_size,
+            live_size: indexes
+                .iter()
+                .flat_map(|index| index.locations())
+                .map(|e| e.size())
+                .sum(),
+            num_entries_deleted: self.stats.num_entries_deleted,
+            num_bytes_deleted: self.stats.num_bytes_deleted,
+            num_blob_files: blob_ids.len() as u64,
+            blob_size,
+            blob_bytes_deleted: self.blobs.bytes_dead(),
+            rate_limited_bytes: self.limiter.bytes(),
+            rate_limit_waits: self.limiter.waits(),
+            rate_limit_wait_time: self.limiter.waited(),
+        }
+    }
 
-                    let offset = disk.write(&buf).expect("write buf");
-                    index.insert(k, v.timestamp(), counter, offset, v.size());
+    /// Checks the checksum of every record in every data file and that every
+    /// entry in the index can be read, along with the checksum of its value
+    /// if it is kept in a blob file. It holds the store throughout, so it is
+    /// not throttled; [`SharedBitcask::verify`] is.
+    ///
+    /// [`SharedBitcask::verify`]: crate::SharedBitcask::verify
+    pub fn verify(&self) -> VerifyReport {
+        let files = walk_dir(self.root())
+            .into_iter()
+            .map(|id| {
+                (
+                    id,
+                    FileMap::open(data_path(self.root(), id)).map(|f| f.map()),
+                )
+            })
+            .collect();
+
+        verify::verify(
+            files,
+            &self.indexes(),
+            &FileCache::new(self.root()),
+            &RateLimiter::new(None),
+        )
+    }
+
+    /// Changes [`Options::io_rate_limit`], taking effect for background
+    /// merges, checkpoints and verification already running.
+    pub fn set_io_rate_limit(&self, limit: Option<u64>) {
+        self.limiter.set_rate(limit);
+    }
+
+    pub fn io_rate_limit(&self) -> Option<u64> {
+        self.limiter.rate()
+    }
+
+    /// Rewrites the blob files in which at least
+    /// [`Options::blob_garbage_ratio`] of the bytes are values that were
+    /// overwritten or deleted, copying their live values to the active blob
+    /// file and pointing their keys there, and deletes them. The data files
+    /// are left alone apart from a record for every moved value, and blob
+    /// files that are mostly live are not copied at all, so this can run
+    /// independently of [`Bitcask::merge`]. It holds the store throughout, so
+    /// it is not throttled by [`Options::io_rate_limit`]. Returns the number
+    /// of blob files deleted.
+    pub fn merge_blobs(&mut self) -> Result<usize> {
+        if self.opts.read_only {
+            return Err(KeyValueStoreError::ReadOnly);
+        }
+
+        // sealed first so that the active blob file can be rewritten too
+        self.blobs.seal()?;
+
+        let root = self.root().to_path_buf();
+        let mut maps: HashMap<u64, Arc<memmap::Mmap>> = HashMap::new();
+        let mut live: HashMap<u64, u64> = HashMap::new();
+        let mut values = Vec::new();
+
+        let sources = std::iter::once((DEFAULT_NAMESPACE, &self.index))
+            .chain(self.namespaces.iter().map(|(id, space)| (id, &space.index)));
+
+        for (namespace, index) in sources {
+            for item in index.entries() {
+                let (key, entry) = item?;
+                let map = match maps.get(&entry.file()) {
+                    Some(map) => map,
+                    None => {
+                        let map = FileMap::open(data_path(&root, entry.file()))?.map();
+                        maps.entry(entry.file()).or_insert(map)
+                    }
+                };
+
+                let start = entry.offset() as usize;
+                let record = map
+                    .get(start..start + entry.size() as usize)
+                    .ok_or_else(|| {
+                        KeyValueStoreError::InvalidFormat(
+                            "entry extends past the end of the data file".to_string(),
+                        )
+                    })?;
+
+                if let Some(blob) = blob::record_blob(record) {
+                    *live.entry(blob.file).or_default() += blob.len;
+                    values.push((namespace, key.into_owned(), entry.timestamp(), blob));
                 }
+            }
+        }
+
+        let mut garbage = HashSet::new();
+
+        for id in walk_blobs(&root) {
+            let path = blob_path(&root, id);
+            let size = std::fs::metadata(&path)?.len();
+            let size = size.saturating_sub(BLOB_FILE_HEADER.len() as u64);
+            let live = live.get(&id).copied().unwrap_or(0);
+            let dead = size.saturating_sub(live);
+
+            if live == 0 || dead as f64 >= self.opts.blob_garbage_ratio * size as f64 {
+                garbage.insert(id);
+            }
+        }
+
+        let mut blob_maps: HashMap<u64, Arc<memmap::Mmap>> = HashMap::new();
+        let mut ops = Vec::new();
+
+        for (namespace, key, timestamp, blob) in values {
+            if !garbage.contains(&blob.file) {
+                continue;
+            }
+
+            let map = match blob_maps.get(&blob.file) {
+                Some(map) => map,
                 None => {
-                    let mut f = OpenOptions::new()
-                        .read(true)
-                        .open(data_path(&self.opts.base_dir, v.file()))
-                        .expect("");
+                    let map = FileMap::open(blob_path(&root, blob.file))?.map();
+                    blob_maps.entry(blob.file).or_insert(map)
+                }
+            };
+
+            let start = blob.offset as usize;
+            let value = map.get(start..start + blob.len as usize).ok_or_else(|| {
+                KeyValueStoreError::InvalidFormat(
+                    "value extends past the end of the blob file".to_string(),
+                )
+            })?;
+
+            // the original checksum is kept, so a corrupted value still fails
+            // to verify after it is moved
+            let moved = BlobRef {
+                checksum: blob.checksum,
+                ..self.write_blob(value, blob.len)?
+            };
+
+            ops.push(Op::PutBlob {
+                namespace,
+                timestamp,
+                key,
+                blob: moved,
+            });
+        }
+
+        // the moved values must be on disk before the old copies are deleted
+        self.commit(ops, true)?;
+
+        for &id in &garbage {
+            self.blobs.remove(id)?;
+        }
+
+        Ok(garbage.len())
+    }
+
+    /// Appends `ops` to the data files, then flushes, and syncs if `sync` is
+    /// set, once for all of them. Only then are they applied to the index, so
+    /// readers never see writes that are not committed. Returns for each op
+    /// whether it changed the store, which for deletes is whether the key
+    /// existed.
+    pub(crate) fn commit(&mut self, ops: Vec<Op>, sync: bool) -> Result<Vec<bool>> {
+        if self.opts.read_only {
+            return Err(KeyValueStoreError::ReadOnly);
+        }
+
+        let mut changed = Vec::with_capacity(ops.len());
+        let mut locations = Vec::new();
+        // whether the keys written so far exist after the writes before them
+        let mut live: HashMap<(u32, &[u8]), bool> = HashMap::new();
+
+        for op in &ops {
+            match op {
+                Op::Put {
+                    namespace,
+                    timestamp,
+                    key,
+                    value,
+                } => {
+                    let blob = match self.is_blob_value(key, value) {
+                        true => Some(self.write_blob(value.as_slice(), value.len() as u64)?),
+                        false => None,
+                    };
+
+                    let location =
+                        self.append_put(*namespace, *timestamp, key, value, blob.as_ref(), sync)?;
+                    locations.push((location, blob));
+                    live.insert((*namespace, key), true);
+                    changed.push(true);
+                }
+                Op::PutBlob {
+                    namespace,
+                    timestamp,
+                    key,
+                    blob,
+                } => {
+                    let location =
+                        self.append_put(*namespace, *timestamp, key, &[], Some(blob), sync)?;
+                    locations.push((location, Some(*blob)));
+                    live.insert((*namespace, key), true);
+                    changed.push(true);
+                }
+                Op::Delete {
+                    namespace,
+                    timestamp,
+                    key,
+                } => {
+                    let exists = match live.get(&(*namespace, key.as_slice())) {
+                        Some(&exists) => exists,
+                        None => self.index_of(*namespace).get(key)?.is_some(),
+                    };
+
+                    if exists {
+                        match *namespace {
+                            DEFAULT_NAMESPACE => self.disk.delete(*timestamp, key),
+                            id => self.disk.append_tombstone(
+                                RecordKind::NamespaceTombstone,
+                                *timestamp,
+                                &namespaced_key(id, key),
+                            ),
+                        }
+                    }
+
+                    live.insert((*namespace, key), false);
+                    changed.push(exists);
+                }
+            }
+        }
+
+        // blobs are synced ahead of the records pointing to them
+        match sync {
+            true => {
+                self.blobs.sync()?;
+                self.disk.sync()?;
+            }
+            false => self.disk.flush()?,
+        }
 
-                    f.seek(SeekFrom::Start(v.offset())).expect("seek");
-                    let mut buf = vec![0u8; v.size() as usize];
-                    f.read_exact(&mut buf).expect("read exact");
+        let mut locations = locations.into_iter();
+
+        for (op, &changed) in ops.iter().zip(&changed) {
+            let (namespace, added, prev) = match op {
+                Op::Put {
+                    namespace,
+                    timestamp,
+                    key,
+                    value,
+                } => {
+                    let ((file_id, offset, size), blob) =
+                        locations.next().expect("location of put");
+                    let index = self.index_of_mut(*namespace);
+                    let prev = index.insert(key, *timestamp, file_id, offset, size)?;
+
+                    if *namespace == DEFAULT_NAMESPACE {
+                        self.update_secondary(key, prev.as_ref(), Some(value));
+                    }
+
+                    (*namespace, Some((file_id, size, blob)), prev)
+                }
+                Op::PutBlob {
+                    namespace,
+                    timestamp,
+                    key,
+                    ..
+                } => {
+                    let ((file_id, offset, size), blob) =
+                        locations.next().expect("location of put");
+                    let index = self.index_of_mut(*namespace);
+                    let prev = index.insert(key, *timestamp, file_id, offset, size)?;
+
+                    if *namespace == DEFAULT_NAMESPACE {
+                        let entry = index::Entry::from(*timestamp, file_id, offset, size);
+                        let value = self.read_entry_ref(&entry).ok();
+                        self.update_secondary(key, prev.as_ref(), value.as_deref());
+                    }
+
+                    (*namespace, Some((file_id, size, blob)), prev)
+                }
+                Op::Delete { namespace, key, .. } if changed => {
+                    let prev = self.index_of_mut(*namespace).delete(key)?;
 
-                    let offset = disk.write(&buf).expect("write buf");
-                    index.insert(k, v.timestamp(), counter, offset, v.size());
+                    if *namespace == DEFAULT_NAMESPACE {
+                        self.update_secondary(key, prev.as_ref(), None);
+                    }
 
-                    files.insert(v.file(), f);
+                    (*namespace, None, prev)
+                }
+                Op::Delete { namespace, .. } => (*namespace, None, None),
+            };
+
+            if let Some((file, size, blob)) = added {
+                if let Some(space) = self.namespaces.get_mut(namespace) {
+                    space.count_put(file, size, blob.as_ref());
                 }
             }
+
+            if let Some(prev) = prev {
+                self.mark_blobs_dead(namespace, Some(prev));
+                self.stats.count_deleted(prev.file(), 1, prev.size());
+
+                if let Some(space) = self.namespaces.get_mut(namespace) {
+                    space.count_removed(&prev);
+                }
+            }
+        }
+
+        self.write_index(idx_path(self.root()))?;
+
+        Ok(changed)
+    }
+
+    /// Appends the record of a put of `value`, or of `blob` if the value is
+    /// kept in a blob file, starting a new data file if it doesn't fit in
+    /// the active one. Returns the file, offset and size of the record.
+    fn append_put(
+        &mut self,
+        namespace: u32,
+        timestamp: u64,
+        key: &[u8],
+        value: &[u8],
+        blob: Option<&BlobRef>,
+        sync: bool,
+    ) -> Result<(u64, u64, u64)> {
+        let (kind, record_key) = match (namespace, blob) {
+            (DEFAULT_NAMESPACE, None) => (RecordKind::Value, Cow::Borrowed(key)),
+            (DEFAULT_NAMESPACE, Some(_)) => (RecordKind::Blob, Cow::Borrowed(key)),
+            (id, None) => (
+                RecordKind::NamespaceValue,
+                Cow::Owned(namespaced_key(id, key)),
+            ),
+            (id, Some(_)) => (
+                RecordKind::NamespaceBlob,
+                Cow::Owned(namespaced_key(id, key)),
+            ),
+        };
+
+        let encoded = blob.map(BlobRef::encode);
+        let value = encoded.as_deref().unwrap_or(value);
+
+        if self.disk.position() + compute_size(&record_key, value) > self.opts.file_size_limit {
+            self.seal_data_file(sync)?;
         }
 
-        let i_path = idx_path(&temp);
-        index.write(i_path);
-        disk.flush();
-        delete_files(&self.opts.base_dir);
+        let (offset, size) = self.disk.append_record(kind, timestamp, &record_key, value);
+
+        Ok((self.counter, offset, size))
+    }
+
+    /// Whether the value of a put goes to a blob file, which it does if it
+    /// is larger than the blob threshold or doesn't fit in a data file.
+    fn is_blob_value(&self, key: &[u8], value: &[u8]) -> bool {
+        let large = match self.opts.blob_threshold {
+            Some(threshold) => value.len() as u64 > threshold,
+            None => false,
+        };
 
-        self.disk = disk;
-        self.counter = counter;
-        self.index = index;
+        large || compute_size(key, value) > self.opts.file_size_limit
+    }
 
-        let src_path = idx_path(&temp);
-        let dest_path = idx_path(&self.opts.base_dir);
-        std::fs::rename(src_path, dest_path).unwrap();
+    /// Appends `len` bytes from `reader` to the active blob file.
+    fn write_blob<R>(&mut self, reader: R, len: u64) -> Result<BlobRef>
+    where
+        R: Read,
+    {
+        self.blobs
+            .append(reader, len, self.opts.blob_file_size_limit)
+    }
 
-        for i in 0..counter + 1 {
-            let src_path = data_path(&temp, i);
-            let dest_path = data_path(&self.opts.base_dir, i);
-            std::fs::rename(src_path, dest_path).unwrap();
+    /// Counts the values of the replaced `entries` of the namespace
+    /// `namespace` that are kept in blob files as garbage in them.
+    fn mark_blobs_dead<I>(&mut self, namespace: u32, entries: I)
+    where
+        I: IntoIterator<Item = index::Entry>,
+    {
+        if !self.blobs.any() {
+            return;
         }
 
-        self.stats.num_bytes_deleted = 0;
-        self.stats.num_entries_deleted = 0;
+        let mut space = self.namespaces.get_mut(namespace);
+
+        blob::entry_blobs(&self.opts.base_dir, entries, |blob| {
+            self.blobs.mark_dead(blob.file, blob.len);
+
+            if let Some(space) = space.as_deref_mut() {
+                space.count_blob_removed(&blob);
+            }
+        });
     }
 
-    fn swap_file(&mut self) {
-        self.flush();
+    fn remove(&mut self, ts: u64, key: &[u8]) -> Result<bool> {
+        match self.index.delete(key)? {
+            Some(e) => {
+                self.update_secondary(key, Some(&e), None);
+                self.mark_blobs_dead(DEFAULT_NAMESPACE, Some(e));
+                self.disk.delete(ts, key);
+                self.stats.count_deleted(e.file(), 1, e.size());
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
 
-        let path = idx_path(&self.root());
-        self.index.write(path);
+    /// Starts a new data file once the active one is full, syncing the full
+    /// one first if `sync`, or keeping a handle to it if the commit leaves
+    /// syncing to the caller.
+    fn seal_data_file(&mut self, sync: bool) -> Result<()> {
+        if sync {
+            self.disk.sync()?;
+        } else if let Some(unsynced) = &mut self.unsynced {
+            unsynced.push(self.disk.try_clone_file()?);
+        }
+
+        self.swap_file()
+    }
+
+    fn swap_file(&mut self) -> Result<()> {
+        self.flush()?;
+
+        let data_path = data_path(self.root(), self.counter + 1);
+        self.disk = new_data_file(data_path)?;
         self.counter += 1;
 
-        let data_path = data_path(&self.root(), self.counter);
-        let disk = new_data_file(data_path);
-        self.disk = disk;
+        Ok(())
+    }
+
+    fn write_index<P>(&self, path: P) -> io::Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        index_file::write(path, &self.index, &self.namespaces, &self.secondary)
+    }
+
+    /// Writes the index file to a temporary file, syncs it and renames it
+    /// over the index, so after a crash the index on disk is either the old
+    /// or the new one, and the new one once this returns.
+    fn replace_index(&self) -> io::Result<()> {
+        let root = self.root();
+        let tmp = tmp_idx_path(root);
+
+        self.write_index(&tmp)?;
+        File::open(&tmp)?.sync_all()?;
+        fs::rename(&tmp, idx_path(root))?;
+
+        sync_dir(root)
+    }
+
+    /// Updates the secondary indexes for a write of `key` replacing the entry
+    /// `prev` with `value`, reading the replaced value back to find the index
+    /// keys it was listed under.
+    fn update_secondary(&mut self, key: &[u8], prev: Option<&index::Entry>, value: Option<&[u8]>) {
+        self.secondary.forget_unregistered();
+
+        if self.secondary.is_empty() {
+            return;
+        }
+
+        let old = prev.and_then(|e| self.read_entry_ref(e).ok());
+        self.secondary.update(key, old.as_deref(), value);
+    }
+
+    /// The index of the default namespace, followed by the name and index of
+    /// every other namespace in order of name.
+    fn spaces(&self) -> Vec<(Option<&str>, &Index)> {
+        let mut named: Vec<_> = self
+            .namespaces
+            .iter()
+            .map(|(_, space)| (Some(space.name.as_str()), &space.index))
+            .collect();
+        named.sort_by_key(|(name, _)| *name);
+
+        std::iter::once((None, &self.index)).chain(named).collect()
+    }
+
+    /// The indexes of the default namespace and every other namespace.
+    fn indexes(&self) -> Vec<&Index> {
+        std::iter::once(&self.index)
+            .chain(self.namespaces.iter().map(|(_, space)| &space.index))
+            .collect()
+    }
+
+    fn index_of(&self, namespace: u32) -> &Index {
+        match namespace {
+            DEFAULT_NAMESPACE => &self.index,
+            id => &self.namespaces.get(id).expect("namespace").index,
+        }
+    }
+
+    fn index_of_mut(&mut self, namespace: u32) -> &mut Index {
+        match namespace {
+            DEFAULT_NAMESPACE => &mut self.index,
+            id => &mut self.namespaces.get_mut(id).expect("namespace").index,
+        }
     }
 }
 
 impl Drop for Bitcask {
     fn drop(&mut self) {
-        self.flush();
+        // errors can't be returned from here; callers who need them flush or
+        // close the store first
+        let _ = self.flush();
     }
 }
 
-fn new_data_file<P>(path: P) -> DiskTable
+fn prefix_bounds<'a>(
+    prefix: &'a [u8],
+    end: &'a Option<Vec<u8>>,
+) -> (Bound<&'a [u8]>, Bound<&'a [u8]>) {
+    let end = match end {
+        Some(end) => Bound::Excluded(end.as_slice()),
+        None => Bound::Unbounded,
+    };
+
+    (Bound::Included(prefix), end)
+}
+
+fn new_data_file<P>(path: P) -> io::Result<DiskTable>
 where
     P: AsRef<Path>,
 {
     let mut file = OpenOptions::new()
         .create(true)
-        .write(true)
         .append(true)
-        .open(path.as_ref())
-        .expect("open file");
+        .open(path.as_ref())?;
 
-    file.write_all(DATA_FILE_HEADER).expect("write header");
-    file.flush().expect("flush");
+    file.write_all(DATA_FILE_HEADER)?;
+    file.flush()?;
 
     DiskTable::open(file)
 }
@@ -0,0 +1,610 @@
+use super::batch::Op;
+use super::blob::{entry_blobs, BlobRef};
+use super::cursor::Cursor;
+use super::error::{KeyValueStoreError, Result};
+use super::index::{Entry, Index};
+use super::iter::{FileCache, Keys, RangeIter};
+use super::reader::{EntryReader, RecordKind, Value, ValueRef};
+use super::settings::{IndexKind, Metrics, NamespaceStats, SyncPolicy};
+use super::util::{compute_size, idx_path, prefix_successor};
+use super::{prefix_bounds, Bitcask};
+use crate::api::WriteExt;
+use chrono::Utc;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+
+/// The id of the default namespace, whose keys are stored without a prefix.
+pub const DEFAULT_NAMESPACE: u32 = 0;
+
+/// The named namespaces of a store and their indexes. The default namespace
+/// is not part of it; its index is [`Bitcask::index`].
+///
+/// In the data files, the key of a record in a namespace starts with the
+/// namespace id as a big endian `u32`. A namespace is created by a record
+/// whose key is its id followed by its name, and dropped by a record whose key
+/// is its id, so dropping a namespace writes a single record no matter how
+/// many keys it holds. The records left behind are removed by the next merge.
+///
+/// Merges may move the create record of a namespace into a data file after
+/// its keys, and namespace ids are never reused, so when the data files are
+/// loaded the keys of a namespace not yet created are kept aside until its
+/// create record is read.
+pub struct Namespaces {
+    names: BTreeMap<String, u32>,
+    spaces: HashMap<u32, Space>,
+    next_id: u32,
+    kind: IndexKind,
+    /// The directory the indexes read keys from, for hashed key indexes.
+    root: PathBuf,
+    /// Keys loaded for namespaces whose create record has not been read yet.
+    pending: HashMap<u32, Index>,
+    /// Namespaces whose drop record has been read.
+    dropped: HashSet<u32>,
+}
+
+pub struct Space {
+    pub name: String,
+    pub index: Index,
+    pub stats: Metrics,
+    /// The number and size of the records the index points to in each data
+    /// file.
+    pub live: HashMap<u64, (u64, u64)>,
+    /// Bytes of the values of the live keys kept in each blob file.
+    pub blob_bytes: HashMap<u64, u64>,
+}
+
+impl Space {
+    /// Size of the records the index points to.
+    pub fn live_size(&self) -> u64 {
+        self.live.values().map(|(_, bytes)| bytes).sum()
+    }
+
+    /// Counts a record of `size` bytes written to the data file `file` of
+    /// the namespace, whose value is kept in `blob` if set.
+    pub fn count_put(&mut self, file: u64, size: u64, blob: Option<&BlobRef>) {
+        let live = self.live.entry(file).or_default();
+        live.0 += 1;
+        live.1 += size;
+
+        if let Some(blob) = blob {
+            *self.blob_bytes.entry(blob.file).or_default() += blob.len;
+        }
+    }
+
+    /// Counts the live record of `entry` as moved to the data file `file` by
+    /// a merge.
+    pub fn count_moved(&mut self, entry: &Entry, file: u64) {
+        if let Some(live) = self.live.get_mut(&entry.file()) {
+            live.0 = live.0.saturating_sub(1);
+            live.1 = live.1.saturating_sub(entry.size());
+
+            if live.0 == 0 {
+                self.live.remove(&entry.file());
+            }
+        }
+
+        let live = self.live.entry(file).or_default();
+        live.0 += 1;
+        live.1 += entry.size();
+    }
+
+    /// Counts the record of `entry`, which was overwritten or deleted, as
+    /// garbage.
+    pub fn count_removed(&mut self, entry: &Entry) {
+        if let Some(live) = self.live.get_mut(&entry.file()) {
+            live.0 = live.0.saturating_sub(1);
+            live.1 = live.1.saturating_sub(entry.size());
+
+            if live.0 == 0 {
+                self.live.remove(&entry.file());
+            }
+        }
+
+        self.stats.count_deleted(entry.file(), 1, entry.size());
+    }
+
+    /// Stops counting the value `blob` of a key that was overwritten or
+    /// deleted.
+    pub fn count_blob_removed(&mut self, blob: &BlobRef) {
+        if let Some(bytes) = self.blob_bytes.get_mut(&blob.file) {
+            *bytes = bytes.saturating_sub(blob.len);
+        }
+    }
+}
+
+impl Namespaces {
+    pub fn new(kind: IndexKind, root: &Path) -> Self {
+        Self {
+            names: BTreeMap::new(),
+            spaces: HashMap::new(),
+            next_id: DEFAULT_NAMESPACE + 1,
+            kind,
+            root: root.to_path_buf(),
+            pending: HashMap::new(),
+            dropped: HashSet::new(),
+        }
+    }
+
+    pub fn id(&self, name: &str) -> Option<u32> {
+        self.names.get(name).copied()
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Space> {
+        self.spaces.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: u32) -> Option<&mut Space> {
+        self.spaces.get_mut(&id)
+    }
+
+    /// Iterates over the namespaces in the order of their names.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &Space)> + '_ {
+        self.names.values().map(|&id| (id, &self.spaces[&id]))
+    }
+
+    pub fn next_id(&self) -> u32 {
+        self.next_id
+    }
+
+    pub fn set_next_id(&mut self, next_id: u32) {
+        self.next_id = next_id;
+    }
+
+    /// Reserves the id for a new namespace. Ids are not reused while records
+    /// of a dropped namespace may still be in the data files.
+    pub fn allocate(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    pub fn insert(&mut self, id: u32, name: String) {
+        self.next_id = self.next_id.max(id + 1);
+        self.names.insert(name.clone(), id);
+
+        let index = self
+            .pending
+            .remove(&id)
+            .unwrap_or_else(|| Index::new(self.kind, &self.root));
+
+        let space = Space {
+            name,
+            index,
+            stats: Metrics::new(),
+            live: HashMap::new(),
+            blob_bytes: HashMap::new(),
+        };
+        self.spaces.insert(id, space);
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        for space in self.spaces.values_mut() {
+            space.index.shrink_to_fit();
+        }
+    }
+
+    /// Counts the live bytes of every namespace, and the bytes of their values
+    /// in each blob file if the store has `blobs`, once the indexes are
+    /// loaded. Writes keep the counts up to date from then on, so dropping a
+    /// namespace does not have to walk its keys.
+    pub fn count_usage(&mut self, blobs: bool) {
+        for space in self.spaces.values_mut() {
+            for entry in space.index.locations() {
+                let live = space.live.entry(entry.file()).or_default();
+                live.0 += 1;
+                live.1 += entry.size();
+            }
+
+            if blobs {
+                let usage = &mut space.blob_bytes;
+                entry_blobs(&self.root, space.index.locations(), |blob| {
+                    *usage.entry(blob.file).or_default() += blob.len;
+                });
+            }
+        }
+    }
+
+    /// Drops the maps of the data files `ids` the indexes read keys from,
+    /// and the garbage counted in them.
+    pub fn forget_files(&mut self, ids: &[u64]) {
+        for space in self.spaces.values_mut() {
+            space.index.forget_files(ids);
+            space.stats.forget_files(ids);
+        }
+    }
+
+    pub fn remove(&mut self, id: u32) -> Option<Space> {
+        let space = self.spaces.remove(&id)?;
+
+        // the name may already belong to a namespace created after this one
+        // was dropped, when the drop record was moved past its create record
+        if self.names.get(&space.name) == Some(&id) {
+            self.names.remove(&space.name);
+        }
+
+        Some(space)
+    }
+
+    /// Applies the records of the data file `file_id` to `index`, the index
+    /// of the default namespace, and to the namespaces.
+    pub fn load_data<P>(&mut self, path: P, file_id: u64, index: &mut Index) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let reader = EntryReader::open(path.as_ref())?;
+
+        for entry in reader {
+            match entry.kind() {
+                RecordKind::Value | RecordKind::Blob | RecordKind::Tombstone => {
+                    index.load(entry.key(), &entry, file_id)?;
+                }
+                RecordKind::NamespaceValue
+                | RecordKind::NamespaceBlob
+                | RecordKind::NamespaceTombstone => {
+                    // records of dropped namespaces are skipped
+                    if let Some((id, key)) = split_key(entry.key()) {
+                        if let Some(space) = self.spaces.get_mut(&id) {
+                            space.index.load(key, &entry, file_id)?;
+                        } else if !self.dropped.contains(&id) {
+                            let (kind, root) = (self.kind, &self.root);
+                            let index = self
+                                .pending
+                                .entry(id)
+                                .or_insert_with(|| Index::new(kind, root));
+                            index.load(key, &entry, file_id)?;
+                        }
+                    }
+                }
+                RecordKind::NamespaceCreate => {
+                    if let Some((id, name)) = split_key(entry.key()) {
+                        if let Ok(name) = std::str::from_utf8(name) {
+                            // a merge interrupted before deleting the files
+                            // it rewrote leaves copies of their records
+                            if !self.spaces.contains_key(&id) && !self.dropped.contains(&id) {
+                                self.insert(id, name.to_string());
+                            }
+                        }
+                    }
+                }
+                RecordKind::NamespaceDrop => {
+                    if let Some((id, _)) = split_key(entry.key()) {
+                        self.next_id = self.next_id.max(id + 1);
+                        self.remove(id);
+                        self.pending.remove(&id);
+                        self.dropped.insert(id);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drops the keys loaded for namespaces that were never created, once
+    /// all the data files are loaded.
+    pub fn finish_load(&mut self) {
+        self.pending.clear();
+        self.dropped.clear();
+    }
+}
+
+/// The key under which `key` of the namespace `id` is stored in the data
+/// files.
+pub fn namespaced_key(id: u32, key: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(4 + key.len());
+    out.extend_from_slice(&id.to_be_bytes());
+    out.extend_from_slice(key);
+    out
+}
+
+/// Splits a key as stored in the data files into the namespace id and the
+/// key within the namespace.
+pub fn split_key(key: &[u8]) -> Option<(u32, &[u8])> {
+    let (id, rest) = key.split_first_chunk::<4>()?;
+    Some((u32::from_be_bytes(*id), rest))
+}
+
+impl Bitcask {
+    /// Returns the namespace `name`, creating it if it does not exist yet.
+    ///
+    /// Namespaces share the data files of the store but each has its own
+    /// keys, so the same key can hold different values in different
+    /// namespaces. The methods of [`Bitcask`] itself work on the default
+    /// namespace, which is not listed by [`Bitcask::namespaces`]. Snapshots,
+    /// dumps, checkpoints and merges cover every namespace; see
+    /// [`Snapshot::namespace`](crate::Snapshot::namespace) and
+    /// [`Namespace::cursor`].
+    pub fn namespace(&mut self, name: &str) -> Result<Namespace<'_>> {
+        if let Some(id) = self.namespaces.id(name) {
+            return Ok(Namespace { kv: self, id });
+        }
+
+        if self.opts.read_only {
+            return Err(KeyValueStoreError::ReadOnly);
+        }
+
+        if name.is_empty() {
+            return Err(KeyValueStoreError::InvalidFormat(
+                "namespace name is empty".to_string(),
+            ));
+        }
+
+        let id = self.namespaces.allocate();
+        let key = namespaced_key(id, name.as_bytes());
+        self.append_namespace_record(RecordKind::NamespaceCreate, &key)?;
+        self.namespaces.insert(id, name.to_string());
+        self.write_index(idx_path(self.root()))?;
+
+        Ok(Namespace { kv: self, id })
+    }
+
+    /// The names of all namespaces, in order.
+    pub fn namespaces(&self) -> Vec<String> {
+        self.namespaces.names.keys().cloned().collect()
+    }
+
+    /// Drops the namespace `name` and every key in it, returning whether it
+    /// existed. This writes a single record and takes no longer for a large
+    /// namespace than for a small one; the space taken by the keys is
+    /// reclaimed by the next merge.
+    pub fn drop_namespace(&mut self, name: &str) -> Result<bool> {
+        if self.opts.read_only {
+            return Err(KeyValueStoreError::ReadOnly);
+        }
+
+        let id = match self.namespaces.id(name) {
+            Some(id) => id,
+            None => return Ok(false),
+        };
+
+        self.append_namespace_record(RecordKind::NamespaceDrop, &id.to_be_bytes())?;
+
+        let space = self.namespaces.remove(id).expect("namespace");
+
+        for (&file, &bytes) in &space.blob_bytes {
+            self.blobs.mark_dead(file, bytes);
+        }
+
+        for (&file, &(entries, bytes)) in &space.live {
+            self.stats.count_deleted(file, entries, bytes);
+        }
+
+        self.write_index(idx_path(self.root()))?;
+
+        Ok(true)
+    }
+
+    /// Appends a record creating or dropping a namespace, flushing and
+    /// syncing it like a commit.
+    fn append_namespace_record(&mut self, kind: RecordKind, key: &[u8]) -> Result<()> {
+        let sync = self.opts.sync == SyncPolicy::Always;
+        let ts = Utc::now().timestamp() as u64;
+
+        if self.disk.position() + compute_size(key, &[]) > self.opts.file_size_limit {
+            self.seal_data_file(sync)?;
+        }
+
+        match kind.is_tombstone() {
+            true => self.disk.append_tombstone(kind, ts, key),
+            false => {
+                self.disk.append_record(kind, ts, key, &[]);
+            }
+        }
+
+        match sync {
+            true => self.disk.sync()?,
+            false => self.disk.flush()?,
+        }
+
+        Ok(())
+    }
+}
+
+/// A named key space of a store, returned by [`Bitcask::namespace`].
+pub struct Namespace<'a> {
+    kv: &'a mut Bitcask,
+    id: u32,
+}
+
+impl Namespace<'_> {
+    pub fn name(&self) -> &str {
+        &self.space().name
+    }
+
+    pub fn len(&self) -> usize {
+        self.index().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index().is_empty()
+    }
+
+    /// See [`Bitcask::exists`].
+    pub fn exists(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.index().get(key)?.is_some())
+    }
+
+    /// See [`Bitcask::get`].
+    pub fn get(&self, key: &[u8]) -> Result<Option<Value>> {
+        self.index()
+            .get(key)?
+            .map(|entry| self.kv.read_entry(&entry))
+            .transpose()
+    }
+
+    /// See [`Bitcask::get_ref`].
+    pub fn get_ref(&self, key: &[u8]) -> Result<Option<ValueRef>> {
+        self.index()
+            .get(key)?
+            .map(|entry| self.kv.read_entry_ref(&entry))
+            .transpose()
+    }
+
+    /// See [`Bitcask::with_value`].
+    pub fn with_value<F, T>(&self, key: &[u8], f: F) -> Result<Option<T>>
+    where
+        F: FnOnce(&[u8]) -> T,
+    {
+        Ok(self.get_ref(key)?.map(|value| f(&value)))
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        if let Some(v) = self.get(key)? {
+            if v.data() == value {
+                return Ok(());
+            }
+        }
+
+        let op = Op::Put {
+            namespace: self.id,
+            timestamp: Utc::now().timestamp() as u64,
+            key: key.to_vec(),
+            value: value.to_vec(),
+        };
+
+        let sync = self.kv.opts.sync == SyncPolicy::Always;
+        self.kv.commit(vec![op], sync)?;
+
+        Ok(())
+    }
+
+    /// Deletes `key`, returning whether it existed.
+    pub fn delete(&mut self, key: &[u8]) -> Result<bool> {
+        let op = Op::Delete {
+            namespace: self.id,
+            timestamp: Utc::now().timestamp() as u64,
+            key: key.to_vec(),
+        };
+
+        let sync = self.kv.opts.sync == SyncPolicy::Always;
+        let changed = self.kv.commit(vec![op], sync)?;
+
+        Ok(changed[0])
+    }
+
+    pub fn keys<R>(&self, range: R) -> Result<Keys<'_>>
+    where
+        R: RangeBounds<[u8]>,
+    {
+        Ok(Keys::new(self.index().range(range)?))
+    }
+
+    pub fn iter<R>(&self, range: R) -> Result<RangeIter<'_>>
+    where
+        R: RangeBounds<[u8]>,
+    {
+        let range = self.index().range(range)?;
+        Ok(RangeIter::new(FileCache::new(self.kv.root()), range))
+    }
+
+    /// See [`Bitcask::cursor`].
+    pub fn cursor(&self) -> Result<Cursor<'_>> {
+        Cursor::new(FileCache::new(self.kv.root()), self.index())
+    }
+
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<RangeIter<'_>> {
+        let end = prefix_successor(prefix);
+        self.iter(prefix_bounds(prefix, &end))
+    }
+
+    pub fn scan_prefix_keys(&self, prefix: &[u8]) -> Result<Keys<'_>> {
+        let end = prefix_successor(prefix);
+        self.keys(prefix_bounds(prefix, &end))
+    }
+
+    pub fn stats(&self) -> NamespaceStats {
+        let space = self.space();
+
+        NamespaceStats {
+            num_keys: space.index.len() as u64,
+            live_size: space.live_size(),
+            num_entries_deleted: space.stats.num_entries_deleted,
+            num_bytes_deleted: space.stats.num_bytes_deleted,
+        }
+    }
+
+    fn space(&self) -> &Space {
+        self.kv.namespaces.get(self.id).expect("namespace")
+    }
+
+    fn index(&self) -> &Index {
+        &self.space().index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcask::settings::Options;
+    use crate::bitcask::util::walk_dir;
+    use std::fs;
+
+    fn store(name: &str) -> Options {
+        let root =
+            std::env::temp_dir().join(format!("kvs-namespace-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        Options::new(root)
+    }
+
+    fn value(kv: &mut Bitcask, space: &str, key: &[u8]) -> Option<Vec<u8>> {
+        let space = kv.namespace(space).unwrap();
+        space.get(key).unwrap().map(|v| v.data().to_vec())
+    }
+
+    /// Fills the namespaces a and b and the default namespace with the key
+    /// k, then drops a and creates it again with the key n.
+    fn fill(opts: &Options) -> Bitcask {
+        let mut kv = Bitcask::open(opts.clone()).unwrap();
+
+        kv.insert(b"k", b"default").unwrap();
+        kv.namespace("a").unwrap().insert(b"k", b"a").unwrap();
+        kv.namespace("b").unwrap().insert(b"k", b"b").unwrap();
+
+        assert!(kv.drop_namespace("a").unwrap());
+        assert!(!kv.drop_namespace("a").unwrap());
+        assert_eq!(kv.namespaces(), ["b"]);
+
+        kv.namespace("a").unwrap().insert(b"n", b"a").unwrap();
+
+        kv
+    }
+
+    fn check(kv: &mut Bitcask) {
+        assert_eq!(kv.namespaces(), ["a", "b"]);
+        assert_eq!(kv.get(b"k").unwrap().unwrap().data(), b"default");
+        assert_eq!(value(kv, "a", b"k"), None);
+        assert_eq!(value(kv, "a", b"n").unwrap(), b"a");
+        assert_eq!(value(kv, "b", b"k").unwrap(), b"b");
+        assert_eq!(kv.namespace("a").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn dropped_namespace_stays_dropped() {
+        let opts = store("drop");
+        let mut kv = fill(&opts);
+        check(&mut kv);
+        drop(kv);
+
+        check(&mut Bitcask::open(opts.clone()).unwrap());
+
+        // rebuilt from the data files
+        fs::remove_file(idx_path(&opts.base_dir)).unwrap();
+        check(&mut Bitcask::open(opts).unwrap());
+    }
+
+    #[test]
+    fn merge_reclaims_dropped_namespace() {
+        let opts = store("merge");
+        let mut kv = fill(&opts);
+        kv.swap_file().unwrap();
+        kv.merge().unwrap();
+
+        assert!(!walk_dir(kv.root()).contains(&0));
+        check(&mut kv);
+        drop(kv);
+
+        fs::remove_file(idx_path(&opts.base_dir)).unwrap();
+        check(&mut Bitcask::open(opts).unwrap());
+    }
+}
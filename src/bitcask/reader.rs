@@ -1,58 +1,144 @@
+use super::blob::{self, BlobRef};
 use super::error::KeyValueStoreError;
 use crate::api::ReadExt;
-use crate::bitcask::util::{crc_init, DATA_FILE_HEADER, INDEX_FILE_HEADER};
+use crate::bitcask::util::{crc_init, DATA_FILE_HEADER, INDEX_FILE_HEADER, INDEX_FORMAT_MARKER};
 use memmap::Mmap;
+use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
-use std::io::{BufReader, Cursor, Read, Seek};
+use std::io::{self, BufRead, BufReader, Cursor, Read, Seek};
+use std::ops::Deref;
 use std::path::{Path, PathBuf};
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
 
 pub struct FileMap {
-    mmap: RwLock<memmap::Mmap>,
+    mmap: RwLock<Arc<Mmap>>,
     path: PathBuf,
 }
 
 impl FileMap {
-    pub fn new<P>(path: P) -> Self
+    pub fn open<P>(path: P) -> io::Result<Self>
     where
         P: AsRef<Path>,
     {
-        let f = OpenOptions::new()
-            .read(true)
-            .open(&path)
-            .expect("open file");
+        let f = OpenOptions::new().read(true).open(&path)?;
+        let mmap = unsafe { Mmap::map(&f)? };
 
-        let mmap = unsafe { Mmap::map(&f).expect("mmap") };
-
-        Self {
-            mmap: RwLock::new(mmap),
+        Ok(Self {
+            mmap: RwLock::new(Arc::new(mmap)),
             path: path.as_ref().to_path_buf(),
-        }
+        })
     }
 
-    pub fn get(&self, offset: u64, size: u64) -> Result<Value, KeyValueStoreError> {
-        {
-            let guard = self.mmap.read().expect("read guard");
+    /// The current map of the file.
+    pub fn map(&self) -> Arc<Mmap> {
+        Arc::clone(&self.mmap.read().expect("read guard"))
+    }
+
+    /// Returns the map holding the record at `offset`, mapping the file
+    /// again if the record was appended after the current map was made.
+    fn map_record(&self, offset: u64, size: u64) -> Result<Arc<Mmap>, KeyValueStoreError> {
+        // the read guard is released before taking the write guard
+        let stale = offset + size > self.mmap.read().expect("read guard").len() as u64;
 
-            if offset + size > guard.len() as u64 {
-                let f = OpenOptions::new()
-                    .read(true)
-                    .open(&self.path)
-                    .expect("open file");
+        if stale {
+            let f = OpenOptions::new().read(true).open(&self.path)?;
+            let mmap = unsafe { memmap::Mmap::map(&f)? };
 
-                let mmap = unsafe { memmap::Mmap::map(&f).expect("mmap") };
+            let mut guard = self.mmap.write().expect("write guard");
+            *guard = Arc::new(mmap);
+        }
+
+        let map = Arc::clone(&self.mmap.read().expect("read guard"));
+
+        if offset + size > map.len() as u64 {
+            return Err(KeyValueStoreError::InvalidFormat(
+                "entry extends past the end of the data file".to_string(),
+            ));
+        }
+
+        Ok(map)
+    }
 
-                let mut guard = self.mmap.write().expect("write guard");
-                *guard = mmap;
+    /// Reads the key of the value record at `offset`, without the namespace
+    /// id that starts the keys of records in a namespace. Unlike
+    /// [`FileMap::get`], this does not check the checksum.
+    pub fn key(&self, offset: u64, size: u64) -> Result<Vec<u8>, KeyValueStoreError> {
+        let map = self.map_record(offset, size)?;
+        let bytes = &map[offset as usize..(offset + size) as usize];
+
+        if bytes.len() < 29 {
+            return Err(KeyValueStoreError::InvalidFormat(
+                "entry is shorter than a record header".to_string(),
+            ));
+        }
+
+        let mut cursor = Cursor::new(bytes);
+        cursor.seek(std::io::SeekFrom::Start(12)).expect("seek");
+        let flag = cursor.read_u8()?;
+        let key_len = cursor.read_u64()?;
+        let _value_len = cursor.read_u64()?;
+
+        let start = cursor.position() as usize;
+        let key = start
+            .checked_add(key_len as usize)
+            .and_then(|end| bytes.get(start..end))
+            .ok_or_else(|| {
+                KeyValueStoreError::InvalidFormat("key past end of record".to_string())
+            })?;
+
+        match RecordKind::from_flag(flag) {
+            Some(RecordKind::Value | RecordKind::Blob) => Ok(key.to_vec()),
+            Some(RecordKind::NamespaceValue | RecordKind::NamespaceBlob) if key.len() >= 4 => {
+                Ok(key[4..].to_vec())
             }
+            _ => Err(KeyValueStoreError::InvalidFormat(format!(
+                "record type {:#04x} holds no value",
+                flag
+            ))),
         }
+    }
 
-        let guard = self.mmap.read().expect("");
-        let bytes = &guard[offset as usize..(offset + size) as usize];
+    pub fn get(&self, offset: u64, size: u64) -> Result<Value, KeyValueStoreError> {
+        let value = self.get_ref(offset, size)?;
+        value.check()?;
+
+        Ok(value.to_value())
+    }
+
+    /// Like [`FileMap::get`], but returns the value in place in the map
+    /// instead of copying it, and doesn't check the checksum of values kept
+    /// in blob files, which would mean reading them whole. Blob files are
+    /// read from the directory of this file.
+    pub fn get_ref(&self, offset: u64, size: u64) -> Result<ValueRef, KeyValueStoreError> {
+        let root = self.path.parent().expect("store directory");
+        self.get_ref_with(offset, size, |id| blob::map_blob(root, &HashMap::new(), id))
+    }
+
+    /// Like [`FileMap::get_ref`], reading blob files with `map_blob`.
+    pub fn get_ref_with<F>(
+        &self,
+        offset: u64,
+        size: u64,
+        map_blob: F,
+    ) -> Result<ValueRef, KeyValueStoreError>
+    where
+        F: FnOnce(u64) -> Result<Arc<Mmap>, KeyValueStoreError>,
+    {
+        let map = self.map_record(offset, size)?;
+
+        let bytes = &map[offset as usize..(offset + size) as usize];
+
+        // the checksum, timestamp, flag and key length every record starts
+        // with; the value length is checked once the record isn't a tombstone
+        if bytes.len() < 21 {
+            return Err(KeyValueStoreError::InvalidFormat(
+                "entry is shorter than a record header".to_string(),
+            ));
+        }
 
         let mut cursor = Cursor::new(bytes);
 
-        let checksum = cursor.read_u32().expect("read u32");
+        let checksum = cursor.read_u32()?;
         let crc = crc_init();
 
         let mut digest = crc.digest();
@@ -63,146 +149,479 @@ impl FileMap {
             return Err(KeyValueStoreError::ChecksumFailed);
         }
 
-        let timestamp = cursor.read_u64().expect("read u64");
-        let deleted = cursor.read_u8().expect("read u8");
-        let key_len = cursor.read_u64().expect("read u64");
+        let timestamp = cursor.read_u64()?;
+        let flag = cursor.read_u8()?;
+        let key_len = cursor.read_u64()?;
+
+        let kind = match RecordKind::from_flag(flag) {
+            Some(kind) if kind.is_tombstone() => return Err(KeyValueStoreError::ItemDeleted),
+            Some(kind) => kind,
+            None => {
+                return Err(KeyValueStoreError::InvalidFormat(format!(
+                    "unknown record type {:#04x}",
+                    flag
+                )))
+            }
+        };
 
-        if deleted == u8::MAX {
-            return Err(KeyValueStoreError::ItemDeleted);
+        if bytes.len() < 29 {
+            return Err(KeyValueStoreError::InvalidFormat(
+                "entry is shorter than a record header".to_string(),
+            ));
         }
 
-        let value_len = cursor.read_u64().expect("read u64");
+        let value_len = cursor.read_u64()?;
+        let start = offset + cursor.position();
 
-        cursor
-            .seek(std::io::SeekFrom::Current(key_len as i64))
-            .expect("seek");
+        let start = match start.checked_add(key_len) {
+            Some(start) if start.saturating_add(value_len) <= offset + size => start,
+            _ => {
+                return Err(KeyValueStoreError::InvalidFormat(
+                    "value extends past the end of the record".to_string(),
+                ))
+            }
+        };
 
-        let mut value = vec![0u8; value_len as usize];
-        cursor.read_exact(&mut value).expect("read buf");
+        if !kind.is_blob() {
+            return Ok(ValueRef {
+                map,
+                timestamp,
+                start: start as usize,
+                len: value_len as usize,
+                checksum: None,
+            });
+        }
+
+        let blob = BlobRef::decode(&map[start as usize..(start + value_len) as usize])?;
+        let map = map_blob(blob.file)?;
 
-        let value = Value::from(timestamp, value);
+        if blob.offset + blob.len > map.len() as u64 {
+            return Err(KeyValueStoreError::InvalidFormat(
+                "value extends past the end of the blob file".to_string(),
+            ));
+        }
 
-        Ok(value)
+        Ok(ValueRef {
+            map,
+            timestamp,
+            start: blob.offset as usize,
+            len: blob.len as usize,
+            checksum: Some(blob.checksum),
+        })
     }
 }
 
-pub struct EntryReader {
-    file: BufReader<File>,
+pub struct EntryReader<R = BufReader<File>> {
+    file: R,
+    len: u64,
 }
 
 impl EntryReader {
-    pub fn new<P>(path: P) -> Self
+    pub fn open<P>(path: P) -> io::Result<Self>
     where
         P: AsRef<Path>,
     {
-        let path = path.as_ref();
-        let file = File::open(path).unwrap();
-        let mut file = BufReader::new(file);
+        let file = File::open(path.as_ref())?;
+        let len = file.metadata()?.len();
 
+        Self::from_reader(BufReader::new(file), len)
+    }
+}
+
+impl<R> EntryReader<R>
+where
+    R: BufRead + Seek,
+{
+    /// Reads the records of a data file of `len` bytes from `file`, which
+    /// starts at the beginning of the file, such as a cursor over a mapped
+    /// data file.
+    pub fn from_reader(mut file: R, len: u64) -> io::Result<Self> {
         let mut buf = [0u8; DATA_FILE_HEADER.len()];
-        file.read_exact(&mut buf).expect("read header");
-        assert_eq!(buf, DATA_FILE_HEADER);
+        file.read_exact(&mut buf)?;
 
-        Self { file }
-    }
+        if buf != DATA_FILE_HEADER {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a data file",
+            ));
+        }
 
-    pub fn next(&mut self) -> Option<Entry> {
-        let offset = self.file.stream_position().unwrap();
+        Ok(Self { file, len })
+    }
 
-        let mut buf = Vec::new();
-        let mut crc = [0u8; 4];
-        if let Err(_) = self.file.read_exact(&mut crc) {
-            return None;
+    /// Decodes the next record in the file, returning `None` at the end of
+    /// the file. Records with a bad checksum are returned as well; use
+    /// [`RawEntry::is_valid`] to check them.
+    pub fn next_record(&mut self) -> io::Result<Option<RawEntry>> {
+        if self.file.fill_buf()?.is_empty() {
+            return Ok(None);
         }
 
-        let cksum0 = u32::from_be_bytes(crc);
+        let offset = self.file.stream_position()?;
+        let checksum = self.file.read_u32()?;
+
+        let mut buf = Vec::new();
 
         let mut ts = [0u8; 8];
-        self.file.read_exact(&mut ts).expect("read u64");
-        let timestamp = u64::from_be_bytes(ts);
+        self.file.read_exact(&mut ts)?;
         buf.extend_from_slice(&ts);
 
-        let mut deleted = [0u8; 1];
-        self.file.read_exact(&mut deleted).expect("read u8");
-        buf.extend_from_slice(&deleted);
+        let mut flag = [0u8; 1];
+        self.file.read_exact(&mut flag)?;
+        buf.extend_from_slice(&flag);
 
         let mut key_len = [0u8; 8];
-        self.file.read_exact(&mut key_len).unwrap();
-        let k_len = u64::from_be_bytes(key_len);
+        self.file.read_exact(&mut key_len)?;
         buf.extend_from_slice(&key_len);
 
-        let mut value_len = [0u8; 8];
-        self.file.read_exact(&mut value_len).expect("read u64");
-        let v_len = u64::from_be_bytes(value_len);
-        buf.extend_from_slice(&value_len);
+        let kind = RecordKind::from_flag(flag[0]).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown record type {:#04x}", flag[0]),
+            )
+        })?;
+
+        // tombstones are written without a value length or value
+        let value_len = match kind.is_tombstone() {
+            true => 0,
+            false => {
+                let mut value_len = [0u8; 8];
+                self.file.read_exact(&mut value_len)?;
+                buf.extend_from_slice(&value_len);
+                u64::from_be_bytes(value_len)
+            }
+        };
 
-        let mut key = vec![0u8; k_len as usize];
-        self.file.read_exact(&mut key).expect("read key");
-        buf.extend_from_slice(&key);
+        let key_len = u64::from_be_bytes(key_len);
 
-        if deleted[0] == 0 {
-            let mut value = vec![0u8; v_len as usize];
-            self.file.read_exact(&mut value).expect("read value");
-            buf.extend_from_slice(&value);
+        // guard against allocating huge buffers for corrupted lengths
+        let end = self.file.stream_position()?;
+        if key_len.saturating_add(value_len) > self.len.saturating_sub(end) {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "record extends past the end of the file",
+            ));
         }
 
+        let mut key = vec![0u8; key_len as usize];
+        self.file.read_exact(&mut key)?;
+        buf.extend_from_slice(&key);
+
+        let mut value = vec![0u8; value_len as usize];
+        self.file.read_exact(&mut value)?;
+        buf.extend_from_slice(&value);
+
         let crc = crc_init();
         let mut digest = crc.digest();
         digest.update(&buf);
-        let cksum = digest.finalize();
+        let computed = digest.finalize();
 
-        if cksum != cksum0 {
-            return None;
-        }
+        Ok(Some(RawEntry {
+            offset,
+            size: (buf.len() + 4) as u64,
+            checksum,
+            computed,
+            timestamp: u64::from_be_bytes(ts),
+            kind,
+            key,
+            value_len,
+        }))
+    }
+}
+
+impl<R> Iterator for EntryReader<R>
+where
+    R: BufRead + Seek,
+{
+    type Item = Entry;
+
+    /// Returns the next record as an index entry, stopping at the first
+    /// record that can't be read or has a bad checksum.
+    fn next(&mut self) -> Option<Entry> {
+        let record = match self.next_record() {
+            Ok(Some(record)) if record.is_valid() => record,
+            _ => return None,
+        };
 
-        let size = match deleted[0] {
-            0 => (buf.len() + 4) as u64,
-            _ => 0,
+        let size = match record.kind.is_tombstone() {
+            false => record.size,
+            true => 0,
         };
 
-        let entry = Entry::new(key, timestamp, 0, offset, size);
+        let mut entry = Entry::new(record.key, record.timestamp, 0, record.offset, size);
+        entry.kind = record.kind;
 
         Some(entry)
     }
 }
 
+/// The type of a record in a data file, stored in its flag byte. Records
+/// with a flag of `0x80` or above are tombstones and have no value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    /// A value in the default namespace.
+    Value,
+    /// A deletion in the default namespace.
+    Tombstone,
+    /// A value in a namespace. The key starts with the namespace id.
+    NamespaceValue,
+    /// A deletion in a namespace. The key starts with the namespace id.
+    NamespaceTombstone,
+    /// The creation of a namespace. The key is its id, the value its name.
+    NamespaceCreate,
+    /// The drop of a namespace and everything in it. The key is its id.
+    NamespaceDrop,
+    /// A value in the default namespace kept in a blob file. The value of
+    /// the record locates it there.
+    Blob,
+    /// A value in a namespace kept in a blob file. The key starts with the
+    /// namespace id.
+    NamespaceBlob,
+}
+
+impl RecordKind {
+    pub(crate) fn from_flag(flag: u8) -> Option<Self> {
+        match flag {
+            0x00 => Some(Self::Value),
+            0xFF => Some(Self::Tombstone),
+            0x01 => Some(Self::NamespaceValue),
+            0xFE => Some(Self::NamespaceTombstone),
+            0x02 => Some(Self::NamespaceCreate),
+            0xFD => Some(Self::NamespaceDrop),
+            0x03 => Some(Self::Blob),
+            0x04 => Some(Self::NamespaceBlob),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn flag(self) -> u8 {
+        match self {
+            Self::Value => 0x00,
+            Self::Tombstone => 0xFF,
+            Self::NamespaceValue => 0x01,
+            Self::NamespaceTombstone => 0xFE,
+            Self::NamespaceCreate => 0x02,
+            Self::NamespaceDrop => 0xFD,
+            Self::Blob => 0x03,
+            Self::NamespaceBlob => 0x04,
+        }
+    }
+
+    pub fn is_tombstone(self) -> bool {
+        self.flag() >= 0x80
+    }
+
+    /// Whether the value of the record is kept in a blob file.
+    pub fn is_blob(self) -> bool {
+        matches!(self, Self::Blob | Self::NamespaceBlob)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Value => "value",
+            Self::Tombstone => "tombstone",
+            Self::NamespaceValue => "ns-value",
+            Self::NamespaceTombstone => "ns-delete",
+            Self::NamespaceCreate => "ns-create",
+            Self::NamespaceDrop => "ns-drop",
+            Self::Blob => "blob",
+            Self::NamespaceBlob => "ns-blob",
+        }
+    }
+}
+
+/// A record decoded from a data file as it is stored on disk.
+#[derive(Debug)]
+pub struct RawEntry {
+    offset: u64,
+    size: u64,
+    checksum: u32,
+    computed: u32,
+    timestamp: u64,
+    kind: RecordKind,
+    key: Vec<u8>,
+    value_len: u64,
+}
+
+impl RawEntry {
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Size of the record on disk, including the checksum.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The checksum stored in the record.
+    pub fn checksum(&self) -> u32 {
+        self.checksum
+    }
+
+    /// The checksum computed from the record's contents.
+    pub fn computed_checksum(&self) -> u32 {
+        self.computed
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.checksum == self.computed
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn is_tombstone(&self) -> bool {
+        self.kind.is_tombstone()
+    }
+
+    pub fn kind(&self) -> RecordKind {
+        self.kind
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    pub fn value_len(&self) -> u64 {
+        self.value_len
+    }
+}
+
 pub struct IndexReader {
     file: BufReader<File>,
+    version: u32,
+    /// The key length of the first entry of a version 1 index file, which
+    /// had to be read to find out the version.
+    first_key_len: Option<u64>,
+    /// The bytes left in the file, which no length read from it can exceed.
+    remaining: u64,
 }
 
 impl IndexReader {
-    pub fn new<P>(path: P) -> IndexReader
+    /// Opens the index file at `path`. Fails with
+    /// [`KeyValueStoreError::InvalidFormat`] if it doesn't start with the
+    /// index file header.
+    pub fn open<P>(path: P) -> Result<IndexReader, KeyValueStoreError>
     where
         P: AsRef<Path>,
     {
-        let path = path.as_ref();
-        let file = File::open(path).unwrap();
+        let file = File::open(path.as_ref())?;
+        let len = file.metadata()?.len();
         let mut file = BufReader::new(file);
 
         let mut buf = [0u8; INDEX_FILE_HEADER.len()];
-        file.read_exact(&mut buf).expect("read exact");
-        assert_eq!(buf, INDEX_FILE_HEADER);
+        file.read_exact(&mut buf).map_err(truncated)?;
+
+        if buf != INDEX_FILE_HEADER {
+            return Err(KeyValueStoreError::InvalidFormat(
+                "not an index file".to_string(),
+            ));
+        }
+
+        let mut reader = Self {
+            file,
+            version: 1,
+            first_key_len: None,
+            remaining: len.saturating_sub(buf.len() as u64),
+        };
+
+        // version 1 files start right away with the first entry, later
+        // versions with a key length no key can have, then the version
+        match reader.at_end()? {
+            true => {}
+            false => match reader.read_u64()? {
+                INDEX_FORMAT_MARKER => reader.version = reader.read_u32()?,
+                key_len => reader.first_key_len = Some(key_len),
+            },
+        }
+
+        Ok(reader)
+    }
 
-        Self { file }
+    pub fn version(&self) -> u32 {
+        self.version
     }
 
-    pub fn next(&mut self) -> Option<Entry> {
-        let key_len = match self.file.read_u64() {
-            Ok(len) => len,
-            Err(_) => return None,
+    pub fn at_end(&mut self) -> Result<bool, KeyValueStoreError> {
+        Ok(self.file.fill_buf()?.is_empty())
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, KeyValueStoreError> {
+        let mut buf = [0u8; 1];
+        self.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, KeyValueStoreError> {
+        let mut buf = [0u8; 4];
+        self.read_exact(&mut buf)?;
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, KeyValueStoreError> {
+        let mut buf = [0u8; 8];
+        self.read_exact(&mut buf)?;
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    /// Reads a length as a `u64` followed by that many bytes.
+    pub fn read_bytes(&mut self) -> Result<Vec<u8>, KeyValueStoreError> {
+        let len = self.read_u64()?;
+        self.read_vec(len)
+    }
+
+    /// Reads the next entry of a version 1 file, or `None` at its end.
+    pub fn next(&mut self) -> Result<Option<Entry>, KeyValueStoreError> {
+        match self.first_key_len.is_none() && self.at_end()? {
+            true => Ok(None),
+            false => self.read_entry().map(Some),
+        }
+    }
+
+    /// Reads an entry: its key, timestamp, file, offset and size.
+    pub fn read_entry(&mut self) -> Result<Entry, KeyValueStoreError> {
+        let key_len = match self.first_key_len.take() {
+            Some(len) => len,
+            None => self.read_u64()?,
         };
 
-        let mut key = vec![0u8; key_len as usize];
-        self.file.read_exact(&mut key).expect("read exact");
+        let key = self.read_vec(key_len)?;
+        let timestamp = self.read_u64()?;
+        let file = self.read_u64()?;
+        let offset = self.read_u64()?;
+        let size = self.read_u64()?;
 
-        let timestamp = self.file.read_u64().expect("read u64");
-        let file = self.file.read_u64().expect("read u64");
-        let offset = self.file.read_u64().expect("read u64");
-        let size = self.file.read_u64().expect("read u64");
+        Ok(Entry::new(key, timestamp, file, offset, size))
+    }
 
-        let entry = Entry::new(key, timestamp, file, offset, size);
+    /// Reads `len` bytes, failing before allocating them if the file is
+    /// shorter than that.
+    fn read_vec(&mut self, len: u64) -> Result<Vec<u8>, KeyValueStoreError> {
+        if len > self.remaining {
+            return Err(truncated(io::ErrorKind::UnexpectedEof.into()));
+        }
 
-        Some(entry)
+        let mut buf = vec![0u8; len as usize];
+        self.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), KeyValueStoreError> {
+        self.file.read_exact(buf).map_err(truncated)?;
+        self.remaining = self.remaining.saturating_sub(buf.len() as u64);
+        Ok(())
+    }
+}
+
+/// Reports an index file that ends in the middle of a value as an invalid
+/// one, and other read errors as they are.
+fn truncated(e: io::Error) -> KeyValueStoreError {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => {
+            KeyValueStoreError::InvalidFormat("index file ends early".to_string())
+        }
+        _ => e.into(),
     }
 }
 
@@ -213,6 +632,7 @@ pub struct Entry {
     timestamp: u64,
     offset: u64,
     size: u64,
+    kind: RecordKind,
 }
 
 impl Entry {
@@ -223,9 +643,18 @@ impl Entry {
             file,
             offset,
             size,
+            kind: RecordKind::Value,
         }
     }
 
+    pub fn is_deleted(&self) -> bool {
+        self.kind.is_tombstone()
+    }
+
+    pub fn kind(&self) -> RecordKind {
+        self.kind
+    }
+
     pub fn key(&self) -> &[u8] {
         &self.key
     }
@@ -266,3 +695,68 @@ impl Value {
         &self.data
     }
 }
+
+/// A value read in place from a mapped data or blob file, without copying
+/// it. The file stays mapped until every `ValueRef` into it is dropped, so
+/// the value stays valid even if a merge removes the file in the meantime.
+#[derive(Clone)]
+pub struct ValueRef {
+    map: Arc<Mmap>,
+    timestamp: u64,
+    start: usize,
+    len: usize,
+    /// The checksum of values kept in blob files, which is not covered by
+    /// the checksum of their record.
+    checksum: Option<u32>,
+}
+
+impl ValueRef {
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.map[self.start..self.start + self.len]
+    }
+
+    /// Copies the value into an owned [`Value`].
+    pub fn to_value(&self) -> Value {
+        Value::from(self.timestamp, self.data().to_vec())
+    }
+
+    /// Checks the checksum of a value kept in a blob file.
+    pub(crate) fn check(&self) -> Result<(), KeyValueStoreError> {
+        let checksum = match self.checksum {
+            Some(checksum) => checksum,
+            None => return Ok(()),
+        };
+
+        match crc_init().checksum(self.data()) == checksum {
+            true => Ok(()),
+            false => Err(KeyValueStoreError::ChecksumFailed),
+        }
+    }
+}
+
+impl Deref for ValueRef {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.data()
+    }
+}
+
+impl AsRef<[u8]> for ValueRef {
+    fn as_ref(&self) -> &[u8] {
+        self.data()
+    }
+}
+
+impl std::fmt::Debug for ValueRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ValueRef")
+            .field("timestamp", &self.timestamp)
+            .field("len", &self.len)
+            .finish()
+    }
+}
@@ -0,0 +1,223 @@
+use super::error::{KeyValueStoreError, Result};
+use super::index::Index;
+use super::iter::{FileCache, IndexRange, Keys, RangeIter};
+use super::Bitcask;
+use std::collections::btree_map::Range;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::RangeBounds;
+
+/// Computes the keys a secondary index lists an entry under from the entry's
+/// key and value. An entry can be listed under any number of keys.
+pub type IndexFn = fn(&[u8], &[u8]) -> Vec<Vec<u8>>;
+
+/// The secondary indexes of the default namespace.
+///
+/// Index functions can't be stored, so the indexes must be registered again
+/// every time the store is opened. Until then the entries read from the index
+/// file are kept aside and adopted when the index is registered. A write made
+/// before that drops them, since they could no longer be kept up to date, and
+/// registering the index then builds it from scratch.
+pub struct SecondaryIndexes {
+    indexes: BTreeMap<String, SecondaryIndex>,
+}
+
+/// Maps every index key to the primary keys of the entries listed under it.
+pub struct SecondaryIndex {
+    /// `None` for entries read from the index file that are not registered.
+    f: Option<IndexFn>,
+    map: BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>>,
+}
+
+impl SecondaryIndexes {
+    pub fn new() -> Self {
+        Self {
+            indexes: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indexes.is_empty()
+    }
+
+    /// Returns the registered index `name`.
+    pub fn get(&self, name: &str) -> Option<&SecondaryIndex> {
+        self.indexes.get(name).filter(|index| index.f.is_some())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &SecondaryIndex)> {
+        self.indexes.iter()
+    }
+
+    /// Registers `f` for the entries of `name` read from the index file,
+    /// returning false if there are none and the index must be built.
+    pub fn adopt(&mut self, name: &str, f: IndexFn) -> bool {
+        match self.indexes.get_mut(name) {
+            Some(index) if index.f.is_none() => {
+                index.f = Some(f);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn insert(&mut self, name: &str, index: SecondaryIndex) {
+        self.indexes.insert(name.to_string(), index);
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<SecondaryIndex> {
+        self.indexes.remove(name)
+    }
+
+    /// Drops the entries read from the index file for indexes that are not
+    /// registered.
+    pub fn forget_unregistered(&mut self) {
+        self.indexes.retain(|_, index| index.f.is_some());
+    }
+
+    /// Moves `key` from the index keys of its old value to those of its new
+    /// value in every index, where a missing value means the key does not
+    /// exist.
+    pub fn update(&mut self, key: &[u8], old: Option<&[u8]>, new: Option<&[u8]>) {
+        for index in self.indexes.values_mut() {
+            index.update(key, old, new);
+        }
+    }
+}
+
+impl SecondaryIndex {
+    pub fn new(f: IndexFn) -> Self {
+        Self {
+            f: Some(f),
+            map: BTreeMap::new(),
+        }
+    }
+
+    /// Creates an index from entries read from the index file.
+    pub fn loaded(map: BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>>) -> Self {
+        Self { f: None, map }
+    }
+
+    pub fn map(&self) -> &BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>> {
+        &self.map
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) {
+        self.update(key, None, Some(value));
+    }
+
+    /// The index keys in `range` with the primary keys listed under them.
+    pub fn range<R>(&self, range: R) -> Range<'_, Vec<u8>, BTreeSet<Vec<u8>>>
+    where
+        R: RangeBounds<[u8]>,
+    {
+        self.map.range::<[u8], _>(range)
+    }
+
+    fn update(&mut self, key: &[u8], old: Option<&[u8]>, new: Option<&[u8]>) {
+        let f = self.f.expect("registered index");
+
+        if let Some(old) = old {
+            for index_key in f(key, old) {
+                if let Some(keys) = self.map.get_mut(&index_key) {
+                    keys.remove(key);
+                    if keys.is_empty() {
+                        self.map.remove(&index_key);
+                    }
+                }
+            }
+        }
+
+        if let Some(new) = new {
+            for index_key in f(key, new) {
+                self.map.entry(index_key).or_default().insert(key.to_vec());
+            }
+        }
+    }
+}
+
+impl Bitcask {
+    /// Registers the secondary index `name`, listing every entry of the
+    /// default namespace under the keys `f` computes from it. The index is
+    /// updated together with the store on every write, so queries always
+    /// agree with the data.
+    ///
+    /// Indexes are kept in the index file, but `f` is not, so indexes must be
+    /// registered again every time the store is opened. If the store was not
+    /// written to in between, the stored index is used as it is, otherwise it
+    /// is built again from every entry. Register an index under a new name
+    /// when its function changes.
+    pub fn register_index(&mut self, name: &str, f: IndexFn) -> Result<()> {
+        if self.secondary.adopt(name, f) {
+            return Ok(());
+        }
+
+        let mut index = SecondaryIndex::new(f);
+
+        for item in self.scan_all() {
+            let (k, v) = item?;
+            index.insert(&k, v.data());
+        }
+
+        self.secondary.insert(name, index);
+
+        Ok(())
+    }
+
+    /// Removes the secondary index `name`, returning whether it existed.
+    pub fn drop_index(&mut self, name: &str) -> bool {
+        self.secondary.remove(name).is_some()
+    }
+
+    /// Iterates over the entries listed under the index keys in `range` of
+    /// the secondary index `name`, as `(primary key, value)` pairs ordered by
+    /// index key and then primary key. An entry listed under several index
+    /// keys in `range` is returned once for each of them. The entries are
+    /// looked up before the first one is returned, so this fails if the key
+    /// of one can't be read back for an [`IndexKind::HashedKeys`] index.
+    ///
+    /// [`IndexKind::HashedKeys`]: crate::IndexKind::HashedKeys
+    pub fn query_index<R>(&self, name: &str, range: R) -> Result<RangeIter<'_>>
+    where
+        R: RangeBounds<[u8]>,
+    {
+        let index = self.registered_index(name)?;
+        let entries = listed_entries(index.range(range), &self.index)?;
+
+        Ok(RangeIter::new(FileCache::new(self.root()), entries))
+    }
+
+    /// Like [`Bitcask::query_index`], but returns only the primary keys
+    /// without reading any data files.
+    pub fn query_index_keys<R>(&self, name: &str, range: R) -> Result<Keys<'_>>
+    where
+        R: RangeBounds<[u8]>,
+    {
+        let index = self.registered_index(name)?;
+        let entries = listed_entries(index.range(range), &self.index)?;
+
+        Ok(Keys::new(entries))
+    }
+
+    fn registered_index(&self, name: &str) -> Result<&SecondaryIndex> {
+        self.secondary
+            .get(name)
+            .ok_or_else(|| KeyValueStoreError::UnknownIndex(name.to_string()))
+    }
+}
+
+/// Looks up the entries of the primary keys listed under the index keys of
+/// `range` in `primary`, ordered by index key and then primary key.
+fn listed_entries<'a>(
+    range: Range<'a, Vec<u8>, BTreeSet<Vec<u8>>>,
+    primary: &'a Index,
+) -> Result<IndexRange<'a>> {
+    let mut entries = Vec::new();
+
+    for key in range.flat_map(|(_, keys)| keys) {
+        if let Some(entry) = primary.get(key)? {
+            entries.push((key.as_slice(), entry));
+        }
+    }
+
+    Ok(Box::new(entries.into_iter()))
+}
@@ -1,5 +1,7 @@
 use crate::bitcask::util::Size;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Debug, Clone)]
 pub struct Options {
@@ -10,8 +12,43 @@ pub struct Options {
     pub max_key_size: Option<u64>,
     pub max_value_size: Option<u64>,
 
+    /// The share of a sealed data file that must be overwritten or deleted
+    /// records before [`Bitcask::merge`](crate::Bitcask::merge) rewrites it.
     pub bytes_deleted_ratio: f64,
     pub entries_deleted_ratio: f64,
+
+    /// When writes are forced to disk, see [`SyncPolicy`].
+    pub sync: SyncPolicy,
+
+    /// How the in-memory index is kept, see [`IndexKind`].
+    pub index: IndexKind,
+
+    /// Opens the store without creating or modifying any files. Writes
+    /// return [`KeyValueStoreError::ReadOnly`](crate::KeyValueStoreError).
+    pub read_only: bool,
+
+    /// Values larger than this are kept in blob files, with only their
+    /// location in the data files, so merges don't have to copy them. With
+    /// `None`, only values too large for a data file are.
+    pub blob_threshold: Option<u64>,
+    /// Blob files are sealed and a new one started once they reach this
+    /// size.
+    pub blob_file_size_limit: u64,
+    /// The share of a blob file that must be overwritten or deleted values
+    /// before [`Bitcask::merge_blobs`](crate::Bitcask::merge_blobs) rewrites
+    /// it.
+    pub blob_garbage_ratio: f64,
+
+    /// Bytes per second that merges, verification and checkpoints running
+    /// alongside reads and writes may read and write together, so they don't
+    /// take the disk bandwidth reads and writes need. Only those of
+    /// [`SharedBitcask`](crate::SharedBitcask), which don't hold the store
+    /// while they copy or check files, are limited; the ones of
+    /// [`Bitcask`](crate::Bitcask) hold it throughout and are never slowed
+    /// down. Can be changed while the store is open with
+    /// [`Bitcask::set_io_rate_limit`](crate::Bitcask::set_io_rate_limit).
+    /// `None` doesn't limit them.
+    pub io_rate_limit: Option<u64>,
 }
 
 impl Options {
@@ -27,16 +64,107 @@ impl Options {
             max_value_size: None,
             bytes_deleted_ratio: 0.5,
             entries_deleted_ratio: 0.5,
+            sync: SyncPolicy::Never,
+            index: IndexKind::BTree,
+            read_only: false,
+            blob_threshold: None,
+            blob_file_size_limit: Size::MB(256),
+            blob_garbage_ratio: 0.5,
+            io_rate_limit: None,
         }
     }
 }
 
-pub struct Metrics {
+/// Whether a commit forces the written data to disk before returning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Writes are flushed to the operating system, which writes them to disk
+    /// later. A crash of the machine can lose recent writes.
+    Never,
+    /// Every commit calls `fsync` before returning, so committed writes
+    /// survive a crash of the machine.
+    Always,
+}
+
+/// The representation of the in-memory index. A store can be opened with any
+/// of them, whichever kind wrote its index file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexKind {
+    /// A `BTreeMap` from every key to its entry. Fast for reads and writes,
+    /// but takes around 100 bytes per key on top of the key itself.
+    BTree,
+    /// Keys packed into a sorted buffer with 16 byte entries, taking around
+    /// 20 bytes per key on top of the key itself. Reads are a little slower
+    /// and writes are buffered and merged into the buffer in batches, which
+    /// makes them slower for stores with many keys.
+    Compact,
+    /// A `HashMap` from every key to its entry, for stores that are only
+    /// read by key. Lookups and writes take constant time, but keys are not
+    /// kept in order, so range and prefix scans and cursors fail with
+    /// [`KeyValueStoreError::Unsupported`](crate::KeyValueStoreError).
+    Hash,
+    /// A `HashMap` from a 64-bit hash of every key to its entry, taking
+    /// around 90 bytes per key whatever the length of the keys, for stores
+    /// with long keys. Lookups read the key back from the data file to
+    /// check it, so they cost a disk read when the file is not cached. Like
+    /// [`IndexKind::Hash`], it keeps no order and doesn't support scans.
+    HashedKeys,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    /// Number of keys in all namespaces.
+    pub num_keys: u64,
+    pub num_files: u64,
+    /// Total size of all data files on disk.
+    pub data_size: u64,
+    /// Size of the records the indexes of all namespaces point to.
+    pub live_size: u64,
+    /// Entries overwritten or deleted since the store was opened, left in
+    /// data files that were not merged since.
+    pub num_entries_deleted: u64,
+    /// Bytes overwritten or deleted since the store was opened, left in data
+    /// files that were not merged since.
     pub num_bytes_deleted: u64,
+    pub num_blob_files: u64,
+    /// Total size of all blob files on disk.
+    pub blob_size: u64,
+    /// Bytes of values in blob files overwritten or deleted since the store
+    /// was opened. It is not kept on disk and starts again from zero when the
+    /// store is reopened, but
+    /// [`Bitcask::merge_blobs`](crate::Bitcask::merge_blobs) finds the
+    /// garbage from the indexes, so it reclaims older garbage as well.
+    pub blob_bytes_deleted: u64,
+    /// Bytes read and written by background merges, verification and
+    /// checkpoints since the store was opened, which
+    /// [`Options::io_rate_limit`] applies to.
+    pub rate_limited_bytes: u64,
+    /// How many of their reads and writes waited for the rate limit.
+    pub rate_limit_waits: u64,
+    /// The total time they waited for the rate limit.
+    pub rate_limit_wait_time: Duration,
+}
+
+/// Statistics of a single [`Namespace`](crate::Namespace).
+#[derive(Debug, Clone, Default)]
+pub struct NamespaceStats {
+    pub num_keys: u64,
+    /// Size of the records the namespace's index points to.
+    pub live_size: u64,
+    /// Entries overwritten or deleted since the store was opened, left in
+    /// data files that were not merged since.
     pub num_entries_deleted: u64,
+    /// Bytes overwritten or deleted since the store was opened, left in data
+    /// files that were not merged since.
+    pub num_bytes_deleted: u64,
+}
 
-    pub bytes_deleted_limit: u64,
-    pub entries_deleted_ratio: f64,
+pub struct Metrics {
+    pub num_bytes_deleted: u64,
+    pub num_entries_deleted: u64,
+    /// The entries and bytes deleted from each data file, which are taken
+    /// off the totals when a merge rewrites the file.
+    files: HashMap<u64, (u64, u64)>,
 }
 
 impl Metrics {
@@ -44,9 +172,29 @@ impl Metrics {
         Self {
             num_entries_deleted: 0,
             num_bytes_deleted: 0,
+            files: HashMap::new(),
+        }
+    }
 
-            bytes_deleted_limit: 81920,
-            entries_deleted_ratio: 0.5,
+    /// Counts `entries` records taking `bytes` bytes in the data file `file`
+    /// as deleted.
+    pub fn count_deleted(&mut self, file: u64, entries: u64, bytes: u64) {
+        let deleted = self.files.entry(file).or_default();
+        deleted.0 += entries;
+        deleted.1 += bytes;
+
+        self.num_entries_deleted += entries;
+        self.num_bytes_deleted += bytes;
+    }
+
+    /// Stops counting the records deleted from the data files `ids`, which
+    /// a merge rewrote without them.
+    pub fn forget_files(&mut self, ids: &[u64]) {
+        for id in ids {
+            if let Some((entries, bytes)) = self.files.remove(id) {
+                self.num_entries_deleted -= entries;
+                self.num_bytes_deleted -= bytes;
+            }
         }
     }
 }
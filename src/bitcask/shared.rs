@@ -0,0 +1,474 @@
+use super::batch::{Op, WriteBatch};
+use super::error::{KeyValueStoreError, Result};
+use super::merge::MergeHandle;
+use super::reader::{Value, ValueRef};
+use super::settings::{Options, Stats, SyncPolicy};
+use super::snapshot::Snapshot;
+use super::verify::VerifyReport;
+use super::Bitcask;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// A handle to a store that can be shared between threads. Cloning the
+/// handle is cheap and every clone refers to the same store.
+///
+/// Writes use group commit: concurrent writers queue their records, and one
+/// of them becomes the leader, appending every queued record in one buffered
+/// write and flushing, and syncing if the [`SyncPolicy`] asks for it, once
+/// for all of them. The leader then updates the index and wakes the others.
+/// Under contention, many writes share a single `fsync`.
+///
+/// If a thread panics while it holds the store, for example in the middle of
+/// a commit, the store may be left half updated, so every later call on any
+/// clone of the handle fails with [`KeyValueStoreError::Panicked`] instead of
+/// reading or writing it. Reopening the store from disk recovers it.
+#[derive(Clone)]
+pub struct SharedBitcask {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    kv: RwLock<Bitcask>,
+    queue: Mutex<Queue>,
+    committed: Condvar,
+    sync: bool,
+}
+
+#[derive(Default)]
+struct Queue {
+    next_seq: u64,
+    pending: Vec<(u64, Op)>,
+    /// Whether a leader is currently committing.
+    leader: bool,
+    /// Results of committed writes not yet picked up by their writer.
+    done: HashMap<u64, Result<bool>>,
+}
+
+impl SharedBitcask {
+    pub fn new(kv: Bitcask) -> Self {
+        let sync = kv.opts.sync == SyncPolicy::Always;
+
+        let inner = Inner {
+            kv: RwLock::new(kv),
+            queue: Mutex::new(Queue::default()),
+            committed: Condvar::new(),
+            sync,
+        };
+
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+
+    pub fn open(opts: Options) -> Result<Self> {
+        Ok(Self::new(Bitcask::open(opts)?))
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Value>> {
+        self.read()?.get(key)
+    }
+
+    /// Like [`SharedBitcask::get`], but returns the value in place in the
+    /// mapped data file instead of copying it. The value stays valid while
+    /// other threads write and merge.
+    pub fn get_ref(&self, key: &[u8]) -> Result<Option<ValueRef>> {
+        self.read()?.get_ref(key)
+    }
+
+    /// Calls `f` with the value of `key` read in place, returning what it
+    /// returns, or `None` if the key does not exist. `f` runs without
+    /// holding up writers or merges.
+    pub fn with_value<F, T>(&self, key: &[u8], f: F) -> Result<Option<T>>
+    where
+        F: FnOnce(&[u8]) -> T,
+    {
+        Ok(self.get_ref(key)?.map(|value| f(&value)))
+    }
+
+    pub fn exists(&self, key: &[u8]) -> Result<bool> {
+        self.read()?.exists(key)
+    }
+
+    /// Sets `key` to `value`. Like [`Bitcask::insert`], nothing is written if
+    /// the key already has this value.
+    pub fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        {
+            let kv = self.read()?;
+
+            if !kv.opts.read_only && kv.get(key)?.is_some_and(|v| v.data() == value) {
+                return Ok(());
+            }
+        }
+
+        let mut batch = WriteBatch::new();
+        batch.put(key, value);
+
+        self.submit(batch).map(|_| ())
+    }
+
+    /// Deletes `key`, returning whether it existed. Unlike
+    /// [`Bitcask::delete`], this fails if the deletion could not be written.
+    pub fn delete(&self, key: &[u8]) -> Result<bool> {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+
+        self.submit(batch)
+    }
+
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        self.read()?.snapshot()
+    }
+
+    /// Writes a consistent copy of the store to `dest`, see
+    /// [`Bitcask::checkpoint`]. The store is only held while the active data
+    /// file is sealed and the index is written; the files are linked or
+    /// copied afterwards while reads and writes go on.
+    pub fn checkpoint<P>(&self, dest: P) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        self.checkpoint_to(dest.as_ref(), false)
+    }
+
+    /// Brings a checkpoint previously written to `dest` up to date, see
+    /// [`Bitcask::checkpoint_incremental`], holding the store no longer than
+    /// [`SharedBitcask::checkpoint`].
+    pub fn checkpoint_incremental<P>(&self, dest: P) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        self.checkpoint_to(dest.as_ref(), true)
+    }
+
+    fn checkpoint_to(&self, dest: &Path, incremental: bool) -> Result<()> {
+        let (checkpoint, limiter) = {
+            let mut kv = self.write()?;
+            let checkpoint = kv.prepare_checkpoint(dest, incremental)?;
+            (checkpoint, kv.limiter.clone())
+        };
+
+        checkpoint.write(&limiter)?;
+
+        Ok(())
+    }
+
+    /// The statistics of the store, which are still reported after a
+    /// thread panicked while holding it.
+    pub fn stats(&self) -> Stats {
+        self.inner
+            .kv
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .stats()
+    }
+
+    /// Verifies the store like [`Bitcask::verify`], throttled by
+    /// [`Options::io_rate_limit`]. The store is only held while a snapshot
+    /// is taken, so reads and writes go on while the files are checked, and
+    /// records written afterwards are not checked. Fails if a file can't be
+    /// mapped for the snapshot. Like any snapshot, it makes the first write
+    /// to each namespace during the check copy that namespace's index, see
+    /// [`Snapshot`].
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let (snapshot, limiter) = {
+            let kv = self.read()?;
+            (kv.snapshot()?, kv.limiter.clone())
+        };
+
+        Ok(snapshot.verify(&limiter))
+    }
+
+    /// Merges the data files, see [`Bitcask::merge`], waiting until the merge
+    /// finishes. Readers and writers carry on while it runs. Fails like
+    /// [`SharedBitcask::merge_in_background`] and [`MergeHandle::wait`].
+    pub fn merge(&self) -> Result<()> {
+        match self.merge_in_background()? {
+            Some(handle) => handle.wait(),
+            None => Ok(()),
+        }
+    }
+
+    /// Starts merging the data files on a background thread and returns a
+    /// handle to follow or cancel it, or `None` if no file is worth merging.
+    ///
+    /// The store is only held while the files are picked and while the index
+    /// is updated at the end. In between, the merge copies records out of the
+    /// sealed files while reads and writes go on. Index entries written while
+    /// it runs keep their new location. Fails with
+    /// [`KeyValueStoreError::MergeRunning`] if a merge is already running.
+    ///
+    /// [`KeyValueStoreError::MergeRunning`]: crate::KeyValueStoreError::MergeRunning
+    pub fn merge_in_background(&self) -> Result<Option<MergeHandle>> {
+        let plan = match self.write()?.plan_merge()? {
+            Some(plan) => plan,
+            None => return Ok(None),
+        };
+
+        Ok(Some(plan.spawn(self.inner.clone(), |inner| &inner.kv)))
+    }
+
+    /// Rewrites the blob files that are mostly garbage, see
+    /// [`Bitcask::merge_blobs`]. Readers and writers wait until it finishes.
+    pub fn merge_blobs(&self) -> Result<usize> {
+        self.write()?.merge_blobs()
+    }
+
+    /// Changes the rate limit of merges and other background I/O, see
+    /// [`Bitcask::set_io_rate_limit`]. Also applies to a background merge
+    /// that is running.
+    pub fn set_io_rate_limit(&self, limit: Option<u64>) {
+        self.inner
+            .kv
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .set_io_rate_limit(limit);
+    }
+
+    /// Holds the store for reading. Fails with
+    /// [`KeyValueStoreError::Panicked`] if a thread panicked while holding
+    /// it.
+    pub(crate) fn read(&self) -> Result<RwLockReadGuard<'_, Bitcask>> {
+        self.inner
+            .kv
+            .read()
+            .map_err(|_| KeyValueStoreError::Panicked)
+    }
+
+    /// Holds the store for writing, failing like [`SharedBitcask::read`].
+    fn write(&self) -> Result<RwLockWriteGuard<'_, Bitcask>> {
+        self.inner
+            .kv
+            .write()
+            .map_err(|_| KeyValueStoreError::Panicked)
+    }
+
+    /// Queues the single write in `batch` and waits until it is committed,
+    /// committing the queue itself if no other writer is.
+    fn submit(&self, batch: WriteBatch) -> Result<bool> {
+        let inner = &*self.inner;
+        let mut queue = inner.queue.lock().expect("queue lock");
+
+        let seq = queue.next_seq;
+        queue.next_seq += 1;
+        queue
+            .pending
+            .extend(batch.ops.into_iter().map(|op| (seq, op)));
+
+        loop {
+            if let Some(result) = queue.done.remove(&seq) {
+                return result;
+            }
+
+            if queue.leader {
+                queue = inner.committed.wait(queue).expect("queue lock");
+                continue;
+            }
+
+            queue.leader = true;
+            let (seqs, ops): (Vec<_>, Vec<_>) =
+                std::mem::take(&mut queue.pending).into_iter().unzip();
+            drop(queue);
+
+            let mut leader = Leader { inner, seqs };
+            let result = self.write().and_then(|mut kv| kv.commit(ops, inner.sync));
+
+            queue = inner.queue.lock().expect("queue lock");
+            for (i, seq) in std::mem::take(&mut leader.seqs).into_iter().enumerate() {
+                let result = result
+                    .as_ref()
+                    .map(|changed| changed[i])
+                    .map_err(Clone::clone);
+                queue.done.insert(seq, result);
+            }
+
+            queue.leader = false;
+            inner.committed.notify_all();
+        }
+    }
+}
+
+/// The writer committing the queue. If the commit panics, the writes it took
+/// from the queue fail with [`KeyValueStoreError::Panicked`] and the other
+/// writers are woken, so that one of them takes over instead of all of them
+/// waiting forever. The store is poisoned by then, so its commit fails the
+/// same way.
+struct Leader<'a> {
+    inner: &'a Inner,
+    seqs: Vec<u64>,
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            return;
+        }
+
+        let mut queue = self
+            .inner
+            .queue
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        for seq in self.seqs.drain(..) {
+            queue.done.insert(seq, Err(KeyValueStoreError::Panicked));
+        }
+
+        queue.leader = false;
+        self.inner.committed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn store(name: &str) -> Options {
+        let root = std::env::temp_dir().join(format!("kvs-shared-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        Options::new(root)
+    }
+
+    fn wait_until<F>(mut done: F)
+    where
+        F: FnMut() -> bool,
+    {
+        let start = Instant::now();
+
+        while !done() {
+            assert!(start.elapsed() < Duration::from_secs(10), "timed out");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn concurrent_inserts() {
+        let opts = store("inserts");
+        let kv = SharedBitcask::open(opts.clone()).unwrap();
+
+        let writers: Vec<_> = (0..8u8)
+            .map(|t| {
+                let kv = kv.clone();
+                thread::spawn(move || {
+                    for i in 0..50u8 {
+                        kv.insert(&[t, i], &[i, t]).unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        drop(kv);
+        let kv = Bitcask::open(opts).unwrap();
+
+        for t in 0..8u8 {
+            for i in 0..50u8 {
+                assert_eq!(kv.get(&[t, i]).unwrap().unwrap().data(), [i, t]);
+            }
+        }
+    }
+
+    #[test]
+    fn queued_writes_share_a_commit() {
+        let opts = store("group");
+        let kv = SharedBitcask::open(opts.clone()).unwrap();
+
+        for key in 0..4u8 {
+            kv.insert(&[key], b"value").unwrap();
+        }
+
+        // the first writer takes its write and waits for the store as the
+        // leader, and the others queue behind it
+        let held = kv.inner.kv.write().unwrap();
+
+        let writers: Vec<_> = (0..8u8)
+            .map(|key| {
+                let kv = kv.clone();
+                thread::spawn(move || (key, kv.delete(&[key])))
+            })
+            .collect();
+
+        wait_until(|| {
+            let queue = kv.inner.queue.lock().unwrap();
+            queue.leader && queue.pending.len() == 7
+        });
+
+        drop(held);
+
+        for writer in writers {
+            let (key, result) = writer.join().unwrap();
+            assert_eq!(result.unwrap(), key < 4, "key {}", key);
+        }
+
+        {
+            let queue = kv.inner.queue.lock().unwrap();
+            assert!(!queue.leader && queue.pending.is_empty() && queue.done.is_empty());
+        }
+
+        drop(kv);
+        let kv = Bitcask::open(opts).unwrap();
+
+        for key in 0..8u8 {
+            assert!(!kv.exists(&[key]).unwrap());
+        }
+    }
+
+    #[test]
+    fn panic_poisons_the_handle() {
+        let opts = store("panic");
+        let kv = SharedBitcask::open(opts.clone()).unwrap();
+        kv.insert(b"a", b"1").unwrap();
+
+        // a thread holds the store until told to panic, while a leader and a
+        // queued writer wait for it
+        let (panic, told) = mpsc::channel::<()>();
+        let inner = kv.inner.clone();
+        let holder = thread::spawn(move || {
+            let _held = inner.kv.write().unwrap();
+            told.recv().unwrap();
+            panic!("while holding the store");
+        });
+
+        wait_until(|| kv.inner.kv.try_read().is_err());
+
+        let writers: Vec<_> = [b"a", b"b"]
+            .into_iter()
+            .map(|key| {
+                let kv = kv.clone();
+                thread::spawn(move || kv.delete(key))
+            })
+            .collect();
+
+        wait_until(|| {
+            let queue = kv.inner.queue.lock().unwrap();
+            queue.leader && queue.pending.len() == 1
+        });
+
+        panic.send(()).unwrap();
+        assert!(holder.join().is_err());
+
+        // the leader fails and the queued writer takes over and fails too,
+        // rather than waiting forever
+        for writer in writers {
+            let result = writer.join().unwrap();
+            assert!(matches!(result, Err(KeyValueStoreError::Panicked)));
+        }
+
+        assert!(matches!(kv.get(b"a"), Err(KeyValueStoreError::Panicked)));
+        assert!(matches!(
+            kv.insert(b"c", b"3"),
+            Err(KeyValueStoreError::Panicked)
+        ));
+        assert_eq!(kv.stats().num_keys, 1);
+
+        drop(kv);
+        let kv = Bitcask::open(opts).unwrap();
+        assert_eq!(kv.get(b"a").unwrap().unwrap().data(), b"1");
+    }
+}
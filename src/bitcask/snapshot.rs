@@ -0,0 +1,174 @@
+use super::blob::map_blob;
+use super::cursor::Cursor;
+use super::error::{KeyValueStoreError, Result};
+use super::index::Index;
+use super::iter::{FileCache, Keys, RangeIter};
+use super::reader::{FileMap, Value, ValueRef};
+use super::throttle::RateLimiter;
+use super::verify::{self, VerifyReport};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::RangeBounds;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// A consistent, read-only view of the store at the time it was taken.
+///
+/// A snapshot owns a copy of the index and keeps every data file it refers
+/// to mapped, along with every blob file, so writes, deletes and merges on
+/// the store after the snapshot was taken are not visible through it. Files
+/// removed by a merge are unlinked from the store directory right away; the
+/// snapshot's maps keep their contents readable until it is dropped.
+///
+/// Taking a snapshot doesn't copy the index, the snapshot shares it with the
+/// store. Instead, the first write to a namespace while a snapshot of it is
+/// alive copies that namespace's index, which takes time and memory in
+/// proportion to its number of keys. With
+/// [`IndexKind::Compact`](crate::IndexKind::Compact) only the keys written
+/// since the index was last compacted are copied. Snapshots that are kept
+/// around while the store is written to should therefore be few and short
+/// lived.
+///
+/// The methods of a snapshot read the default namespace. The named
+/// namespaces are captured along with it and read through
+/// [`Snapshot::namespace`].
+pub struct Snapshot {
+    index: Index,
+    namespaces: BTreeMap<String, Index>,
+    files: HashMap<u64, Arc<FileMap>>,
+    blobs: HashMap<u64, Arc<FileMap>>,
+    root: PathBuf,
+}
+
+impl Snapshot {
+    pub(crate) fn new(
+        mut index: Index,
+        mut namespaces: BTreeMap<String, Index>,
+        files: HashMap<u64, Arc<FileMap>>,
+        blobs: HashMap<u64, Arc<FileMap>>,
+        root: PathBuf,
+    ) -> Self {
+        // keys kept as hashes must be read from the files the snapshot holds
+        index.use_maps(&files);
+
+        for index in namespaces.values_mut() {
+            index.use_maps(&files);
+        }
+
+        Self {
+            index,
+            namespaces,
+            files,
+            blobs,
+            root,
+        }
+    }
+
+    /// The names of the namespaces the store had when the snapshot was
+    /// taken, in order.
+    pub fn namespaces(&self) -> Vec<String> {
+        self.namespaces.keys().cloned().collect()
+    }
+
+    /// The namespace `name` as it was when the snapshot was taken, or `None`
+    /// if the store had no such namespace. The returned snapshot shares the
+    /// files of this one and reads only that namespace.
+    pub fn namespace(&self, name: &str) -> Option<Snapshot> {
+        let index = self.namespaces.get(name)?.clone();
+
+        Some(Self {
+            index,
+            namespaces: BTreeMap::new(),
+            files: self.files.clone(),
+            blobs: self.blobs.clone(),
+            root: self.root.clone(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn exists(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.index.get(key)?.is_some())
+    }
+
+    /// See [`Bitcask::get`].
+    ///
+    /// [`Bitcask::get`]: crate::Bitcask::get
+    pub fn get(&self, key: &[u8]) -> Result<Option<Value>> {
+        let Some(value) = self.get_ref(key)? else {
+            return Ok(None);
+        };
+
+        value.check()?;
+
+        Ok(Some(value.to_value()))
+    }
+
+    /// Like [`Snapshot::get`], but returns the value in place in the mapped
+    /// data file instead of copying it. See [`Bitcask::get_ref`].
+    ///
+    /// [`Bitcask::get_ref`]: crate::Bitcask::get_ref
+    pub fn get_ref(&self, key: &[u8]) -> Result<Option<ValueRef>> {
+        let Some(entry) = self.index.get(key)? else {
+            return Ok(None);
+        };
+
+        let map = self.files.get(&entry.file()).ok_or_else(|| {
+            KeyValueStoreError::InvalidFormat(format!(
+                "data file {} is not part of the snapshot",
+                entry.file()
+            ))
+        })?;
+
+        let value = map.get_ref_with(entry.offset(), entry.size(), |id| {
+            map_blob(&self.root, &self.blobs, id)
+        })?;
+
+        Ok(Some(value))
+    }
+
+    pub fn keys<R>(&self, range: R) -> Result<Keys<'_>>
+    where
+        R: RangeBounds<[u8]>,
+    {
+        Ok(Keys::new(self.index.range(range)?))
+    }
+
+    pub fn iter<R>(&self, range: R) -> Result<RangeIter<'_>>
+    where
+        R: RangeBounds<[u8]>,
+    {
+        Ok(RangeIter::new(self.cache(), self.index.range(range)?))
+    }
+
+    pub fn cursor(&self) -> Result<Cursor<'_>> {
+        Cursor::new(self.cache(), &self.index)
+    }
+
+    /// Verifies the data files as they were when the snapshot was taken and
+    /// the entries of every namespace in it, see
+    /// [`Bitcask::verify`](crate::Bitcask::verify).
+    pub(crate) fn verify(&self, limiter: &RateLimiter) -> VerifyReport {
+        let mut files: Vec<_> = self
+            .files
+            .iter()
+            .map(|(&id, map)| (id, Ok(map.map())))
+            .collect();
+        files.sort_by_key(|(id, _)| *id);
+
+        let indexes: Vec<&Index> = std::iter::once(&self.index)
+            .chain(self.namespaces.values())
+            .collect();
+
+        verify::verify(files, &indexes, &self.cache(), limiter)
+    }
+
+    fn cache(&self) -> FileCache {
+        FileCache::with_maps(&self.root, self.files.clone(), self.blobs.clone())
+    }
+}
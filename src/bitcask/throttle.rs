@@ -0,0 +1,158 @@
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The longest a single wait lasts before the limit is looked at again, so
+/// that changing it takes effect quickly.
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+/// The bucket holds at most this many seconds' worth of bytes, which bounds
+/// the bursts after the limiter was idle.
+const BURST_SECS: f64 = 0.1;
+
+/// Limits the rate at which merges, verification and checkpoints read and
+/// write files, so they leave disk bandwidth for reads and writes. A token
+/// bucket that fills at the limit and holds a tenth of a second's worth of
+/// bytes; taking more than it holds waits until it has refilled. Clones
+/// share the bucket, so everything throttled together stays under the limit.
+#[derive(Clone)]
+pub struct RateLimiter {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    /// Bytes per second, or 0 for no limit.
+    rate: AtomicU64,
+    bucket: Mutex<Bucket>,
+    bytes: AtomicU64,
+    waits: AtomicU64,
+    /// Nanoseconds spent waiting.
+    waited: AtomicU64,
+}
+
+struct Bucket {
+    /// Bytes that can be taken without waiting, negative while in debt.
+    available: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<u64>) -> Self {
+        let inner = Inner {
+            rate: AtomicU64::new(rate.unwrap_or(0)),
+            bucket: Mutex::new(Bucket {
+                available: 0.0,
+                refilled: Instant::now(),
+            }),
+            bytes: AtomicU64::new(0),
+            waits: AtomicU64::new(0),
+            waited: AtomicU64::new(0),
+        };
+
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        match self.inner.rate.load(Ordering::Relaxed) {
+            0 => None,
+            rate => Some(rate),
+        }
+    }
+
+    /// Changes the limit, also for the reads and writes currently waiting.
+    pub fn set_rate(&self, rate: Option<u64>) {
+        self.inner.rate.store(rate.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// Accounts for `bytes` read or written, first waiting until the limit
+    /// allows them.
+    pub fn acquire(&self, bytes: u64) {
+        self.inner.bytes.fetch_add(bytes, Ordering::Relaxed);
+
+        let mut bucket = self.inner.bucket.lock().expect("rate limiter lock");
+        let mut owed = bytes as f64;
+        let mut waited = Duration::ZERO;
+
+        loop {
+            let rate = self.inner.rate.load(Ordering::Relaxed);
+
+            if rate == 0 {
+                bucket.available = 0.0;
+                bucket.refilled = Instant::now();
+                break;
+            }
+
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+            let burst = rate as f64 * BURST_SECS;
+            bucket.available = (bucket.available + elapsed * rate as f64).min(burst);
+            bucket.refilled = now;
+
+            bucket.available -= owed;
+            owed = 0.0;
+
+            if bucket.available >= 0.0 {
+                break;
+            }
+
+            let wait = Duration::from_secs_f64(-bucket.available / rate as f64).min(MAX_WAIT);
+            drop(bucket);
+            std::thread::sleep(wait);
+            waited += wait;
+            bucket = self.inner.bucket.lock().expect("rate limiter lock");
+        }
+
+        if !waited.is_zero() {
+            self.inner.waits.fetch_add(1, Ordering::Relaxed);
+            self.inner
+                .waited
+                .fetch_add(waited.as_nanos() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Bytes read and written under the limit.
+    pub fn bytes(&self) -> u64 {
+        self.inner.bytes.load(Ordering::Relaxed)
+    }
+
+    /// How many reads and writes had to wait for the limit.
+    pub fn waits(&self) -> u64 {
+        self.inner.waits.load(Ordering::Relaxed)
+    }
+
+    /// The total time spent waiting for the limit.
+    pub fn waited(&self) -> Duration {
+        Duration::from_nanos(self.inner.waited.load(Ordering::Relaxed))
+    }
+
+    /// Wraps `reader` so that reading from it takes from this limit.
+    pub fn reader<R>(&self, reader: R) -> Throttled<R>
+    where
+        R: Read,
+    {
+        Throttled {
+            reader,
+            limiter: self.clone(),
+        }
+    }
+}
+
+/// A reader whose reads are throttled by a [`RateLimiter`].
+pub struct Throttled<R> {
+    reader: R,
+    limiter: RateLimiter,
+}
+
+impl<R> Read for Throttled<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.limiter.acquire(n as u64);
+        Ok(n)
+    }
+}
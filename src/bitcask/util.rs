@@ -5,8 +5,16 @@ use std::path::{Path, PathBuf};
 
 pub const DATA_FILE_HEADER: &[u8] = b"KV-STORE - DATA FILE\0";
 pub const INDEX_FILE_HEADER: &[u8] = b"KV-STORE - INDEX FILE\0";
+pub const BLOB_FILE_HEADER: &[u8] = b"KV-STORE - BLOB FILE\0";
+/// Written after the index file header in place of a key length, followed by
+/// the format version. Version 1 files have neither.
+pub const INDEX_FORMAT_MARKER: u64 = u64::MAX;
+pub const INDEX_FORMAT_VERSION: u32 = 4;
+pub const DUMP_FILE_HEADER: &[u8] = b"KV-STORE - DUMP FILE\0";
+pub const DUMP_FORMAT_VERSION: u32 = 2;
 pub const DATA_FILE_EXT: &str = "kv";
 pub const INDEX_FILE_EXT: &str = "idx";
+pub const BLOB_FILE_EXT: &str = "blob";
 
 pub struct Size;
 
@@ -36,6 +44,13 @@ where
     root.as_ref().join(format!("{}.{}", id, DATA_FILE_EXT))
 }
 
+pub fn blob_path<P>(root: P, id: u64) -> PathBuf
+where
+    P: AsRef<Path>,
+{
+    root.as_ref().join(format!("{}.{}", id, BLOB_FILE_EXT))
+}
+
 pub fn idx_path<P>(root: P) -> PathBuf
 where
     P: AsRef<Path>,
@@ -43,6 +58,24 @@ where
     root.as_ref().join(format!("db.{}", INDEX_FILE_EXT))
 }
 
+/// The temporary file an index file is written to before it replaces the
+/// index of `root`.
+pub fn tmp_idx_path<P>(root: P) -> PathBuf
+where
+    P: AsRef<Path>,
+{
+    idx_path(root).with_extension("tmp")
+}
+
+/// Forces the entries of the directory `path` to disk, so files created,
+/// renamed or removed in it survive a crash of the machine.
+pub fn sync_dir<P>(path: P) -> std::io::Result<()>
+where
+    P: AsRef<Path>,
+{
+    fs::File::open(path)?.sync_all()
+}
+
 pub fn compute_size(k: &[u8], v: &[u8]) -> u64 {
     (std::mem::size_of::<u32>()
         + std::mem::size_of::<u64>()
@@ -53,11 +86,41 @@ pub fn compute_size(k: &[u8], v: &[u8]) -> u64 {
         + v.len()) as u64
 }
 
+/// Returns the smallest key that is greater than every key starting with
+/// `prefix`, or `None` if no such key exists (the prefix is empty or all `0xff`).
+pub fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+
+    None
+}
+
 pub fn crc_init() -> Crc<u32> {
     Crc::<u32>::new(&CRC_32_CKSUM)
 }
 
 pub fn walk_dir<P>(path: P) -> Vec<u64>
+where
+    P: AsRef<Path>,
+{
+    walk_ext(path, DATA_FILE_EXT)
+}
+
+/// Returns the ids of the blob files in `path`, in ascending order.
+pub fn walk_blobs<P>(path: P) -> Vec<u64>
+where
+    P: AsRef<Path>,
+{
+    walk_ext(path, BLOB_FILE_EXT)
+}
+
+fn walk_ext<P>(path: P, file_ext: &str) -> Vec<u64>
 where
     P: AsRef<Path>,
 {
@@ -75,7 +138,7 @@ where
                     continue;
                 }
                 let ext = xs[1];
-                if ext == "kv" {
+                if ext == file_ext {
                     let file_id: u64 = match xs[0].parse() {
                         Ok(id) => id,
                         _ => continue,
@@ -88,17 +151,3 @@ where
 
     hs.into_iter().collect()
 }
-
-pub fn delete_files<P>(path: P)
-where
-    P: AsRef<Path>,
-{
-    let mut dir = fs::read_dir(path.as_ref()).expect("read dir");
-    while let Some(Ok(e)) = dir.next() {
-        if e.file_type().expect("file type").is_dir() {
-            continue;
-        }
-
-        std::fs::remove_file(e.path()).expect("delete file");
-    }
-}
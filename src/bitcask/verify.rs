@@ -0,0 +1,118 @@
+use super::error::KeyValueStoreError;
+use super::index::Index;
+use super::iter::FileCache;
+use super::reader::EntryReader;
+use super::throttle::RateLimiter;
+use memmap::Mmap;
+use std::io::{self, Cursor};
+use std::sync::Arc;
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub num_files: u64,
+    pub num_records: u64,
+    pub num_tombstones: u64,
+    /// Records whose checksum did not match, as `(file, offset)`. Scanning a
+    /// file stops at its first corrupt record.
+    pub corrupt_records: Vec<(u64, u64)>,
+    /// Files that could not be scanned to the end, as `(file, error)`.
+    pub unreadable_files: Vec<(u64, String)>,
+    pub num_entries: u64,
+    /// Index entries whose value could not be read, as `(key, error)`.
+    pub bad_entries: Vec<(Vec<u8>, KeyValueStoreError)>,
+    /// Errors reading back the keys of entries of
+    /// [`IndexKind::HashedKeys`](crate::IndexKind::HashedKeys) indexes,
+    /// whose values are not checked.
+    pub unreadable_keys: Vec<KeyValueStoreError>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.corrupt_records.is_empty()
+            && self.unreadable_files.is_empty()
+            && self.bad_entries.is_empty()
+            && self.unreadable_keys.is_empty()
+    }
+}
+
+/// Checks the checksum of every record in the mapped data `files`, by id,
+/// then reads every entry in `indexes` back through `cache`, reading no
+/// faster than `limiter` allows.
+pub fn verify(
+    files: Vec<(u64, io::Result<Arc<Mmap>>)>,
+    indexes: &[&Index],
+    cache: &FileCache,
+    limiter: &RateLimiter,
+) -> VerifyReport {
+    let mut report = VerifyReport::default();
+
+    for (id, file) in files {
+        report.num_files += 1;
+
+        let map = match file {
+            Ok(map) => map,
+            Err(e) => {
+                report.unreadable_files.push((id, e.to_string()));
+                continue;
+            }
+        };
+
+        let mut reader = match EntryReader::from_reader(Cursor::new(&map[..]), map.len() as u64) {
+            Ok(reader) => reader,
+            Err(e) => {
+                report.unreadable_files.push((id, e.to_string()));
+                continue;
+            }
+        };
+
+        loop {
+            match reader.next_record() {
+                Ok(Some(record)) if record.is_valid() => {
+                    limiter.acquire(record.size());
+                    report.num_records += 1;
+                    if record.is_tombstone() {
+                        report.num_tombstones += 1;
+                    }
+                }
+                Ok(Some(record)) => {
+                    report.corrupt_records.push((id, record.offset()));
+                    break;
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    report.unreadable_files.push((id, e.to_string()));
+                    break;
+                }
+            }
+        }
+    }
+
+    for item in indexes.iter().flat_map(|index| index.entries()) {
+        report.num_entries += 1;
+
+        let (k, entry) = match item {
+            Ok(item) => item,
+            Err(e) => {
+                report.unreadable_keys.push(e);
+                continue;
+            }
+        };
+
+        if !cache.has_file(entry.file()) {
+            let e = std::io::Error::new(std::io::ErrorKind::NotFound, "data file missing");
+            report.bad_entries.push((k.to_vec(), e.into()));
+            continue;
+        }
+
+        let checked = cache.get_ref(&entry).and_then(|value| {
+            limiter.acquire(value.len() as u64);
+            value.check()
+        });
+
+        if let Err(e) = checked {
+            report.bad_entries.push((k.to_vec(), e));
+        }
+    }
+
+    report
+}
@@ -0,0 +1,535 @@
+//! A small HTTP server exposing a store as a JSON API.
+//!
+//! | Method | Path | Description |
+//! |--------|------|-------------|
+//! | `GET` | `/kv/{key}` | The value of a key as the raw response body |
+//! | `PUT` | `/kv/{key}` | Insert or replace a key with the request body |
+//! | `DELETE` | `/kv/{key}` | Delete a key |
+//! | `GET` | `/kv?prefix=&start=&end=&limit=` | List entries in key order |
+//! | `GET` | `/admin/stats` | Statistics about the store |
+//! | `POST` | `/admin/merge` | Start compacting the data files in the background |
+//! | `GET` | `/admin/merge` | Whether a merge is running, its progress and how the last one ended |
+//!
+//! Keys in paths and query strings are percent decoded. Passing
+//! `encoding=base64` makes keys in the path and query, and keys and values in
+//! listings, URL safe base64 instead, for stores with binary keys. Otherwise
+//! listed keys and values are decoded as UTF-8, replacing invalid bytes.
+
+use crate::{Bitcask, KeyValueStoreError, MergeHandle, SharedBitcask};
+use base64::alphabet::URL_SAFE;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use base64::Engine;
+use serde_json::{json, Value as Json};
+use std::io::{self, Read};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tiny_http::{Header, Method, Request, Response};
+
+/// Largest request body accepted.
+const MAX_BODY_LEN: u64 = 512 * 1024 * 1024;
+const DEFAULT_LIMIT: usize = 100;
+const WORKERS: usize = 4;
+
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &URL_SAFE,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Called with the errors of connections, see [`HttpServer::on_error`].
+type ErrorHook = Arc<dyn Fn(io::Error) + Send + Sync>;
+
+pub struct HttpServer {
+    server: tiny_http::Server,
+    kv: SharedBitcask,
+    merges: Mutex<Merges>,
+    on_error: ErrorHook,
+}
+
+/// The merges started by `POST /admin/merge`.
+#[derive(Default)]
+struct Merges {
+    running: Option<MergeHandle>,
+    /// How the last merge that finished ended.
+    last: Option<Result<(), KeyValueStoreError>>,
+}
+
+impl Merges {
+    /// Picks up the outcome of the running merge if it has finished.
+    fn reap(&mut self) {
+        if let Some(handle) = self.running.take_if(|handle| handle.is_finished()) {
+            self.last = Some(handle.wait());
+        }
+    }
+}
+
+struct Reply {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn json(status: u16, body: Json) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    fn bytes(body: Vec<u8>) -> Self {
+        Self {
+            status: 200,
+            content_type: "application/octet-stream",
+            body,
+        }
+    }
+
+    fn empty() -> Self {
+        Self {
+            status: 204,
+            content_type: "text/plain",
+            body: Vec::new(),
+        }
+    }
+
+    fn error<S>(status: u16, msg: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self::json(status, json!({ "error": msg.into() }))
+    }
+}
+
+impl From<KeyValueStoreError> for Reply {
+    fn from(e: KeyValueStoreError) -> Self {
+        let status = match e {
+            KeyValueStoreError::ReadOnly => 403,
+            KeyValueStoreError::PayloadTooLarge => 413,
+            KeyValueStoreError::MergeRunning => 409,
+            KeyValueStoreError::Unsupported(_) => 501,
+            _ => 500,
+        };
+
+        Self::error(status, e.to_string())
+    }
+}
+
+impl HttpServer {
+    pub fn bind<A>(kv: Bitcask, addr: A) -> io::Result<Self>
+    where
+        A: ToSocketAddrs,
+    {
+        let server = tiny_http::Server::http(addr).map_err(io::Error::other)?;
+
+        Ok(Self {
+            server,
+            kv: SharedBitcask::new(kv),
+            merges: Mutex::new(Merges::default()),
+            on_error: Arc::new(|_| {}),
+        })
+    }
+
+    /// Calls `f` with the error of every request that fails to be received
+    /// or answered. Such errors only end their own connection, and are
+    /// ignored unless a hook is set.
+    pub fn on_error<F>(&mut self, f: F)
+    where
+        F: Fn(io::Error) + Send + Sync + 'static,
+    {
+        self.on_error = Arc::new(f);
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.server
+            .server_addr()
+            .to_ip()
+            .expect("listening on a TCP socket")
+    }
+
+    /// Serves requests forever. Requests that fail to be received are passed
+    /// to [`HttpServer::on_error`] and skipped.
+    pub fn run(&self) -> io::Result<()> {
+        thread::scope(|s| {
+            let workers: Vec<_> = (0..WORKERS).map(|_| s.spawn(|| self.serve())).collect();
+
+            workers
+                .into_iter()
+                .try_for_each(|w| w.join().expect("http worker panicked"))
+        })
+    }
+
+    fn serve(&self) -> io::Result<()> {
+        loop {
+            let mut request = match self.server.recv() {
+                Ok(request) => request,
+                Err(e) => {
+                    (self.on_error)(e);
+                    continue;
+                }
+            };
+
+            let reply = self.handle(&mut request);
+
+            let content_type =
+                Header::from_bytes("Content-Type", reply.content_type).expect("valid header");
+            let response = Response::from_data(reply.body)
+                .with_status_code(reply.status)
+                .with_header(content_type);
+
+            if let Err(e) = request.respond(response) {
+                (self.on_error)(e);
+            }
+        }
+    }
+
+    fn handle(&self, request: &mut Request) -> Reply {
+        let url = request.url().to_string();
+        let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+        let query = Query::parse(query);
+
+        let base64 = match query.get("encoding") {
+            None | Some(b"utf8") => false,
+            Some(b"base64") => true,
+            Some(_) => return Reply::error(400, "encoding must be utf8 or base64"),
+        };
+
+        if let Some(key) = path.strip_prefix("/kv/") {
+            let key = match decode_key(percent_decode(key.as_bytes()), base64) {
+                Ok(key) => key,
+                Err(reply) => return reply,
+            };
+
+            return match request.method() {
+                Method::Get => self.get(&key),
+                Method::Put => self.put(&key, request),
+                Method::Delete => self.delete(&key),
+                _ => Reply::error(405, "method not allowed"),
+            };
+        }
+
+        match (request.method(), path) {
+            (Method::Get, "/kv") => self.list(&query, base64),
+            (Method::Get, "/admin/stats") => self.stats(),
+            (Method::Post, "/admin/merge") => self.merge(),
+            (Method::Get, "/admin/merge") => self.merge_status(),
+            (_, "/kv" | "/admin/stats" | "/admin/merge") => Reply::error(405, "method not allowed"),
+            _ => Reply::error(404, "not found"),
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Reply {
+        match self.kv.get(key) {
+            Ok(Some(value)) => Reply::bytes(value.data().to_vec()),
+            Ok(None) => Reply::error(404, "key not found"),
+            Err(e) => e.into(),
+        }
+    }
+
+    fn put(&self, key: &[u8], request: &mut Request) -> Reply {
+        let mut value = Vec::new();
+        let mut body = request.as_reader().take(MAX_BODY_LEN + 1);
+
+        if let Err(e) = body.read_to_end(&mut value) {
+            return Reply::error(400, e.to_string());
+        }
+        if value.len() as u64 > MAX_BODY_LEN {
+            return KeyValueStoreError::PayloadTooLarge.into();
+        }
+
+        match self.kv.insert(key, &value) {
+            Ok(()) => Reply::empty(),
+            Err(e) => e.into(),
+        }
+    }
+
+    fn delete(&self, key: &[u8]) -> Reply {
+        match self.kv.delete(key) {
+            Ok(true) => Reply::empty(),
+            Ok(false) => Reply::error(404, "key not found"),
+            Err(e) => e.into(),
+        }
+    }
+
+    fn list(&self, query: &Query, base64: bool) -> Reply {
+        let mut keys = [None, None, None];
+
+        for (i, name) in ["prefix", "start", "end"].iter().enumerate() {
+            if let Some(key) = query.get(name) {
+                match decode_key(key.to_vec(), base64) {
+                    Ok(key) => keys[i] = Some(key),
+                    Err(reply) => return reply,
+                }
+            }
+        }
+
+        let [prefix, start, end] = keys;
+
+        if prefix.is_some() && (start.is_some() || end.is_some()) {
+            return Reply::error(400, "prefix can't be combined with start or end");
+        }
+
+        let limit = match query.get("limit") {
+            Some(limit) => match std::str::from_utf8(limit).ok().and_then(|l| l.parse().ok()) {
+                Some(limit) => limit,
+                None => return Reply::error(400, "invalid limit"),
+            },
+            None => DEFAULT_LIMIT,
+        };
+
+        let kv = match self.kv.read() {
+            Ok(kv) => kv,
+            Err(e) => return e.into(),
+        };
+
+        let iter = match &prefix {
+            Some(prefix) => kv.scan_prefix(prefix),
+            None => {
+                let start = start.as_deref().map_or(Bound::Unbounded, Bound::Included);
+                let end = end.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+                kv.iter((start, end))
+            }
+        };
+
+        let iter = match iter {
+            Ok(iter) => iter,
+            Err(e) => return e.into(),
+        };
+
+        let mut entries = Vec::new();
+        let mut next = None;
+
+        for item in iter {
+            let (key, value) = match item {
+                Ok(item) => item,
+                Err(e) => return e.into(),
+            };
+
+            if entries.len() == limit {
+                next = Some(encode(&key, base64));
+                break;
+            }
+
+            entries.push(json!({
+                "key": encode(&key, base64),
+                "value": encode(value.data(), base64),
+            }));
+        }
+
+        Reply::json(200, json!({ "entries": entries, "next": next }))
+    }
+
+    fn stats(&self) -> Reply {
+        let stats = self.kv.stats();
+
+        Reply::json(
+            200,
+            json!({
+                "num_keys": stats.num_keys,
+                "num_files": stats.num_files,
+                "data_size": stats.data_size,
+                "live_size": stats.live_size,
+                "num_entries_deleted": stats.num_entries_deleted,
+                "num_bytes_deleted": stats.num_bytes_deleted,
+                "num_blob_files": stats.num_blob_files,
+                "blob_size": stats.blob_size,
+                "blob_bytes_deleted": stats.blob_bytes_deleted,
+                "rate_limited_bytes": stats.rate_limited_bytes,
+                "rate_limit_waits": stats.rate_limit_waits,
+                "rate_limit_wait_secs": stats.rate_limit_wait_time.as_secs_f64(),
+            }),
+        )
+    }
+
+    /// Starts a merge in the background, answering `202 Accepted` once it
+    /// runs, `204 No Content` if no file is worth merging and `409 Conflict`
+    /// if a merge is already running. How it ends is reported by
+    /// `GET /admin/merge`.
+    fn merge(&self) -> Reply {
+        let mut merges = self.merges.lock().expect("merge lock");
+        merges.reap();
+
+        match self.kv.merge_in_background() {
+            Ok(Some(handle)) => {
+                merges.running = Some(handle);
+                Reply::json(202, json!({ "merging": true }))
+            }
+            Ok(None) => Reply::empty(),
+            Err(e) => e.into(),
+        }
+    }
+
+    /// Whether a merge started by `POST /admin/merge` is running and how far
+    /// it got, and whether the last one that finished succeeded or the error
+    /// it failed with.
+    fn merge_status(&self) -> Reply {
+        let mut merges = self.merges.lock().expect("merge lock");
+        merges.reap();
+
+        let progress = merges.running.as_ref().map(|handle| {
+            let progress = handle.progress();
+            json!({
+                "bytes_read": progress.bytes_read,
+                "bytes_total": progress.bytes_total,
+            })
+        });
+
+        let last = merges.last.as_ref().map(|result| match result {
+            Ok(()) => json!({ "ok": true }),
+            Err(e) => json!({ "ok": false, "error": e.to_string() }),
+        });
+
+        Reply::json(
+            200,
+            json!({
+                "merging": merges.running.is_some(),
+                "progress": progress,
+                "last": last,
+            }),
+        )
+    }
+}
+
+/// The decoded `name=value` pairs of a query string.
+struct Query(Vec<(Vec<u8>, Vec<u8>)>);
+
+impl Query {
+    fn parse(query: &str) -> Self {
+        let pairs = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let pair = pair.replace('+', " ");
+                let (name, value) = pair.split_once('=').unwrap_or((&pair, ""));
+                (
+                    percent_decode(name.as_bytes()),
+                    percent_decode(value.as_bytes()),
+                )
+            })
+            .collect();
+
+        Self(pairs)
+    }
+
+    fn get(&self, name: &str) -> Option<&[u8]> {
+        self.0
+            .iter()
+            .find(|(n, _)| n == name.as_bytes())
+            .map(|(_, v)| v.as_slice())
+    }
+}
+
+fn decode_key(key: Vec<u8>, base64: bool) -> Result<Vec<u8>, Reply> {
+    match base64 {
+        true => BASE64
+            .decode(&key)
+            .map_err(|e| Reply::error(400, format!("invalid base64 key: {}", e))),
+        false => Ok(key),
+    }
+}
+
+fn encode(bytes: &[u8], base64: bool) -> String {
+    match base64 {
+        true => BASE64.encode(bytes),
+        false => String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
+fn percent_decode(s: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut i = 0;
+
+    while i < s.len() {
+        match s.get(i + 1..i + 3) {
+            Some(hex) if s[i] == b'%' && hex.iter().all(u8::is_ascii_hexdigit) => {
+                let hex = std::str::from_utf8(hex).expect("ascii");
+                out.push(u8::from_str_radix(hex, 16).expect("hex digits"));
+                i += 3;
+            }
+            _ => {
+                out.push(s[i]);
+                i += 1;
+            }
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Options;
+    use std::io::Write;
+    use std::net::TcpStream;
+    use std::time::{Duration, Instant};
+
+    /// Sends a request and returns the status and body of the response.
+    fn request(addr: SocketAddr, method: &str, path: &str) -> (u16, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: test\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            method, path
+        )
+        .unwrap();
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let status = std::str::from_utf8(&response[9..12])
+            .unwrap()
+            .parse()
+            .unwrap();
+
+        (status, response[split + 4..].to_vec())
+    }
+
+    fn merge_status(addr: SocketAddr) -> Json {
+        let (status, body) = request(addr, "GET", "/admin/merge");
+        assert_eq!(status, 200);
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[test]
+    fn merge_status_reports_the_last_merge() {
+        let root = std::env::temp_dir().join(format!("kvs-http-merge-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+
+        // every data file is sealed with overwritten values only
+        let mut opts = Options::new(root);
+        opts.file_size_limit = 100;
+        let mut kv = Bitcask::open(opts).unwrap();
+        for i in 0..20u8 {
+            kv.insert(b"a", &[i; 32]).unwrap();
+        }
+
+        let server = HttpServer::bind(kv, "127.0.0.1:0").unwrap();
+        let addr = server.local_addr();
+        thread::spawn(move || server.run());
+
+        let status = merge_status(addr);
+        assert_eq!(status["merging"], false);
+        assert!(status["last"].is_null());
+
+        assert_eq!(request(addr, "POST", "/admin/merge").0, 202);
+
+        let start = Instant::now();
+        let status = loop {
+            let status = merge_status(addr);
+            if status["merging"] == false {
+                break status;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10), "timed out");
+            thread::sleep(Duration::from_millis(1));
+        };
+
+        assert_eq!(status["last"]["ok"], true);
+        assert_eq!(request(addr, "GET", "/kv/a"), (200, vec![19; 32]));
+    }
+}
@@ -0,0 +1,451 @@
+//! Order preserving key encoding.
+//!
+//! Keys are compared byte by byte, so ranges and prefix scans only follow
+//! the logical order of keys if they are encoded so that byte order equals
+//! logical order. The encoders here guarantee that:
+//!
+//! - unsigned integers are written big endian
+//! - signed integers are written big endian with the sign bit flipped, so
+//!   negative numbers sort before positive ones
+//! - floats have all bits flipped if negative and only the sign bit flipped
+//!   otherwise, so they sort by value with `-0.0` before `0.0`
+//! - strings and byte strings escape `0x00` as `0x00 0xFF` and end with
+//!   `0x00 0x00`, so a string sorts before every longer string starting with
+//!   it and can be followed by further parts
+//!
+//! Composite keys are their parts written one after another, and sort by
+//! their first part, then their second and so on. Writing only the leading
+//! parts of a composite key gives a prefix for
+//! [`Bitcask::scan_prefix`](crate::Bitcask::scan_prefix).
+//!
+//! ```text
+//! let key = key::encode(&(user_id, timestamp));
+//! let (user_id, timestamp): (u64, i64) = key::decode(&key)?;
+//!
+//! let prefix = key::encode(&(user_id,));
+//! for item in kv.scan_prefix(&prefix)? { ... }
+//! ```
+
+use crate::KeyValueStoreError;
+
+type Result<T> = std::result::Result<T, KeyValueStoreError>;
+
+const ESCAPE: u8 = 0xFF;
+const END: u8 = 0x00;
+
+/// Builds an encoded key from its parts.
+#[derive(Debug, Clone, Default)]
+pub struct KeyWriter {
+    buf: Vec<u8>,
+}
+
+macro_rules! write_uint {
+    ($($name:ident: $ty:ty),*) => {
+        $(
+            pub fn $name(&mut self, v: $ty) -> &mut Self {
+                self.buf.extend_from_slice(&v.to_be_bytes());
+                self
+            }
+        )*
+    };
+}
+
+macro_rules! write_int {
+    ($($name:ident: $ty:ty => $unsigned:ident),*) => {
+        $(
+            pub fn $name(&mut self, v: $ty) -> &mut Self {
+                self.$unsigned(v.cast_unsigned() ^ (1 << (<$ty>::BITS - 1)))
+            }
+        )*
+    };
+}
+
+impl KeyWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn bool(&mut self, v: bool) -> &mut Self {
+        self.u8(v as u8)
+    }
+
+    write_uint!(u8: u8, u16: u16, u32: u32, u64: u64, u128: u128);
+
+    write_int!(
+        i8: i8 => u8,
+        i16: i16 => u16,
+        i32: i32 => u32,
+        i64: i64 => u64,
+        i128: i128 => u128
+    );
+
+    pub fn f32(&mut self, v: f32) -> &mut Self {
+        let bits = v.to_bits();
+        self.u32(match bits >> 31 {
+            0 => bits | (1 << 31),
+            _ => !bits,
+        })
+    }
+
+    pub fn f64(&mut self, v: f64) -> &mut Self {
+        let bits = v.to_bits();
+        self.u64(match bits >> 63 {
+            0 => bits | (1 << 63),
+            _ => !bits,
+        })
+    }
+
+    pub fn str(&mut self, v: &str) -> &mut Self {
+        self.bytes(v.as_bytes())
+    }
+
+    pub fn bytes(&mut self, v: &[u8]) -> &mut Self {
+        for &b in v {
+            self.buf.push(b);
+            if b == 0 {
+                self.buf.push(ESCAPE);
+            }
+        }
+
+        self.buf.extend_from_slice(&[END, END]);
+        self
+    }
+}
+
+/// Reads the parts of an encoded key in the order they were written.
+#[derive(Debug, Clone)]
+pub struct KeyReader<'a> {
+    input: &'a [u8],
+}
+
+macro_rules! read_uint {
+    ($($name:ident: $ty:ty),*) => {
+        $(
+            pub fn $name(&mut self) -> Result<$ty> {
+                self.take().map(<$ty>::from_be_bytes)
+            }
+        )*
+    };
+}
+
+macro_rules! read_int {
+    ($($name:ident: $ty:ty => $unsigned:ident),*) => {
+        $(
+            pub fn $name(&mut self) -> Result<$ty> {
+                let v = self.$unsigned()? ^ (1 << (<$ty>::BITS - 1));
+                Ok(v.cast_signed())
+            }
+        )*
+    };
+}
+
+impl<'a> KeyReader<'a> {
+    pub fn new(input: &'a [u8]) -> Self {
+        Self { input }
+    }
+
+    /// The bytes not read yet.
+    pub fn remaining(&self) -> &'a [u8] {
+        self.input
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.input.is_empty()
+    }
+
+    /// Fails if any bytes are left, which means the key held more parts than
+    /// were read.
+    pub fn finish(&self) -> Result<()> {
+        match self.is_empty() {
+            true => Ok(()),
+            false => Err(invalid("trailing bytes after key")),
+        }
+    }
+
+    pub fn bool(&mut self) -> Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid("invalid bool in key")),
+        }
+    }
+
+    read_uint!(u8: u8, u16: u16, u32: u32, u64: u64, u128: u128);
+
+    read_int!(
+        i8: i8 => u8,
+        i16: i16 => u16,
+        i32: i32 => u32,
+        i64: i64 => u64,
+        i128: i128 => u128
+    );
+
+    pub fn f32(&mut self) -> Result<f32> {
+        let bits = self.u32()?;
+        Ok(f32::from_bits(match bits >> 31 {
+            0 => !bits,
+            _ => bits ^ (1 << 31),
+        }))
+    }
+
+    pub fn f64(&mut self) -> Result<f64> {
+        let bits = self.u64()?;
+        Ok(f64::from_bits(match bits >> 63 {
+            0 => !bits,
+            _ => bits ^ (1 << 63),
+        }))
+    }
+
+    pub fn str(&mut self) -> Result<String> {
+        String::from_utf8(self.bytes()?).map_err(|_| invalid("invalid utf-8 in key"))
+    }
+
+    pub fn bytes(&mut self) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+
+        loop {
+            match self.u8()? {
+                0 => match self.u8()? {
+                    ESCAPE => out.push(0),
+                    END => return Ok(out),
+                    _ => return Err(invalid("invalid escape in key")),
+                },
+                b => out.push(b),
+            }
+        }
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        match self.input.split_first_chunk::<N>() {
+            Some((bytes, rest)) => {
+                self.input = rest;
+                Ok(*bytes)
+            }
+            None => Err(invalid("unexpected end of key")),
+        }
+    }
+}
+
+/// A type that can be a key or a part of a composite key.
+pub trait Key: Sized {
+    fn write(&self, w: &mut KeyWriter);
+    fn read(r: &mut KeyReader<'_>) -> Result<Self>;
+}
+
+pub fn encode<K>(key: &K) -> Vec<u8>
+where
+    K: Key,
+{
+    let mut w = KeyWriter::new();
+    key.write(&mut w);
+    w.into_bytes()
+}
+
+/// Decodes a key, failing if `bytes` holds more than one key.
+pub fn decode<K>(bytes: &[u8]) -> Result<K>
+where
+    K: Key,
+{
+    let mut r = KeyReader::new(bytes);
+    let key = K::read(&mut r)?;
+    r.finish()?;
+    Ok(key)
+}
+
+macro_rules! impl_key {
+    ($($ty:ty => $method:ident),*) => {
+        $(
+            impl Key for $ty {
+                fn write(&self, w: &mut KeyWriter) {
+                    w.$method(*self);
+                }
+
+                fn read(r: &mut KeyReader<'_>) -> Result<Self> {
+                    r.$method()
+                }
+            }
+        )*
+    };
+}
+
+impl_key!(
+    bool => bool,
+    u8 => u8, u16 => u16, u32 => u32, u64 => u64, u128 => u128,
+    i8 => i8, i16 => i16, i32 => i32, i64 => i64, i128 => i128,
+    f32 => f32, f64 => f64
+);
+
+impl Key for String {
+    fn write(&self, w: &mut KeyWriter) {
+        w.str(self);
+    }
+
+    fn read(r: &mut KeyReader<'_>) -> Result<Self> {
+        r.str()
+    }
+}
+
+impl Key for Vec<u8> {
+    fn write(&self, w: &mut KeyWriter) {
+        w.bytes(self);
+    }
+
+    fn read(r: &mut KeyReader<'_>) -> Result<Self> {
+        r.bytes()
+    }
+}
+
+macro_rules! impl_key_tuple {
+    ($(($($name:ident),+)),*) => {
+        $(
+            impl<$($name: Key),+> Key for ($($name,)+) {
+                #[allow(non_snake_case)]
+                fn write(&self, w: &mut KeyWriter) {
+                    let ($($name,)+) = self;
+                    $($name.write(w);)+
+                }
+
+                fn read(r: &mut KeyReader<'_>) -> Result<Self> {
+                    Ok(($($name::read(r)?,)+))
+                }
+            }
+        )*
+    };
+}
+
+impl_key_tuple!(
+    (A),
+    (A, B),
+    (A, B, C),
+    (A, B, C, D),
+    (A, B, C, D, E),
+    (A, B, C, D, E, F)
+);
+
+fn invalid(msg: &str) -> KeyValueStoreError {
+    KeyValueStoreError::InvalidFormat(msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that `values`, given in ascending order, encode to ascending
+    /// keys and decode back to themselves.
+    fn assert_ordered<K>(values: &[K])
+    where
+        K: Key + std::fmt::Debug + PartialEq,
+    {
+        let keys: Vec<_> = values.iter().map(encode).collect();
+
+        for pair in keys.windows(2) {
+            assert!(pair[0] < pair[1], "{:?} >= {:?}", pair[0], pair[1]);
+        }
+
+        for (value, key) in values.iter().zip(&keys) {
+            assert_eq!(&decode::<K>(key).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn i64_order() {
+        assert_ordered(&[i64::MIN, -256, -1, 0, 1, 255, i64::MAX]);
+    }
+
+    #[test]
+    fn f64_order() {
+        assert_ordered(&[
+            f64::NEG_INFINITY,
+            f64::MIN,
+            -1.5,
+            -f64::MIN_POSITIVE,
+            -0.0,
+            0.0,
+            f64::MIN_POSITIVE,
+            1.5,
+            f64::MAX,
+            f64::INFINITY,
+        ]);
+    }
+
+    #[test]
+    fn f64_order_matches_total_cmp() {
+        let values = [
+            -f64::NAN,
+            f64::NEG_INFINITY,
+            -1.0,
+            -0.0,
+            0.0,
+            1.0,
+            f64::INFINITY,
+            f64::NAN,
+        ];
+
+        for a in values {
+            for b in values {
+                assert_eq!(encode(&a).cmp(&encode(&b)), a.total_cmp(&b), "{a} {b}");
+            }
+        }
+
+        for v in values {
+            assert_eq!(decode::<f64>(&encode(&v)).unwrap().to_bits(), v.to_bits());
+        }
+    }
+
+    #[test]
+    fn bytes_with_zeros() {
+        assert_ordered(&[
+            vec![],
+            vec![0x00],
+            vec![0x00, 0x00],
+            vec![0x00, 0x01],
+            vec![0x00, 0xFF],
+            vec![0x01],
+            vec![0x01, 0x00],
+            vec![0xFF],
+        ]);
+    }
+
+    #[test]
+    fn bytes_reject_bad_escape() {
+        assert!(decode::<Vec<u8>>(&[0x01, 0x00, 0x01]).is_err());
+        assert!(decode::<Vec<u8>>(&[0x01, 0x00]).is_err());
+    }
+
+    #[test]
+    fn tuples_sort_by_parts() {
+        assert_ordered(&[
+            (String::new(), -1i64),
+            (String::new(), 0),
+            ("a".to_string(), i64::MIN),
+            ("a".to_string(), 7),
+            ("a\0".to_string(), -7),
+            ("ab".to_string(), 0),
+            ("b".to_string(), -1),
+        ]);
+    }
+
+    #[test]
+    fn prefix_of_tuple() {
+        let key = encode(&(42u64, "x".to_string(), -3i32));
+
+        assert!(key.starts_with(&encode(&(42u64,))));
+        assert!(key.starts_with(&encode(&(42u64, "x".to_string()))));
+        assert!(!key.starts_with(&encode(&(42u64, "xy".to_string()))));
+    }
+
+    #[test]
+    fn decode_rejects_trailing_bytes() {
+        let key = encode(&(1u8, 2u8));
+        assert!(decode::<(u8,)>(&key).is_err());
+        assert_eq!(decode::<(u8, u8)>(&key).unwrap(), (1, 2));
+    }
+}
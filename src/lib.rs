@@ -1,7 +1,26 @@
 pub(crate) mod api;
 pub(crate) mod bitcask;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod key;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "serde")]
+pub mod typed;
 
-pub use bitcask::reader::Value;
-pub use bitcask::settings::Options;
+#[cfg(feature = "async")]
+pub use bitcask::asynchronous::{AsyncBitcask, AsyncIter};
+pub use bitcask::batch::WriteBatch;
+pub use bitcask::cursor::Cursor;
+pub use bitcask::error::KeyValueStoreError;
+pub use bitcask::iter::{Entries, EntryRef, Keys, RangeIter, SkipErrors, Values};
+pub use bitcask::merge::{MergeHandle, MergeProgress};
+pub use bitcask::namespace::Namespace;
+pub use bitcask::reader::{EntryReader, RawEntry, RecordKind, Value, ValueRef};
+pub use bitcask::secondary::IndexFn;
+pub use bitcask::settings::{IndexKind, NamespaceStats, Options, Stats, SyncPolicy};
+pub use bitcask::shared::SharedBitcask;
+pub use bitcask::snapshot::Snapshot;
 pub use bitcask::util::Size;
+pub use bitcask::verify::VerifyReport;
 pub use bitcask::Bitcask;
@@ -0,0 +1,124 @@
+use super::resp::Reply;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+/// A minimal blocking client for [`Server`](super::Server).
+pub struct Client {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl Client {
+    pub fn connect<A>(addr: A) -> io::Result<Self>
+    where
+        A: ToSocketAddrs,
+    {
+        let stream = TcpStream::connect(addr)?;
+
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    /// Sends a command and returns the server's reply. Error replies are
+    /// returned as [`Reply::Error`] rather than as an `Err`.
+    pub fn command(&mut self, args: &[&[u8]]) -> io::Result<Reply> {
+        let args = args.iter().map(|a| Reply::bulk(a)).collect();
+        Reply::Array(Some(args)).write(&mut self.writer)?;
+        self.writer.flush()?;
+
+        Reply::read(&mut self.reader)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"))
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        match self.command(&[b"GET", key])? {
+            Reply::Bulk(value) => Ok(value),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        match self.command(&[b"SET", key, value])? {
+            Reply::Simple(_) => Ok(()),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    pub fn del(&mut self, key: &[u8]) -> io::Result<bool> {
+        self.integer(&[b"DEL", key]).map(|n| n == 1)
+    }
+
+    pub fn exists(&mut self, key: &[u8]) -> io::Result<bool> {
+        self.integer(&[b"EXISTS", key]).map(|n| n == 1)
+    }
+
+    pub fn expire(&mut self, key: &[u8], seconds: u64) -> io::Result<bool> {
+        let seconds = seconds.to_string();
+        self.integer(&[b"EXPIRE", key, seconds.as_bytes()])
+            .map(|n| n == 1)
+    }
+
+    /// Returns every key matching `pattern`, following the `SCAN` cursor
+    /// until the server reports the end.
+    pub fn scan(&mut self, pattern: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        let mut cursor = b"0".to_vec();
+        let mut keys = Vec::new();
+
+        loop {
+            let reply = self.command(&[b"SCAN", &cursor, b"MATCH", pattern])?;
+
+            let mut items = match reply {
+                Reply::Array(Some(items)) if items.len() == 2 => items,
+                reply => return Err(unexpected(reply)),
+            };
+
+            match items.pop() {
+                Some(Reply::Array(Some(page))) => {
+                    for key in page {
+                        match key {
+                            Reply::Bulk(Some(key)) => keys.push(key),
+                            reply => return Err(unexpected(reply)),
+                        }
+                    }
+                }
+                Some(reply) => return Err(unexpected(reply)),
+                None => unreachable!(),
+            }
+
+            cursor = match items.pop() {
+                Some(Reply::Bulk(Some(next))) => next,
+                Some(reply) => return Err(unexpected(reply)),
+                None => unreachable!(),
+            };
+
+            if cursor == b"0" {
+                return Ok(keys);
+            }
+        }
+    }
+
+    pub fn info(&mut self) -> io::Result<String> {
+        match self.command(&[b"INFO"])? {
+            Reply::Bulk(Some(info)) => Ok(String::from_utf8_lossy(&info).into_owned()),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    fn integer(&mut self, args: &[&[u8]]) -> io::Result<i64> {
+        match self.command(args)? {
+            Reply::Integer(n) => Ok(n),
+            reply => Err(unexpected(reply)),
+        }
+    }
+}
+
+fn unexpected(reply: Reply) -> io::Error {
+    let msg = match reply {
+        Reply::Error(e) => e,
+        reply => format!("unexpected reply {:?}", reply),
+    };
+
+    io::Error::other(msg)
+}
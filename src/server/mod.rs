@@ -0,0 +1,672 @@
+//! A TCP server speaking a subset of the Redis protocol, so existing Redis
+//! clients can use a store.
+//!
+//! Supported commands are `GET`, `SET` (with `EX`, `PX`, `NX`, `XX` and
+//! `KEEPTTL`), `DEL`, `EXISTS`, `SCAN` (with `MATCH` and `COUNT`), `EXPIRE`,
+//! `TTL`, `INFO`, `PING` and `QUIT`. Expiry times are kept in memory by the
+//! server and are lost when it restarts.
+//!
+//! `SCAN` cursors are numbers handed out by the server, which remembers the
+//! last key returned for each, so a scan resumes right after that key. Only
+//! the most recent [`MAX_SCAN_CURSORS`] cursors are remembered; older ones
+//! fail with `ERR invalid cursor`.
+
+mod client;
+mod resp;
+
+pub use client::Client;
+pub use resp::Reply;
+
+use crate::Bitcask;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::Bound;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_SCAN_COUNT: usize = 10;
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+/// The number of `SCAN` cursors remembered at a time.
+pub const MAX_SCAN_CURSORS: usize = 1024;
+
+/// Called with the errors of connections, see [`Server::on_error`].
+type ErrorHook = Arc<dyn Fn(io::Error) + Send + Sync>;
+
+pub struct Server {
+    listener: TcpListener,
+    state: Arc<State>,
+    max_clients: usize,
+    on_error: ErrorHook,
+}
+
+struct State {
+    // lock order: expires before kv before cursors
+    expires: Mutex<HashMap<Vec<u8>, Instant>>,
+    kv: Mutex<Bitcask>,
+    cursors: Mutex<ScanCursors>,
+    clients: AtomicUsize,
+    started: Instant,
+}
+
+/// The last key returned by recent `SCAN` calls, by the cursor returned with
+/// it.
+#[derive(Default)]
+struct ScanCursors {
+    next: u64,
+    keys: BTreeMap<u64, Vec<u8>>,
+}
+
+impl ScanCursors {
+    /// Remembers `key` under a new cursor, forgetting the oldest cursor if
+    /// too many are remembered.
+    fn insert(&mut self, key: Vec<u8>) -> u64 {
+        // 0 starts a scan, so it is never handed out
+        self.next += 1;
+        self.keys.insert(self.next, key);
+
+        if self.keys.len() > MAX_SCAN_CURSORS {
+            self.keys.pop_first();
+        }
+
+        self.next
+    }
+}
+
+impl Server {
+    pub fn bind<A>(kv: Bitcask, addr: A) -> io::Result<Self>
+    where
+        A: ToSocketAddrs,
+    {
+        let listener = TcpListener::bind(addr)?;
+
+        let state = State {
+            expires: Mutex::new(HashMap::new()),
+            kv: Mutex::new(kv),
+            cursors: Mutex::new(ScanCursors::default()),
+            clients: AtomicUsize::new(0),
+            started: Instant::now(),
+        };
+
+        Ok(Self {
+            listener,
+            state: Arc::new(state),
+            max_clients: Self::DEFAULT_MAX_CLIENTS,
+            on_error: Arc::new(|_| {}),
+        })
+    }
+
+    /// The number of clients served at once unless changed with
+    /// [`Server::set_max_clients`].
+    pub const DEFAULT_MAX_CLIENTS: usize = 1024;
+
+    /// Limits the number of clients served at once. Each client is served on
+    /// its own thread; clients connecting beyond the limit are sent an error
+    /// and disconnected.
+    pub fn set_max_clients(&mut self, max_clients: usize) {
+        self.max_clients = max_clients;
+    }
+
+    /// Calls `f` with the error of every connection that fails, whether it
+    /// is dropped while being accepted, sends something that isn't a command
+    /// or can't be written to. Such errors only end their own connection,
+    /// and are ignored unless a hook is set.
+    pub fn on_error<F>(&mut self, f: F)
+    where
+        F: Fn(io::Error) + Send + Sync + 'static,
+    {
+        self.on_error = Arc::new(f);
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts connections, serving each client on its own thread. Returns
+    /// the error if accepting fails, for example because the process ran
+    /// out of file descriptors; connections dropped before they were
+    /// accepted are passed to [`Server::on_error`] instead.
+    pub fn run(self) -> io::Result<()> {
+        let state = Arc::clone(&self.state);
+        thread::spawn(move || loop {
+            thread::sleep(EXPIRE_INTERVAL);
+            state.remove_expired();
+        });
+
+        for stream in self.listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) if is_dropped(&e) => {
+                    (self.on_error)(e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            if self.state.clients.fetch_add(1, Ordering::Relaxed) >= self.max_clients {
+                self.state.clients.fetch_sub(1, Ordering::Relaxed);
+                let _ = Reply::error("ERR max number of clients reached").write(&mut stream);
+                continue;
+            }
+
+            let state = Arc::clone(&self.state);
+            let on_error = Arc::clone(&self.on_error);
+
+            thread::spawn(move || {
+                if let Err(e) = handle(&state, stream) {
+                    on_error(e);
+                }
+                state.clients.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether accepting failed because of the connection being accepted rather
+/// than the listener.
+fn is_dropped(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+    )
+}
+
+fn handle(state: &State, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    while let Some(args) = resp::read_command(&mut reader)? {
+        if args.is_empty() {
+            continue;
+        }
+
+        let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+        let reply = match quit {
+            true => Reply::ok(),
+            false => state.execute(&args),
+        };
+
+        reply.write(&mut writer)?;
+
+        // only flush once all pipelined commands have been answered
+        if quit || reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+
+        if quit {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+impl State {
+    fn execute(&self, args: &[Vec<u8>]) -> Reply {
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+        let args = &args[1..];
+
+        match name.as_str() {
+            "PING" => match args.first() {
+                Some(msg) => Reply::bulk(msg),
+                None => Reply::Simple("PONG".to_string()),
+            },
+            "GET" if args.len() == 1 => self.get(&args[0]),
+            "SET" if args.len() >= 2 => self.set(args),
+            "DEL" if !args.is_empty() => self.del(args),
+            "EXISTS" if !args.is_empty() => self.exists(args),
+            "SCAN" if !args.is_empty() => self.scan(args),
+            "EXPIRE" if args.len() == 2 => self.expire(&args[0], &args[1]),
+            "TTL" if args.len() == 1 => self.ttl(&args[0]),
+            "INFO" => self.info(),
+            "COMMAND" => Reply::Array(Some(Vec::new())),
+            "GET" | "SET" | "DEL" | "EXISTS" | "SCAN" | "EXPIRE" | "TTL" => Reply::error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name.to_lowercase()
+            )),
+            _ => Reply::error(format!("ERR unknown command '{}'", name.to_lowercase())),
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Reply {
+        let expires = self.expires.lock().expect("expires lock");
+        let kv = self.kv.lock().expect("kv lock");
+
+        if is_expired(&expires, key) {
+            return Reply::null();
+        }
+
+        match kv.get(key) {
+            Ok(Some(value)) => Reply::bulk(value.data()),
+            Ok(None) => Reply::null(),
+            Err(e) => Reply::error(format!("ERR {}", e)),
+        }
+    }
+
+    fn set(&self, args: &[Vec<u8>]) -> Reply {
+        let (key, value) = (&args[0], &args[1]);
+
+        let mut ttl = None;
+        let mut keep_ttl = false;
+        let mut nx = false;
+        let mut xx = false;
+
+        let mut opts = args[2..].iter();
+        while let Some(opt) = opts.next() {
+            let opt = String::from_utf8_lossy(opt).to_ascii_uppercase();
+
+            match opt.as_str() {
+                "NX" => nx = true,
+                "XX" => xx = true,
+                "KEEPTTL" => keep_ttl = true,
+                "EX" | "PX" => {
+                    let n = match opts.next().and_then(|n| parse_int(n)) {
+                        Some(n) if n > 0 => n as u64,
+                        _ => return Reply::error("ERR invalid expire time in 'set' command"),
+                    };
+                    ttl = Some(match opt.as_str() {
+                        "EX" => Duration::from_secs(n),
+                        _ => Duration::from_millis(n),
+                    });
+                }
+                _ => return Reply::error("ERR syntax error"),
+            }
+        }
+
+        if (nx && xx) || (keep_ttl && ttl.is_some()) {
+            return Reply::error("ERR syntax error");
+        }
+
+        let mut expires = self.expires.lock().expect("expires lock");
+        let mut kv = self.kv.lock().expect("kv lock");
+
+        remove_if_expired(&mut expires, &mut kv, key);
+
+        let exists = match kv.exists(key) {
+            Ok(exists) => exists,
+            Err(e) => return Reply::error(format!("ERR {}", e)),
+        };
+
+        if (nx && exists) || (xx && !exists) {
+            return Reply::null();
+        }
+
+        if let Err(e) = kv.insert(key, value) {
+            return Reply::error(format!("ERR {}", e));
+        }
+
+        match ttl {
+            Some(ttl) => {
+                expires.insert(key.to_vec(), Instant::now() + ttl);
+            }
+            None if !keep_ttl => {
+                expires.remove(key);
+            }
+            None => {}
+        }
+
+        Reply::ok()
+    }
+
+    fn del(&self, keys: &[Vec<u8>]) -> Reply {
+        let mut expires = self.expires.lock().expect("expires lock");
+        let mut kv = self.kv.lock().expect("kv lock");
+
+        let mut count = 0;
+
+        for key in keys {
+            let expired = is_expired(&expires, key);
+            expires.remove(key.as_slice());
+
+            match kv.delete(key) {
+                Ok(deleted) if deleted && !expired => count += 1,
+                Ok(_) => {}
+                Err(e) => return Reply::error(format!("ERR {}", e)),
+            }
+        }
+
+        Reply::Integer(count)
+    }
+
+    fn exists(&self, keys: &[Vec<u8>]) -> Reply {
+        let expires = self.expires.lock().expect("expires lock");
+        let kv = self.kv.lock().expect("kv lock");
+
+        let mut count = 0;
+
+        for key in keys {
+            match kv.exists(key) {
+                Ok(exists) if exists && !is_expired(&expires, key) => count += 1,
+                Ok(_) => {}
+                Err(e) => return Reply::error(format!("ERR {}", e)),
+            }
+        }
+
+        Reply::Integer(count)
+    }
+
+    /// Scans from the start for cursor 0, and otherwise from after the last
+    /// key returned with the cursor, so every call only reads the keys it
+    /// returns. Keys that exist for the whole scan are returned exactly once.
+    fn scan(&self, args: &[Vec<u8>]) -> Reply {
+        let after = match parse_int(&args[0]) {
+            Some(0) => None,
+            Some(n) if n > 0 => {
+                let cursors = self.cursors.lock().expect("cursors lock");
+
+                match cursors.keys.get(&(n as u64)) {
+                    Some(key) => Some(key.clone()),
+                    None => return Reply::error("ERR invalid cursor"),
+                }
+            }
+            _ => return Reply::error("ERR invalid cursor"),
+        };
+
+        let mut pattern = None;
+        let mut count = DEFAULT_SCAN_COUNT;
+
+        let mut opts = args[1..].iter();
+        while let Some(opt) = opts.next() {
+            let opt = String::from_utf8_lossy(opt).to_ascii_uppercase();
+
+            match (opt.as_str(), opts.next()) {
+                ("MATCH", Some(p)) => pattern = Some(p.as_slice()),
+                ("COUNT", Some(n)) => match parse_int(n) {
+                    Some(n) if n > 0 => count = n as usize,
+                    _ => return Reply::error("ERR value is not an integer or out of range"),
+                },
+                _ => return Reply::error("ERR syntax error"),
+            }
+        }
+
+        let expires = self.expires.lock().expect("expires lock");
+        let kv = self.kv.lock().expect("kv lock");
+
+        let start = match &after {
+            Some(key) => Bound::Excluded(key.as_slice()),
+            None => Bound::Unbounded,
+        };
+
+        let all = match kv.keys((start, Bound::Unbounded)) {
+            Ok(all) => all,
+            Err(e) => return Reply::error(format!("ERR {}", e)),
+        };
+
+        let mut keys = Vec::new();
+        let mut scanned = 0;
+        let mut last = None;
+
+        for key in all.take(count) {
+            scanned += 1;
+            last = Some(key);
+
+            if is_expired(&expires, key) {
+                continue;
+            }
+
+            if pattern.is_none_or(|p| glob_match(p, key)) {
+                keys.push(Reply::bulk(key));
+            }
+        }
+
+        let next = match last {
+            Some(key) if scanned == count => {
+                let mut cursors = self.cursors.lock().expect("cursors lock");
+                cursors.insert(key.to_vec())
+            }
+            _ => 0,
+        };
+
+        Reply::Array(Some(vec![
+            Reply::bulk(next.to_string().as_bytes()),
+            Reply::Array(Some(keys)),
+        ]))
+    }
+
+    fn expire(&self, key: &[u8], seconds: &[u8]) -> Reply {
+        let seconds = match parse_int(seconds) {
+            Some(n) => n,
+            None => return Reply::error("ERR value is not an integer or out of range"),
+        };
+
+        let mut expires = self.expires.lock().expect("expires lock");
+        let mut kv = self.kv.lock().expect("kv lock");
+
+        remove_if_expired(&mut expires, &mut kv, key);
+
+        match kv.exists(key) {
+            Ok(true) => {}
+            Ok(false) => return Reply::Integer(0),
+            Err(e) => return Reply::error(format!("ERR {}", e)),
+        }
+
+        if seconds <= 0 {
+            if let Err(e) = kv.delete(key) {
+                return Reply::error(format!("ERR {}", e));
+            }
+            expires.remove(key);
+        } else {
+            let at = Instant::now() + Duration::from_secs(seconds as u64);
+            expires.insert(key.to_vec(), at);
+        }
+
+        Reply::Integer(1)
+    }
+
+    fn ttl(&self, key: &[u8]) -> Reply {
+        let expires = self.expires.lock().expect("expires lock");
+        let kv = self.kv.lock().expect("kv lock");
+
+        match kv.exists(key) {
+            Ok(exists) if exists && !is_expired(&expires, key) => {}
+            Ok(_) => return Reply::Integer(-2),
+            Err(e) => return Reply::error(format!("ERR {}", e)),
+        }
+
+        match expires.get(key) {
+            Some(at) => {
+                let left = at.saturating_duration_since(Instant::now());
+                Reply::Integer(left.as_secs_f64().round() as i64)
+            }
+            None => Reply::Integer(-1),
+        }
+    }
+
+    fn info(&self) -> Reply {
+        let num_expires = self.expires.lock().expect("expires lock").len();
+        let stats = self.kv.lock().expect("kv lock").stats();
+
+        let info = format!(
+            "# Server\r\n\
+             kvs_version:{}\r\n\
+             uptime_in_seconds:{}\r\n\
+             \r\n\
+             # Clients\r\n\
+             connected_clients:{}\r\n\
+             \r\n\
+             # Storage\r\n\
+             data_files:{}\r\n\
+             data_size:{}\r\n\
+             live_size:{}\r\n\
+             entries_deleted:{}\r\n\
+             bytes_deleted:{}\r\n\
+             \r\n\
+             # Keyspace\r\n\
+             db0:keys={},expires={}\r\n",
+            env!("CARGO_PKG_VERSION"),
+            self.started.elapsed().as_secs(),
+            self.clients.load(Ordering::Relaxed),
+            stats.num_files,
+            stats.data_size,
+            stats.live_size,
+            stats.num_entries_deleted,
+            stats.num_bytes_deleted,
+            stats.num_keys,
+            num_expires,
+        );
+
+        Reply::bulk(info.as_bytes())
+    }
+
+    fn remove_expired(&self) {
+        let mut expires = self.expires.lock().expect("expires lock");
+        let now = Instant::now();
+
+        let expired: Vec<Vec<u8>> = expires
+            .iter()
+            .filter(|(_, &at)| at <= now)
+            .map(|(k, _)| k.clone())
+            .collect();
+
+        if expired.is_empty() {
+            return;
+        }
+
+        let mut kv = self.kv.lock().expect("kv lock");
+
+        // keys that fail to delete stay expired and are retried next time
+        for key in expired {
+            if kv.delete(&key).is_ok() {
+                expires.remove(&key);
+            }
+        }
+    }
+}
+
+fn is_expired(expires: &HashMap<Vec<u8>, Instant>, key: &[u8]) -> bool {
+    expires.get(key).is_some_and(|&at| at <= Instant::now())
+}
+
+fn remove_if_expired(expires: &mut HashMap<Vec<u8>, Instant>, kv: &mut Bitcask, key: &[u8]) {
+    if is_expired(expires, key) && kv.delete(key).is_ok() {
+        expires.remove(key);
+    }
+}
+
+fn parse_int(bytes: &[u8]) -> Option<i64> {
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+/// Matches `key` against a Redis style glob pattern supporting `*`, `?` and
+/// `\` to escape the next character.
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    let mut backtrack = None;
+
+    while k < key.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, k));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => {
+                p += 1;
+                k += 1;
+                continue;
+            }
+            Some(b'\\') if pattern.get(p + 1) == Some(&key[k]) => {
+                p += 2;
+                k += 1;
+                continue;
+            }
+            Some(&c) if c != b'\\' && c == key[k] => {
+                p += 1;
+                k += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        match backtrack {
+            Some((bp, bk)) => {
+                p = bp + 1;
+                k = bk + 1;
+                backtrack = Some((bp, bk + 1));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Options;
+    use std::sync::mpsc;
+
+    /// Starts a server over a new store, sending connection errors to the
+    /// returned channel.
+    fn start(name: &str) -> (SocketAddr, mpsc::Receiver<io::Error>) {
+        let root = std::env::temp_dir().join(format!("kvs-server-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+
+        let kv = Bitcask::open(Options::new(root)).unwrap();
+        let mut server = Server::bind(kv, "127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let (errors, received) = mpsc::channel();
+        let errors = Mutex::new(errors);
+        server.on_error(move |e| {
+            let _ = errors.lock().unwrap().send(e);
+        });
+
+        thread::spawn(move || server.run());
+
+        (addr, received)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (addr, _) = start("round-trip");
+        let mut client = Client::connect(addr).unwrap();
+
+        assert_eq!(client.get(b"a").unwrap(), None);
+        client.set(b"a", b"1").unwrap();
+        client.set(b"b", b"2").unwrap();
+        client.set(b"c", b"\r\n\0").unwrap();
+
+        assert_eq!(client.get(b"a").unwrap().unwrap(), b"1");
+        assert_eq!(client.get(b"c").unwrap().unwrap(), b"\r\n\0");
+        assert!(client.exists(b"b").unwrap());
+
+        assert!(client.del(b"b").unwrap());
+        assert!(!client.del(b"b").unwrap());
+        assert!(!client.exists(b"b").unwrap());
+
+        let mut keys = client.scan(b"*").unwrap();
+        keys.sort();
+        assert_eq!(keys, vec![b"a".to_vec(), b"c".to_vec()]);
+
+        assert!(client.expire(b"a", 100).unwrap());
+        assert!(!client.expire(b"b", 100).unwrap());
+
+        // a second client sees the writes of the first
+        let mut other = Client::connect(addr).unwrap();
+        assert_eq!(other.get(b"c").unwrap().unwrap(), b"\r\n\0");
+    }
+
+    #[test]
+    fn connection_errors_go_to_the_hook() {
+        let (addr, errors) = start("errors");
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"*1\r\n:1\r\n").unwrap();
+
+        let e = errors.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        // the server goes on serving other clients
+        let mut client = Client::connect(addr).unwrap();
+        client.set(b"a", b"1").unwrap();
+        assert_eq!(client.get(b"a").unwrap().unwrap(), b"1");
+    }
+}
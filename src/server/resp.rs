@@ -0,0 +1,200 @@
+use std::io::{self, BufRead, Write};
+
+/// Largest bulk string accepted, matching Redis' default `proto-max-bulk-len`.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// A value in the Redis serialization protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+impl Reply {
+    pub fn ok() -> Self {
+        Self::Simple("OK".to_string())
+    }
+
+    pub fn error<S>(msg: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self::Error(msg.into())
+    }
+
+    pub fn bulk(bytes: &[u8]) -> Self {
+        Self::Bulk(Some(bytes.to_vec()))
+    }
+
+    pub fn null() -> Self {
+        Self::Bulk(None)
+    }
+
+    pub fn write<W>(&self, w: &mut W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        match self {
+            Self::Simple(s) => write!(w, "+{}\r\n", s),
+            Self::Error(s) => write!(w, "-{}\r\n", s),
+            Self::Integer(n) => write!(w, ":{}\r\n", n),
+            Self::Bulk(None) => w.write_all(b"$-1\r\n"),
+            Self::Bulk(Some(bytes)) => {
+                write!(w, "${}\r\n", bytes.len())?;
+                w.write_all(bytes)?;
+                w.write_all(b"\r\n")
+            }
+            Self::Array(None) => w.write_all(b"*-1\r\n"),
+            Self::Array(Some(items)) => {
+                write!(w, "*{}\r\n", items.len())?;
+                for item in items {
+                    item.write(w)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Reads a reply, returning `None` if the stream ended before it.
+    pub fn read<R>(r: &mut R) -> io::Result<Option<Self>>
+    where
+        R: BufRead,
+    {
+        let line = match read_line(r)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+
+        let (kind, rest) = match line.split_first() {
+            Some((&kind, rest)) => (kind, rest),
+            None => return Err(invalid("empty reply")),
+        };
+
+        let reply = match kind {
+            b'+' => Self::Simple(String::from_utf8_lossy(rest).into_owned()),
+            b'-' => Self::Error(String::from_utf8_lossy(rest).into_owned()),
+            b':' => Self::Integer(parse_int(rest)?),
+            b'$' => match parse_int(rest)? {
+                n if n < 0 => Self::Bulk(None),
+                n => Self::Bulk(Some(read_bulk(r, n as usize)?)),
+            },
+            b'*' => match parse_int(rest)? {
+                n if n < 0 => Self::Array(None),
+                n => {
+                    let mut items = Vec::with_capacity(n.min(1024) as usize);
+                    for _ in 0..n {
+                        match Self::read(r)? {
+                            Some(item) => items.push(item),
+                            None => return Err(eof()),
+                        }
+                    }
+                    Self::Array(Some(items))
+                }
+            },
+            _ => return Err(invalid("unknown reply type")),
+        };
+
+        Ok(Some(reply))
+    }
+}
+
+/// Reads a command sent by a client, either as an array of bulk strings or
+/// as an inline command of space separated words. Returns `None` if the
+/// stream ended before the command.
+pub fn read_command<R>(r: &mut R) -> io::Result<Option<Vec<Vec<u8>>>>
+where
+    R: BufRead,
+{
+    let line = match read_line(r)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    let rest = match line.strip_prefix(b"*") {
+        Some(rest) => rest,
+        None => {
+            let words = line
+                .split(|b| b.is_ascii_whitespace())
+                .filter(|w| !w.is_empty())
+                .map(|w| w.to_vec())
+                .collect();
+            return Ok(Some(words));
+        }
+    };
+
+    let n = parse_int(rest)?;
+    let mut args = Vec::with_capacity(n.clamp(0, 1024) as usize);
+
+    for _ in 0..n {
+        let line = read_line(r)?.ok_or_else(eof)?;
+        let len = match line.strip_prefix(b"$") {
+            Some(len) => parse_int(len)?,
+            None => return Err(invalid("expected a bulk string")),
+        };
+
+        if len < 0 {
+            return Err(invalid("expected a bulk string"));
+        }
+
+        args.push(read_bulk(r, len as usize)?);
+    }
+
+    Ok(Some(args))
+}
+
+fn read_line<R>(r: &mut R) -> io::Result<Option<Vec<u8>>>
+where
+    R: BufRead,
+{
+    let mut line = Vec::new();
+
+    if r.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+
+    if line.ends_with(b"\n") {
+        line.pop();
+    }
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+
+    Ok(Some(line))
+}
+
+fn read_bulk<R>(r: &mut R, len: usize) -> io::Result<Vec<u8>>
+where
+    R: BufRead,
+{
+    if len > MAX_BULK_LEN {
+        return Err(invalid("bulk string too large"));
+    }
+
+    let mut buf = vec![0u8; len + 2];
+    r.read_exact(&mut buf)?;
+
+    if !buf.ends_with(b"\r\n") {
+        return Err(invalid("bulk string not terminated by CRLF"));
+    }
+
+    buf.truncate(len);
+    Ok(buf)
+}
+
+fn parse_int(bytes: &[u8]) -> io::Result<i64> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| invalid("invalid integer"))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn eof() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected end of stream")
+}
@@ -0,0 +1,87 @@
+use super::ordered;
+use crate::KeyValueStoreError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+type Result<T> = std::result::Result<T, KeyValueStoreError>;
+
+/// Converts values of type `T` to and from the bytes stored in a store.
+pub trait Codec<T> {
+    fn encode(value: &T) -> Result<Vec<u8>>;
+    fn decode(bytes: &[u8]) -> Result<T>;
+}
+
+/// Compact binary encoding using `bincode`.
+pub struct Bincode;
+
+/// JSON encoding using `serde_json`, readable when inspecting a store.
+pub struct Json;
+
+/// CBOR encoding using `ciborium`.
+pub struct Cbor;
+
+/// The order preserving encoding described in [`ordered`], for keys that
+/// should be scanned in the order of their values, such as integers and
+/// tuples.
+pub struct Ordered;
+
+impl<T> Codec<T> for Bincode
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn encode(value: &T) -> Result<Vec<u8>> {
+        bincode::serialize(value).map_err(codec_err)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<T> {
+        bincode::deserialize(bytes).map_err(codec_err)
+    }
+}
+
+impl<T> Codec<T> for Json
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn encode(value: &T) -> Result<Vec<u8>> {
+        serde_json::to_vec(value).map_err(codec_err)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<T> {
+        serde_json::from_slice(bytes).map_err(codec_err)
+    }
+}
+
+impl<T> Codec<T> for Cbor
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn encode(value: &T) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(value, &mut bytes).map_err(codec_err)?;
+        Ok(bytes)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<T> {
+        ciborium::from_reader(bytes).map_err(codec_err)
+    }
+}
+
+impl<T> Codec<T> for Ordered
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn encode(value: &T) -> Result<Vec<u8>> {
+        ordered::to_bytes(value).map_err(codec_err)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<T> {
+        ordered::from_bytes(bytes).map_err(codec_err)
+    }
+}
+
+fn codec_err<E>(e: E) -> KeyValueStoreError
+where
+    E: std::fmt::Display,
+{
+    KeyValueStoreError::Codec(e.to_string())
+}
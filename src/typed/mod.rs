@@ -0,0 +1,139 @@
+//! Typed keys and values on top of a store, encoded with serde.
+//!
+//! See `examples/typed.rs`.
+
+mod codec;
+pub mod ordered;
+
+pub use codec::{Bincode, Cbor, Codec, Json, Ordered};
+
+use crate::{Bitcask, KeyValueStoreError, Options, RangeIter};
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+type Result<T> = std::result::Result<T, KeyValueStoreError>;
+type Types<K, V, C, KC> = PhantomData<fn() -> (K, V, C, KC)>;
+
+/// A store of keys of type `K` and values of type `V`, with values encoded
+/// by the codec `C` and keys by `KC`, which defaults to `C`.
+///
+/// Range scans visit keys in the order of their encoded bytes, so use the
+/// [`Ordered`] key codec for them to follow the order of the keys themselves.
+pub struct TypedStore<K, V, C, KC = C> {
+    kv: Bitcask,
+    types: Types<K, V, C, KC>,
+}
+
+impl<K, V, C, KC> TypedStore<K, V, C, KC>
+where
+    C: Codec<V>,
+    KC: Codec<K>,
+{
+    pub fn new(kv: Bitcask) -> Self {
+        Self {
+            kv,
+            types: PhantomData,
+        }
+    }
+
+    pub fn open(opts: Options) -> Result<Self> {
+        Ok(Self::new(Bitcask::open(opts)?))
+    }
+
+    pub fn inner(&self) -> &Bitcask {
+        &self.kv
+    }
+
+    pub fn inner_mut(&mut self) -> &mut Bitcask {
+        &mut self.kv
+    }
+
+    pub fn into_inner(self) -> Bitcask {
+        self.kv
+    }
+
+    pub fn exists(&self, key: &K) -> Result<bool> {
+        self.kv.exists(&KC::encode(key)?)
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        match self.kv.get(&KC::encode(key)?)? {
+            Some(value) => C::decode(value.data()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn insert(&mut self, key: &K, value: &V) -> Result<()> {
+        self.kv.insert(&KC::encode(key)?, &C::encode(value)?)
+    }
+
+    pub fn delete(&mut self, key: &K) -> Result<bool> {
+        self.kv.delete(&KC::encode(key)?)
+    }
+
+    /// Iterates over the keys in `range`, compared by their encoded bytes.
+    pub fn iter<R>(&self, range: R) -> Result<TypedIter<'_, K, V, C, KC>>
+    where
+        R: RangeBounds<K>,
+    {
+        let start = encode_bound::<K, KC>(range.start_bound())?;
+        let end = encode_bound::<K, KC>(range.end_bound())?;
+
+        let range = (start.as_ref().map(|k| &k[..]), end.as_ref().map(|k| &k[..]));
+
+        Ok(TypedIter {
+            inner: self.kv.iter(range)?,
+            types: PhantomData,
+        })
+    }
+}
+
+/// An iterator over a range of a [`TypedStore`], decoding every entry.
+pub struct TypedIter<'a, K, V, C, KC> {
+    inner: RangeIter<'a>,
+    types: Types<K, V, C, KC>,
+}
+
+impl<K, V, C, KC> TypedIter<'_, K, V, C, KC>
+where
+    C: Codec<V>,
+    KC: Codec<K>,
+{
+    fn decode(item: Result<(Vec<u8>, crate::Value)>) -> Result<(K, V)> {
+        let (key, value) = item?;
+        Ok((KC::decode(&key)?, C::decode(value.data())?))
+    }
+}
+
+impl<K, V, C, KC> Iterator for TypedIter<'_, K, V, C, KC>
+where
+    C: Codec<V>,
+    KC: Codec<K>,
+{
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(Self::decode)
+    }
+}
+
+impl<K, V, C, KC> DoubleEndedIterator for TypedIter<'_, K, V, C, KC>
+where
+    C: Codec<V>,
+    KC: Codec<K>,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(Self::decode)
+    }
+}
+
+fn encode_bound<K, KC>(bound: Bound<&K>) -> Result<Bound<Vec<u8>>>
+where
+    KC: Codec<K>,
+{
+    Ok(match bound {
+        Bound::Included(key) => Bound::Included(KC::encode(key)?),
+        Bound::Excluded(key) => Bound::Excluded(KC::encode(key)?),
+        Bound::Unbounded => Bound::Unbounded,
+    })
+}
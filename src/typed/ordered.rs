@@ -0,0 +1,887 @@
+//! A serde format whose encoded bytes sort in the same order as the values.
+//!
+//! Numbers, strings and byte strings are encoded as described in
+//! [`key`](crate::key), and tuples and structs are their fields one after
+//! another, so they sort by their first field, then their second and so on.
+//! Sequences and maps put `0x01` before every element and end with `0x00`,
+//! options are `0x00` for `None` or `0x01` followed by the value, and enum
+//! variants are their index as a big endian `u32` followed by their fields.
+//!
+//! The format is not self describing, so it can only be decoded into the
+//! type it was encoded from.
+
+use crate::key::{KeyReader, KeyWriter};
+use crate::KeyValueStoreError;
+use serde::de::{self, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize};
+use std::fmt;
+
+const END: u8 = 0x00;
+const MORE: u8 = 0x01;
+
+#[derive(Debug)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
+
+impl From<KeyValueStoreError> for Error {
+    fn from(e: KeyValueStoreError) -> Self {
+        Self(e.to_string())
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+pub fn to_bytes<T>(value: &T) -> Result<Vec<u8>>
+where
+    T: Serialize + ?Sized,
+{
+    let mut serializer = Serializer {
+        out: KeyWriter::new(),
+    };
+    value.serialize(&mut serializer)?;
+    Ok(serializer.out.into_bytes())
+}
+
+pub fn from_bytes<'de, T>(bytes: &'de [u8]) -> Result<T>
+where
+    T: de::Deserialize<'de>,
+{
+    let mut deserializer = Deserializer {
+        input: KeyReader::new(bytes),
+    };
+    let value = T::deserialize(&mut deserializer)?;
+    deserializer.input.finish()?;
+
+    Ok(value)
+}
+
+struct Serializer {
+    out: KeyWriter,
+}
+
+impl ser::Serializer for &mut Serializer {
+    type Ok = ();
+    type Error = Error;
+
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        self.out.bool(v);
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<()> {
+        self.out.i8(v);
+        Ok(())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<()> {
+        self.out.i16(v);
+        Ok(())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<()> {
+        self.out.i32(v);
+        Ok(())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<()> {
+        self.out.i64(v);
+        Ok(())
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<()> {
+        self.out.i128(v);
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<()> {
+        self.out.u8(v);
+        Ok(())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<()> {
+        self.out.u16(v);
+        Ok(())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<()> {
+        self.out.u32(v);
+        Ok(())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<()> {
+        self.out.u64(v);
+        Ok(())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<()> {
+        self.out.u128(v);
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<()> {
+        self.out.f32(v);
+        Ok(())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<()> {
+        self.out.f64(v);
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<()> {
+        self.out.u32(v as u32);
+        Ok(())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<()> {
+        self.out.str(v);
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        self.out.bytes(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<()> {
+        self.out.u8(END);
+        Ok(())
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<()>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.out.u8(MORE);
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<()> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        index: u32,
+        _variant: &'static str,
+    ) -> Result<()> {
+        self.out.u32(index);
+        Ok(())
+    }
+
+    fn serialize_newtype_struct<T>(self, _name: &'static str, value: &T) -> Result<()>
+    where
+        T: Serialize + ?Sized,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<()>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.out.u32(index);
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self> {
+        self.out.u32(index);
+        Ok(self)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self> {
+        self.out.u32(index);
+        Ok(self)
+    }
+}
+
+impl ser::SerializeSeq for &mut Serializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.out.u8(MORE);
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        self.out.u8(END);
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for &mut Serializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T>(&mut self, key: &T) -> Result<()>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.out.u8(MORE);
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: Serialize + ?Sized,
+    {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        self.out.u8(END);
+        Ok(())
+    }
+}
+
+macro_rules! serialize_fields {
+    ($($trait:ident :: $method:ident),*) => {
+        $(
+            impl ser::$trait for &mut Serializer {
+                type Ok = ();
+                type Error = Error;
+
+                fn $method<T>(&mut self, value: &T) -> Result<()>
+                where
+                    T: Serialize + ?Sized,
+                {
+                    value.serialize(&mut **self)
+                }
+
+                fn end(self) -> Result<()> {
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+
+serialize_fields!(
+    SerializeTuple::serialize_element,
+    SerializeTupleStruct::serialize_field,
+    SerializeTupleVariant::serialize_field
+);
+
+impl ser::SerializeStruct for &mut Serializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, _key: &'static str, value: &T) -> Result<()>
+    where
+        T: Serialize + ?Sized,
+    {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStructVariant for &mut Serializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, _key: &'static str, value: &T) -> Result<()>
+    where
+        T: Serialize + ?Sized,
+    {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct Deserializer<'de> {
+    input: KeyReader<'de>,
+}
+
+impl Deserializer<'_> {
+    /// Reads the marker before each element of a sequence or map, returning
+    /// whether another element follows.
+    fn take_marker(&mut self) -> Result<bool> {
+        match self.input.u8()? {
+            MORE => Ok(true),
+            END => Ok(false),
+            _ => Err(Error("invalid marker".to_string())),
+        }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn deserialize_any<V>(self, _visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        Err(Error(
+            "the ordered format is not self describing".to_string(),
+        ))
+    }
+
+    fn deserialize_ignored_any<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_any(visitor)
+    }
+
+    fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_bool(self.input.bool()?)
+    }
+
+    fn deserialize_i8<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_i8(self.input.i8()?)
+    }
+
+    fn deserialize_i16<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_i16(self.input.i16()?)
+    }
+
+    fn deserialize_i32<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_i32(self.input.i32()?)
+    }
+
+    fn deserialize_i64<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_i64(self.input.i64()?)
+    }
+
+    fn deserialize_i128<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_i128(self.input.i128()?)
+    }
+
+    fn deserialize_u8<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_u8(self.input.u8()?)
+    }
+
+    fn deserialize_u16<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_u16(self.input.u16()?)
+    }
+
+    fn deserialize_u32<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_u32(self.input.u32()?)
+    }
+
+    fn deserialize_u64<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_u64(self.input.u64()?)
+    }
+
+    fn deserialize_u128<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_u128(self.input.u128()?)
+    }
+
+    fn deserialize_f32<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_f32(self.input.f32()?)
+    }
+
+    fn deserialize_f64<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_f64(self.input.f64()?)
+    }
+
+    fn deserialize_char<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        match char::from_u32(self.input.u32()?) {
+            Some(c) => visitor.visit_char(c),
+            None => Err(Error("invalid char".to_string())),
+        }
+    }
+
+    fn deserialize_str<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_string<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_string(self.input.str()?)
+    }
+
+    fn deserialize_bytes<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    fn deserialize_byte_buf<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_byte_buf(self.input.bytes()?)
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        match self.take_marker()? {
+            true => visitor.visit_some(self),
+            false => visitor.visit_none(),
+        }
+    }
+
+    fn deserialize_unit<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V>(self, _name: &'static str, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V>(self, _name: &'static str, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_seq(Elements { de: self })
+    }
+
+    fn deserialize_tuple<V>(self, len: usize, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_seq(Fields {
+            de: self,
+            left: len,
+        })
+    }
+
+    fn deserialize_tuple_struct<V>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_map(Elements { de: self })
+    }
+
+    fn deserialize_struct<V>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_tuple(fields.len(), visitor)
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_enum(self)
+    }
+
+    fn deserialize_identifier<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_any(visitor)
+    }
+}
+
+/// The elements of a sequence or map, each preceded by a marker.
+struct Elements<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+}
+
+impl<'de> de::SeqAccess<'de> for Elements<'_, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        match self.de.take_marker()? {
+            true => seed.deserialize(&mut *self.de).map(Some),
+            false => Ok(None),
+        }
+    }
+}
+
+impl<'de> de::MapAccess<'de> for Elements<'_, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
+    where
+        K: DeserializeSeed<'de>,
+    {
+        match self.de.take_marker()? {
+            true => seed.deserialize(&mut *self.de).map(Some),
+            false => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        seed.deserialize(&mut *self.de)
+    }
+}
+
+/// The fields of a tuple or struct, whose number is known up front.
+struct Fields<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+    left: usize,
+}
+
+impl<'de> de::SeqAccess<'de> for Fields<'_, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        if self.left == 0 {
+            return Ok(None);
+        }
+
+        self.left -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.left)
+    }
+}
+
+impl<'de> de::EnumAccess<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self)>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        let index = self.input.u32()?;
+        let variant = seed.deserialize(IntoDeserializer::<Error>::into_deserializer(index))?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V>(self, len: usize, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        de::Deserializer::deserialize_tuple(self, len, visitor)
+    }
+
+    fn struct_variant<V>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        de::Deserializer::deserialize_tuple(self, fields.len(), visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::typed::{Json, Ordered, TypedStore};
+    use crate::Options;
+    use serde::de::DeserializeOwned;
+    use serde::{Deserialize, Serialize};
+    use std::fmt::Debug;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Event {
+        Start,
+        Move(i32, i32),
+        Stop { code: u8 },
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Version {
+        major: u16,
+        minor: u16,
+        tag: Option<String>,
+    }
+
+    /// Checks that `values`, which are in ascending order, encode to bytes
+    /// in the same order and decode back to themselves.
+    fn assert_ordered<T>(values: &[T])
+    where
+        T: Serialize + DeserializeOwned + Debug + PartialEq,
+    {
+        let encoded: Vec<Vec<u8>> = values.iter().map(|v| to_bytes(v).unwrap()).collect();
+
+        for (pair, bytes) in encoded.windows(2).zip(values.windows(2)) {
+            assert!(
+                pair[0] < pair[1],
+                "{:?} sorts after {:?}",
+                bytes[0],
+                bytes[1]
+            );
+        }
+
+        for (value, bytes) in values.iter().zip(&encoded) {
+            assert_eq!(&from_bytes::<T>(bytes).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn numbers() {
+        assert_ordered(&[i64::MIN, -256, -1, 0, 1, 255, i64::MAX]);
+        assert_ordered(&[0u32, 1, 256, u32::MAX]);
+        assert_ordered(&[f64::NEG_INFINITY, -1.5, -0.0, 0.0, 1e-9, 2.0, f64::INFINITY]);
+        assert_ordered(&[false, true]);
+        assert_ordered(&['a', 'b', 'é']);
+    }
+
+    #[test]
+    fn strings_and_bytes() {
+        assert_ordered(&["", "\0", "a", "a\0", "ab", "b"].map(String::from));
+        assert_ordered(&[vec![], vec![0u8], vec![0, 0], vec![0, 1], vec![1]]);
+    }
+
+    #[test]
+    fn compound_values() {
+        assert_ordered(&[
+            ("a".to_string(), 2),
+            ("a".to_string(), 10),
+            ("ab".to_string(), 0),
+        ]);
+        assert_ordered(&[None, Some(0u8), Some(1)]);
+        assert_ordered(&[vec![], vec![1u16], vec![1, 0], vec![2]]);
+        assert_ordered(&[
+            Event::Start,
+            Event::Move(-1, 5),
+            Event::Move(0, 0),
+            Event::Stop { code: 1 },
+        ]);
+        assert_ordered(&[
+            Version {
+                major: 1,
+                minor: 2,
+                tag: None,
+            },
+            Version {
+                major: 1,
+                minor: 2,
+                tag: Some("rc".to_string()),
+            },
+            Version {
+                major: 1,
+                minor: 10,
+                tag: None,
+            },
+            Version {
+                major: 2,
+                minor: 0,
+                tag: None,
+            },
+        ]);
+    }
+
+    #[test]
+    fn typed_store_scans_in_key_order() {
+        let root = std::env::temp_dir().join(format!("kvs-ordered-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let opts = Options::new(root);
+
+        let keys = [(2, "b"), (-5, "z"), (10, "a"), (2, "a"), (-40, "a")];
+        let mut store =
+            TypedStore::<(i32, String), u32, Json, Ordered>::open(opts.clone()).unwrap();
+
+        for (i, (n, s)) in keys.iter().enumerate() {
+            store.insert(&(*n, s.to_string()), &(i as u32)).unwrap();
+        }
+        drop(store);
+
+        let store = TypedStore::<(i32, String), u32, Json, Ordered>::open(opts).unwrap();
+        let scanned: Vec<_> = store.iter(..).unwrap().map(|item| item.unwrap()).collect();
+
+        let expected = [
+            (-40, "a", 4),
+            (-5, "z", 1),
+            (2, "a", 3),
+            (2, "b", 0),
+            (10, "a", 2),
+        ]
+        .map(|(n, s, i)| ((n, s.to_string()), i));
+        assert_eq!(scanned, expected);
+
+        let start = (0, String::new());
+        let end = (10, String::new());
+        let middle: Vec<_> = store
+            .iter(start..end)
+            .unwrap()
+            .map(|item| item.unwrap().0)
+            .collect();
+        assert_eq!(middle, [(2, "a".to_string()), (2, "b".to_string())]);
+    }
+}